@@ -1,27 +1,117 @@
 //! Uses `builder` and filesystem watch code to repeatedly
 //! evaluate and build a given Nix file.
 
+mod progress;
+
+pub use self::progress::BuildProgress;
+
 use crate::builder;
 use crate::builder::RunStatus;
+use crate::nix::log::LogEvent;
+use crate::nix::EvalEnv;
 use crate::notify;
 use crate::pathreduction::reduce_paths;
 use crate::project::roots;
 use crate::project::roots::Roots;
 use crate::project::Project;
+use crate::scheduler::{Job, Scheduler};
 use crate::watch::{DebugMessage, EventError, Reason, Watch};
+use crate::NixFile;
 use crossbeam_channel as chan;
-use slog_scope::{debug, warn};
+use slog_scope::{debug, info, warn};
+use std::collections::HashMap;
+use std::ffi::OsString;
 use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 /// Builder events sent back over `BuildLoop.tx`.
 #[derive(Clone, Debug)]
 pub enum Event {
     /// The build has started
-    Started(Reason),
+    Started {
+        /// The nix file of the project being built
+        nix_file: NixFile,
+        /// Why the build was started
+        reason: Reason,
+    },
+    /// The running build started building or fetching another path
+    Progress {
+        /// The nix file of the project being built
+        nix_file: NixFile,
+        /// How far the build got
+        progress: BuildProgress,
+    },
+    /// The running build was cancelled because its inputs changed
+    /// (or a rebuild was requested); the next build follows
+    Cancelled {
+        /// The nix file of the project whose build was cancelled
+        nix_file: NixFile,
+    },
     /// The build completed successfully
-    Completed(BuildResults),
+    Completed {
+        /// The nix file of the project that was built
+        nix_file: NixFile,
+        /// The results of the build
+        result: BuildResults,
+        /// Measurements of the build
+        metrics: BuildMetrics,
+    },
     /// The build command returned a failing exit status
-    Failure(BuildExitFailure),
+    Failure {
+        /// The nix file of the project that failed to build
+        nix_file: NixFile,
+        /// The failing build's output
+        failure: BuildExitFailure,
+        /// Measurements of the build
+        metrics: BuildMetrics,
+    },
+    /// An internal error kept the project from being built.
+    /// The build loop keeps running and tries again later.
+    Error {
+        /// The nix file of the project that could not be built
+        nix_file: NixFile,
+        /// What went wrong
+        error: LoopError,
+    },
+}
+
+/// Messages to a running `BuildLoop`, see `BuildLoop::forever`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Ping {
+    /// Somebody is interested in the project. It is built if
+    /// it is stale or its GC root went missing.
+    Activity {
+        /// Evaluate the project in this environment from now on.
+        /// If it differs from the previous one, the project is built again.
+        env: Option<EvalEnv>,
+    },
+    /// Build the project, even if nothing changed.
+    Rebuild {
+        /// Fetch unpinned inputs again, see `builder::RunOpts::set_refresh`.
+        refresh: bool,
+    },
+}
+
+/// Internal errors of the build loop, which are not caused by
+/// the Nix expression itself.
+#[derive(Clone, Debug)]
+pub enum LoopError {
+    /// The build could not be run or its result could not be rooted,
+    /// see `UnrecoverableErrors`. The build is retried after a backoff.
+    Build(String),
+    /// The file watcher failed and could not be recreated.
+    /// Recreating it is retried after a backoff.
+    Watch(String),
+}
+
+impl std::fmt::Display for LoopError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LoopError::Build(e) => write!(f, "the build failed to run: {}", e),
+            LoopError::Watch(e) => write!(f, "the file watcher failed: {}", e),
+        }
+    }
 }
 
 /// Results of a single, successful build.
@@ -38,6 +128,15 @@ pub struct BuildExitFailure {
     pub log_lines: Vec<std::ffi::OsString>,
 }
 
+/// Measurements of a single build which ran to the end.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BuildMetrics {
+    /// How long each phase of the build took
+    pub durations: builder::Durations,
+    /// How many paths are watched for changes after the build
+    pub watched_paths: usize,
+}
+
 /// The BuildLoop repeatedly builds the Nix expression in
 /// `project` each time a source file influencing
 /// a previous build changes.
@@ -45,127 +144,964 @@ pub struct BuildExitFailure {
 pub struct BuildLoop<'a> {
     /// Project to be built.
     project: &'a Project,
-    /// Watches all input files for changes.
-    /// As new input files are discovered, they are added to the watchlist.
-    watch: Watch,
+    /// See `BuildLoop::stale_after`.
+    stale_after: Option<Duration>,
+    /// See `BuildLoop::debounce`.
+    debounce: Duration,
 }
 
+/// The default for `BuildLoop::debounce`.
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(200);
+
 impl<'a> BuildLoop<'a> {
     /// Instatiate a new BuildLoop. Uses an internal filesystem
     /// watching implementation.
     pub fn new(project: &'a Project) -> BuildLoop<'a> {
         BuildLoop {
             project,
-            watch: Watch::try_new().expect("Failed to initialize watch"),
+            stale_after: None,
+            debounce: DEFAULT_DEBOUNCE,
         }
     }
 
+    /// Stop building on filesystem changes once no ping was received
+    /// for `duration`. Instead, the project is marked as stale and
+    /// built on the next ping.
+    pub fn stale_after(&mut self, duration: Duration) -> &mut Self {
+        self.stale_after = Some(duration);
+        self
+    }
+
+    /// Once an input file changed, wait until no further changes
+    /// arrived for `quiet_period` before starting a build.
+    /// All files which changed in the meantime cause a single build.
+    pub fn debounce(&mut self, quiet_period: Duration) -> &mut Self {
+        self.debounce = quiet_period;
+        self
+    }
+
     /// Loop forever, watching the filesystem for changes. Blocks.
     /// Sends `Event`s over `Self.tx` once they happen.
-    /// When new filesystem changes are detected while a build is
-    /// still running, it is finished first before starting a new build.
+    /// See `BuildLoops` for how changes and pings are turned into builds.
+    /// Returns once the sender of `rx_ping` or the receiver of `tx` hangs up,
+    /// cancelling a running build.
     #[allow(clippy::drop_copy, clippy::zero_ptr)] // triggered by `select!`
-    pub fn forever(&mut self, tx: chan::Sender<Event>, rx_ping: chan::Receiver<()>) {
-        let send = |msg| tx.send(msg).expect("Failed to send an event");
-        let translate_reason = |rsn| match rsn {
-            Ok(rsn) => rsn,
+    pub fn forever(&mut self, tx: chan::Sender<Event>, rx_ping: chan::Receiver<Ping>) {
+        let mut loops = BuildLoops::new(1, self.debounce, self.stale_after, tx);
+        loops.ping(self.project, Ping::Activity { env: None });
+        while !loops.hung_up() {
+            let notifications = loops.notifications();
+            let updates = loops.updates();
+            let timer = loops.timer();
+            chan::select! {
+                recv(rx_ping) -> msg => match msg {
+                    Ok(ping) => loops.ping(self.project, ping),
+                    // nobody is interested in this project anymore
+                    Err(chan::RecvError) => break,
+                },
+                recv(notifications) -> msg => loops.notified(msg),
+                recv(updates) -> msg => if let Ok(update) = msg {
+                    loops.updated(update)
+                },
+                recv(timer) -> _ => loops.tick(),
+            }
+        }
+        loops.shutdown();
+    }
+
+    /// Execute a single build of the environment.
+    ///
+    /// This will create GC roots for the evaluation.
+    pub fn once(&mut self) -> Result<BuildResults, BuildError> {
+        let (tx, rx) = chan::unbounded();
+        let run_result = builder::run(
+            tx,
+            &self.project.nix_file,
+            &self.project.cas,
+            &builder::RunOpts::default(),
+        )?;
+        build_result(self.project, run_result.status, rx.iter().collect())
+    }
+}
+
+/// Builds many projects on a fixed number of worker threads
+/// (see `Scheduler`), watching their input files with a single `Watch`.
+///
+/// `BuildLoops` does not block; its owner receives on
+/// `BuildLoops::notifications`, `BuildLoops::updates` and
+/// `BuildLoops::timer` and passes what arrives back in.
+///
+/// When the input files of a project change, the project is built
+/// once they did not change for the debounce period. When they change
+/// while its build is waiting for a worker, the build waits for the
+/// next quiet period instead; when they change while its build runs,
+/// the build is obsolete: it is cancelled and a new build starts.
+/// All changes which queued up in the meantime cause a single rebuild.
+/// Internal errors are sent as `Event::Error`; the build is retried
+/// after a backoff and a failed file watcher is recreated.
+pub struct BuildLoops {
+    loops: HashMap<NixFile, ProjectLoop>,
+    /// Watches the input files of all projects.
+    /// `None` if no watcher could be created yet.
+    watch: Option<Watch>,
+    /// The watcher reported an error or died; `watch` has to be
+    /// recreated before the next build.
+    watch_failed: bool,
+    /// Delays recreating a failed watcher.
+    watch_backoff: Backoff,
+    /// When to try recreating the failed watcher next.
+    renew_watch_at: Option<Instant>,
+    scheduler: Scheduler,
+    /// The builds running on `scheduler` report back on this channel.
+    updates_tx: chan::Sender<BuildUpdate>,
+    updates_rx: chan::Receiver<BuildUpdate>,
+    events_tx: chan::Sender<Event>,
+    /// The receiver of `events_tx` is gone.
+    hung_up: bool,
+    stale_after: Option<Duration>,
+    debounce: Duration,
+    /// The id of the next build, see `BuildUpdate`.
+    next_build_id: u64,
+}
+
+/// The state of a single project in `BuildLoops`.
+struct ProjectLoop {
+    project: Project,
+    /// The environment to build in, see `Ping::Activity`.
+    env: Option<EvalEnv>,
+    /// Why the project has to be built next, if it has to.
+    reason: Option<Reason>,
+    /// Its input files changed, so the next build waits until this time.
+    quiet_until: Option<Instant>,
+    /// The build which waits for a worker or runs.
+    build: Option<Build>,
+    /// Wall clock time, because it can come from before the daemon restarted.
+    last_ping: SystemTime,
+    /// Whether we skipped a build because nobody pinged us for a while.
+    stale: bool,
+    /// Never go stale, see `BuildLoops::keep_fresh`.
+    keep_fresh: bool,
+    backoff: Backoff,
+    /// After an internal error, the build is retried at this time.
+    retry: Option<(Instant, Reason)>,
+    /// The results of the latest successful build.
+    output_paths: Option<builder::OutputPaths<roots::RootPath>>,
+}
+
+/// A build submitted to the `Scheduler`.
+struct Build {
+    id: u64,
+    cancel_tx: chan::Sender<()>,
+    reason: Reason,
+    /// Whether a worker picked up the build.
+    running: bool,
+}
+
+/// What a build running on a worker reports back to its `BuildLoops`,
+/// see `BuildLoops::updated`.
+pub struct BuildUpdate {
+    nix_file: NixFile,
+    /// Updates of builds which were cancelled and forgotten are ignored.
+    id: u64,
+    kind: UpdateKind,
+}
+
+enum UpdateKind {
+    Started,
+    Progress(BuildProgress),
+    Finished(BuildThreadResult),
+}
+
+impl ProjectLoop {
+    fn new(project: Project) -> ProjectLoop {
+        ProjectLoop {
+            reason: Some(Reason::ProjectAdded(project.nix_file.clone())),
+            project,
+            env: None,
+            quiet_until: None,
+            build: None,
+            last_ping: SystemTime::now(),
+            stale: false,
+            keep_fresh: false,
+            backoff: Backoff::new(),
+            retry: None,
+            output_paths: None,
+        }
+    }
+
+    fn add_reason(&mut self, reason: Reason) {
+        self.reason = Some(merge_reasons(self.reason.take(), reason));
+    }
+
+    /// Build in `env` from now on, if it is given.
+    /// Returns whether it differs from the environment of the previous builds.
+    fn update_env(&mut self, env: Option<EvalEnv>) -> bool {
+        match env {
+            Some(env) if self.env.as_ref() != Some(&env) => {
+                debug!("evaluation environment changed"; "nix_file" => &self.project.nix_file, "env" => ?env);
+                self.env = Some(env);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the project has to be built and may be built now.
+    fn build_due(&self, now: Instant) -> bool {
+        self.build.is_none()
+            && self.reason.is_some()
+            && self
+                .quiet_until
+                .map_or(true, |quiet_until| quiet_until <= now)
+    }
+
+    /// The next time `BuildLoops::tick` has something to do for this project.
+    /// The end of the quiet period only counts if a build can start then.
+    fn deadline(&self, can_build: bool) -> Option<Instant> {
+        let quiet_until = match self.build {
+            None if can_build && self.reason.is_some() => self.quiet_until,
+            _ => None,
+        };
+        let retry = self.retry.as_ref().map(|(at, _)| *at);
+        quiet_until.into_iter().chain(retry).min()
+    }
+}
+
+impl BuildLoops {
+    /// Run up to `max_builds` builds at the same time, see `BuildLoop::debounce`
+    /// and `BuildLoop::stale_after`. All events are sent to `events_tx`.
+    pub fn new(
+        max_builds: usize,
+        debounce: Duration,
+        stale_after: Option<Duration>,
+        events_tx: chan::Sender<Event>,
+    ) -> BuildLoops {
+        let mut watch_backoff = Backoff::new();
+        let (watch, renew_watch_at) = match Watch::try_new() {
+            Ok(watch) => (Some(watch), None),
+            Err(e) => {
+                warn!("failed to create the file watcher"; "error" => ?e);
+                (None, Some(Instant::now() + watch_backoff.next()))
+            }
+        };
+        let (updates_tx, updates_rx) = chan::unbounded();
+        BuildLoops {
+            loops: HashMap::new(),
+            watch_failed: watch.is_none(),
+            watch,
+            watch_backoff,
+            renew_watch_at,
+            scheduler: Scheduler::new(max_builds),
+            updates_tx,
+            updates_rx,
+            events_tx,
+            hung_up: false,
+            stale_after,
+            debounce,
+            next_build_id: 0,
+        }
+    }
+
+    /// Whether the receiver of the events is gone.
+    pub fn hung_up(&self) -> bool {
+        self.hung_up
+    }
+
+    /// The file change events of the watcher, to be passed to `BuildLoops::notified`.
+    pub fn notifications(&self) -> chan::Receiver<notify::Result<notify::Event>> {
+        match &self.watch {
+            Some(watch) if !self.watch_failed => watch.rx.clone(),
+            _ => chan::never(),
+        }
+    }
+
+    /// The updates of the running builds, to be passed to `BuildLoops::updated`.
+    pub fn updates(&self) -> chan::Receiver<BuildUpdate> {
+        self.updates_rx.clone()
+    }
+
+    /// Fires when `BuildLoops::tick` has something to do.
+    pub fn timer(&self) -> chan::Receiver<Instant> {
+        let deadline = self
+            .loops
+            .values()
+            .filter_map(|project_loop| project_loop.deadline(!self.watch_failed))
+            .chain(self.renew_watch_at)
+            .min();
+        match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                chan::after(if deadline > now {
+                    deadline - now
+                } else {
+                    Duration::from_secs(0)
+                })
+            }
+            None => chan::never(),
+        }
+    }
+
+    /// Pass `ping` to the loop of `project`, which is added first
+    /// (and built) if it is not there yet.
+    pub fn ping(&mut self, project: &Project, ping: Ping) {
+        let nix_file = &project.nix_file;
+        let now = SystemTime::now();
+        self.scheduler.ping(nix_file, now);
+        let mut added = false;
+        let project_loop = self.loops.entry(nix_file.clone()).or_insert_with(|| {
+            added = true;
+            ProjectLoop::new(project.clone())
+        });
+        project_loop.last_ping = now;
+        match ping {
+            Ping::Rebuild { refresh } => {
+                project_loop.stale = false;
+                project_loop.quiet_until = None;
+                project_loop.add_reason(Reason::RebuildRequested {
+                    refresh,
+                    files: vec![],
+                });
+                // the running build might not pick up what the
+                // user changed, so build again afterwards
+                self.supersede(nix_file, false);
+            }
+            // the first build uses the environment anyway
+            Ping::Activity { env } if added => {
+                project_loop.update_env(env);
+            }
+            Ping::Activity { env } => {
+                if project_loop.update_env(env) {
+                    project_loop.stale = false;
+                    project_loop.quiet_until = None;
+                    project_loop.add_reason(Reason::EnvironmentChanged);
+                    // the running build uses the old environment
+                    self.supersede(nix_file, true);
+                } else if project_loop.stale {
+                    project_loop.stale = false;
+                    project_loop.add_reason(Reason::PingReceived);
+                } else if project_loop.build.is_none() && project_loop.reason.is_none() {
+                    let gc_root_missing = project_loop
+                        .output_paths
+                        .as_ref()
+                        .map_or(false, |output_paths| !output_paths.shell_gc_root_is_dir());
+                    if gc_root_missing {
+                        project_loop.add_reason(Reason::PingReceived);
+                    }
+                }
+            }
+        }
+        self.start_due_builds();
+    }
+
+    /// Whether `nix_file` is not built on file changes anymore,
+    /// because it was not pinged for a while (see `BuildLoop::stale_after`).
+    pub fn is_stale(&self, nix_file: &NixFile) -> bool {
+        self.loops
+            .get(nix_file)
+            .map_or(false, |project_loop| project_loop.stale)
+    }
+
+    /// Whether a build of `nix_file` waits for the end of the quiet period
+    /// or for a worker, or runs.
+    pub fn has_pending_build(&self, nix_file: &NixFile) -> bool {
+        self.loops.get(nix_file).map_or(false, |project_loop| {
+            project_loop.build.is_some() || project_loop.reason.is_some()
+        })
+    }
+
+    /// Keep building `nix_file` on file changes even if it is not pinged,
+    /// e.g. while clients run its services.
+    pub fn keep_fresh(&mut self, nix_file: &NixFile, keep_fresh: bool) {
+        if let Some(project_loop) = self.loops.get_mut(nix_file) {
+            project_loop.keep_fresh = keep_fresh;
+        }
+    }
+
+    /// Record that `nix_file` was last pinged at `last_ping`, e.g. before
+    /// the daemon restarted. This sets its scheduling priority and when it
+    /// goes stale.
+    pub fn set_last_ping(&mut self, nix_file: &NixFile, last_ping: SystemTime) {
+        self.scheduler.ping(nix_file, last_ping);
+        if let Some(project_loop) = self.loops.get_mut(nix_file) {
+            project_loop.last_ping = last_ping;
+        }
+    }
+
+    /// Stop building and watching `nix_file`. A running build is cancelled,
+    /// and none of its results are used.
+    /// Returns whether the project was known.
+    pub fn remove(&mut self, nix_file: &NixFile) -> bool {
+        let project_loop = match self.loops.remove(nix_file) {
+            Some(project_loop) => project_loop,
+            None => return false,
+        };
+        if let Some(build) = project_loop.build {
+            if !self.scheduler.withdraw(nix_file) {
+                let _ = build.cancel_tx.try_send(());
+            }
+        }
+        self.scheduler.forget(nix_file);
+        if let Some(watch) = &mut self.watch {
+            watch.remove(nix_file);
+        }
+        true
+    }
+
+    /// Cancel all builds and wait until the workers stopped.
+    pub fn shutdown(&mut self) {
+        let nix_files: Vec<NixFile> = self.loops.keys().cloned().collect();
+        for nix_file in nix_files {
+            self.remove(&nix_file);
+        }
+        self.scheduler.close();
+    }
+
+    /// Handle a message received on `BuildLoops::notifications`.
+    pub fn notified(&mut self, msg: Result<notify::Result<notify::Event>, chan::RecvError>) {
+        let routes = match msg {
+            Ok(event) => match &self.watch {
+                Some(watch) => watch.process(event),
+                None => Ok(vec![]),
+            },
+            Err(chan::RecvError) => Err(EventError::RxNoEventReceived),
+        };
+        match routes {
+            Ok(routes) => {
+                // the watcher works again
+                self.watch_backoff.reset();
+                for (nix_file, reason) in routes {
+                    self.inputs_changed(&nix_file, reason)
+                }
+            }
+            Err(e) => {
+                let reason = self.translate_error(e);
+                let nix_files: Vec<NixFile> = self.loops.keys().cloned().collect();
+                for nix_file in nix_files {
+                    self.inputs_changed(&nix_file, reason.clone())
+                }
+                // a watcher which keeps failing must not keep us busy
+                if self.watch_failed && self.renew_watch_at.is_none() {
+                    self.renew_watch_at = Some(Instant::now() + self.watch_backoff.next());
+                }
+            }
+        }
+        self.start_due_builds();
+    }
+
+    /// Handle a message received on `BuildLoops::updates`.
+    pub fn updated(&mut self, update: BuildUpdate) {
+        let BuildUpdate { nix_file, id, kind } = update;
+        let project_loop = match self.loops.get_mut(&nix_file) {
+            Some(project_loop) if project_loop.build.as_ref().map(|b| b.id) == Some(id) => {
+                project_loop
+            }
+            // the project was removed while the build ran
+            _ => return,
+        };
+        match kind {
+            UpdateKind::Started => {
+                let build = project_loop.build.as_mut().expect("checked above");
+                build.running = true;
+                let reason = build.reason.clone();
+                self.send(Event::Started { nix_file, reason })
+            }
+            UpdateKind::Progress(progress) => self.send(Event::Progress { nix_file, progress }),
+            UpdateKind::Finished((run_result, log_lines)) => {
+                let build = project_loop.build.take().expect("checked above");
+                self.finish_build(nix_file, build.reason, run_result, log_lines)
+            }
+        }
+        self.start_due_builds();
+    }
+
+    /// Handle a message received on `BuildLoops::timer`.
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+        for project_loop in self.loops.values_mut() {
+            match project_loop.retry.take() {
+                Some((at, reason)) if at <= now => project_loop.add_reason(reason),
+                retry => project_loop.retry = retry,
+            }
+        }
+        if self.watch_failed && self.renew_watch_at.map_or(false, |at| at <= now) {
+            self.renew_watch();
+        }
+        self.start_due_builds();
+    }
+
+    /// Record that input files of `nix_file` changed.
+    fn inputs_changed(&mut self, nix_file: &NixFile, reason: Reason) {
+        let stale_after = self.stale_after;
+        let project_loop = match self.loops.get_mut(nix_file) {
+            Some(project_loop) => project_loop,
+            None => return,
+        };
+        match &project_loop.build {
+            None => match stale_after {
+                Some(duration)
+                    if !project_loop.keep_fresh
+                        // a `last_ping` in the future (the clock was changed) is never stale
+                        && project_loop
+                            .last_ping
+                            .elapsed()
+                            .map_or(false, |elapsed| elapsed > duration) =>
+                {
+                    if !project_loop.stale {
+                        info!("project is stale, building on next ping"; "nix_file" => nix_file, "reason" => ?reason);
+                    }
+                    project_loop.stale = true;
+                    return;
+                }
+                _ => {}
+            },
+            Some(build) if build.running && project_loop.reason.is_none() => {
+                info!("inputs changed, cancelling the running build"; "nix_file" => nix_file);
+            }
+            Some(_) => {}
+        }
+        project_loop.add_reason(reason);
+        // Editors, formatters or a `git pull` change many files
+        // in a row; wait until they are done.
+        project_loop.quiet_until = Some(Instant::now() + self.debounce);
+        self.supersede(nix_file, true);
+    }
+
+    /// The build of `nix_file` is obsolete because of its pending reason.
+    /// If it still waits for a worker, it is withdrawn and the pending
+    /// build includes its reason. If it runs and `cancel_running` is set,
+    /// it is cancelled; the pending build starts once it finished.
+    fn supersede(&mut self, nix_file: &NixFile, cancel_running: bool) {
+        let project_loop = match self.loops.get_mut(nix_file) {
+            Some(project_loop) => project_loop,
+            None => return,
+        };
+        let withdrawn = match &project_loop.build {
+            Some(build) if !build.running && self.scheduler.withdraw(nix_file) => true,
+            Some(build) => {
+                if cancel_running {
+                    // a cancellation might be pending already
+                    let _ = build.cancel_tx.try_send(());
+                }
+                false
+            }
+            None => false,
+        };
+        if withdrawn {
+            let build = project_loop.build.take().expect("checked above");
+            project_loop.reason = Some(match project_loop.reason.take() {
+                Some(reason) => merge_reasons(Some(build.reason), reason),
+                None => build.reason,
+            });
+        }
+    }
+
+    /// Turn an error of the watcher into a build reason.
+    /// If the watcher failed, it is recreated before the next build.
+    fn translate_error(&mut self, error: EventError) -> Reason {
+        match error {
             // we should continue and just cite an unknown reason
-            Err(EventError::EventHasNoFilePath(msg)) => {
+            EventError::EventHasNoFilePath(msg) => {
                 warn!(
                     "event has no file path; possible issue with the watcher?";
                     "message" => ?msg
                 );
                 // can’t Clone `Event`s, so we return the Debug output here
-                Reason::UnknownEvent(DebugMessage::from(format!("{:#?}", msg)))
+                Reason::UnknownEvent {
+                    message: DebugMessage::from(format!("{:#?}", msg)),
+                    files: vec![],
+                }
             }
-            Err(EventError::RxNoEventReceived) => {
-                panic!("The file watcher died!");
+            // changes might have been missed, so we build again
+            EventError::Notify(msg) => {
+                warn!("the file watcher failed, recreating it"; "error" => ?msg);
+                self.watch_failed = true;
+                Reason::UnknownEvent {
+                    message: msg,
+                    files: vec![],
+                }
             }
-        };
-
-        // The project has just been added, so run the builder in the first iteration
-        let mut reason = Some(Event::Started(Reason::ProjectAdded(
-            self.project.nix_file.clone(),
-        )));
-        let mut output_paths = None;
+            EventError::RxNoEventReceived => {
+                warn!("the file watcher died, recreating it");
+                self.watch_failed = true;
+                Reason::UnknownEvent {
+                    message: DebugMessage::from(String::from("the file watcher died")),
+                    files: vec![],
+                }
+            }
+        }
+    }
 
-        // Drain pings initially: we're going to trigger a first build anyway
-        rx_ping.try_iter().for_each(drop);
+    /// Replace the failed `Watch` (if there is one) by a new one
+    /// which watches the same paths. If that fails, all projects
+    /// are told and it is tried again after a backoff.
+    fn renew_watch(&mut self) {
+        let renewed = match &self.watch {
+            Some(failed) => failed.renew(),
+            None => Watch::try_new(),
+        };
+        match renewed {
+            Ok(watch) => {
+                info!("created the file watcher");
+                self.watch = Some(watch);
+                self.watch_failed = false;
+                self.renew_watch_at = None;
+            }
+            Err(e) => {
+                let error = LoopError::Watch(format!("{:?}", e));
+                let nix_files: Vec<NixFile> = self.loops.keys().cloned().collect();
+                for nix_file in nix_files {
+                    self.send(Event::Error {
+                        nix_file,
+                        error: error.clone(),
+                    })
+                }
+                self.renew_watch_at = Some(Instant::now() + self.watch_backoff.next());
+            }
+        }
+    }
 
-        let rx_notify = self.watch.rx.clone();
+    /// Submit a build of every project which has to be built and may be built now.
+    /// Without a working watcher, we would miss changes to the new inputs.
+    fn start_due_builds(&mut self) {
+        if self.watch_failed {
+            return;
+        }
+        let now = Instant::now();
+        for (nix_file, project_loop) in self.loops.iter_mut() {
+            if !project_loop.build_due(now) {
+                continue;
+            }
+            let mut reason = project_loop.reason.take().expect("checked by build_due");
+            if let Some((_, earlier)) = project_loop.retry.take() {
+                reason = merge_reasons(Some(earlier), reason);
+            }
+            project_loop.quiet_until = None;
+            let refresh = match reason {
+                Reason::RebuildRequested { refresh, .. } => refresh,
+                _ => false,
+            };
+            let (cancel_tx, cancel_rx) = chan::bounded(1);
+            let id = self.next_build_id;
+            self.next_build_id += 1;
+            self.scheduler.submit(
+                nix_file,
+                build_job(
+                    &project_loop.project,
+                    project_loop.env.clone(),
+                    refresh,
+                    cancel_rx,
+                    id,
+                    self.updates_tx.clone(),
+                ),
+            );
+            project_loop.build = Some(Build {
+                id,
+                cancel_tx,
+                reason,
+                running: false,
+            });
+        }
+    }
 
-        loop {
-            // If there is some reason to build, run the build!
-            if let Some(rsn) = reason {
-                send(rsn);
-                match self.once() {
-                    Ok(result) => {
-                        output_paths = Some(result.output_paths.clone());
-                        send(Event::Completed(result));
-                    }
-                    Err(BuildError::Recoverable(failure)) => send(Event::Failure(failure)),
-                    Err(BuildError::Unrecoverable(err)) => {
-                        panic!("Unrecoverable error:\n{:#?}", err);
-                    }
+    /// Watch the files the finished build referenced, root its result
+    /// and tell the events receiver how it went.
+    fn finish_build(
+        &mut self,
+        nix_file: NixFile,
+        reason: Reason,
+        run_result: Result<builder::RunResult, builder::Error>,
+        log_lines: Vec<OsString>,
+    ) {
+        let project_loop = self.loops.get_mut(&nix_file).expect("checked by caller");
+        let (result, durations) = match run_result {
+            // the pending reason is built next, which includes this one
+            Ok(builder::RunResult {
+                status: RunStatus::Cancelled,
+                ..
+            }) => {
+                project_loop.reason = Some(match project_loop.reason.take() {
+                    Some(pending) => merge_reasons(Some(reason), pending),
+                    None => reason,
+                });
+                self.send(Event::Cancelled { nix_file });
+                return;
+            }
+            Ok(run_result) => {
+                let durations = run_result.durations;
+                let result =
+                    register_paths(&mut self.watch, &nix_file, &run_result.referenced_paths)
+                        .map_err(BuildError::from)
+                        .and_then(|()| {
+                            build_result(&project_loop.project, run_result.status, log_lines)
+                        });
+                (result, durations)
+            }
+            Err(e) => (Err(BuildError::from(e)), builder::Durations::default()),
+        };
+        let metrics = BuildMetrics {
+            durations,
+            watched_paths: self
+                .watch
+                .as_ref()
+                .map_or(0, |watch| watch.paths(&nix_file).len()),
+        };
+        let event = match result {
+            Ok(result) => {
+                project_loop.backoff.reset();
+                project_loop.output_paths = Some(result.output_paths.clone());
+                Event::Completed {
+                    nix_file,
+                    result,
+                    metrics,
                 }
-                reason = None;
             }
-
-            chan::select! {
-                recv(rx_notify) -> msg => if let Ok(msg) = msg {
-                    if let Some(rsn) = self.watch.process(msg) {
-                        reason = Some(Event::Started(translate_reason(rsn)));
-                    }
-                },
-                recv(rx_ping) -> msg => if let (Ok(()), Some(output_paths)) = (msg, &output_paths) {
-                    if !output_paths.shell_gc_root_is_dir() {
-                        reason = Some(Event::Started(Reason::PingReceived));
-                    }
-                },
+            Err(BuildError::Recoverable(failure)) => {
+                project_loop.backoff.reset();
+                Event::Failure {
+                    nix_file,
+                    failure,
+                    metrics,
+                }
             }
+            Err(BuildError::Unrecoverable(err)) => {
+                let delay = project_loop.backoff.next();
+                warn!("build failed to run, retrying"; "nix_file" => &nix_file, "error" => ?err, "delay" => ?delay);
+                project_loop.retry = Some((Instant::now() + delay, reason));
+                Event::Error {
+                    nix_file,
+                    error: LoopError::Build(format!("{:?}", err)),
+                }
+            }
+        };
+        self.send(event)
+    }
+
+    fn send(&mut self, event: Event) {
+        if self.events_tx.send(event).is_err() && !self.hung_up {
+            debug!("nobody listens to the build events anymore");
+            self.hung_up = true;
         }
     }
+}
 
-    /// Execute a single build of the environment.
-    ///
-    /// This will create GC roots and expand the file watch list for
-    /// the evaluation.
-    pub fn once(&mut self) -> Result<BuildResults, BuildError> {
-        let (tx, rx) = chan::unbounded();
-        let run_result = builder::run(tx, &self.project.nix_file, &self.project.cas)?;
+/// A cancellable build (see `builder::run`) of `project`, to run on a worker
+/// of the `Scheduler`. It reports its start, progress and result to `updates`.
+fn build_job(
+    project: &Project,
+    env: Option<EvalEnv>,
+    refresh: bool,
+    cancel: chan::Receiver<()>,
+    id: u64,
+    updates: chan::Sender<BuildUpdate>,
+) -> Job {
+    let nix_file = project.nix_file.clone();
+    let cas = project.cas.clone();
+    Box::new(move || {
+        // the build loops might not wait for the updates anymore
+        let update = |kind| {
+            let _ = updates.send(BuildUpdate {
+                nix_file: nix_file.clone(),
+                id,
+                kind,
+            });
+        };
+        update(UpdateKind::Started);
+        let (tx, rx) = chan::unbounded::<OsString>();
+        let (events_tx, events_rx) = chan::unbounded();
+        let progress_updates = updates.clone();
+        let progress_nix_file = nix_file.clone();
+        let lines = thread::spawn(move || {
+            track_progress(rx, events_rx, |progress| {
+                let _ = progress_updates.send(BuildUpdate {
+                    nix_file: progress_nix_file.clone(),
+                    id,
+                    kind: UpdateKind::Progress(progress),
+                });
+            })
+        });
+        let mut opts = builder::RunOpts::default();
+        opts.set_log_event_sender(events_tx)
+            .set_refresh(refresh)
+            .set_cancel_receiver(cancel);
+        if let Some(env) = env {
+            opts.set_env(env);
+        }
+        let run_result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            builder::run(tx, &nix_file, &cas, &opts)
+        }))
+        .unwrap_or_else(|panic| Err(builder::Error::from(panic)));
+        // `builder::run` dropped `tx` and `opts` holds the last events
+        // sender, so all lines and events arrived after this
+        drop(opts);
+        let lines = lines.join().unwrap_or_default();
+        update(UpdateKind::Finished((run_result, lines)));
+    })
+}
 
-        self.register_paths(&run_result.referenced_paths)?;
+/// Collect the log `lines` of a build until it finished and call `on_progress`
+/// whenever the build got further. The progress is followed by the logged
+/// `events` if nix supports the internal-json log format, by the lines otherwise.
+#[allow(clippy::drop_copy, clippy::zero_ptr)] // triggered by `select!`
+fn track_progress<F>(
+    mut lines: chan::Receiver<OsString>,
+    mut events: chan::Receiver<LogEvent>,
+    on_progress: F,
+) -> Vec<OsString>
+where
+    F: Fn(BuildProgress),
+{
+    let structured = crate::nix::log::internal_json_supported();
+    let mut tracker = progress::Tracker::default();
+    let mut log_lines = vec![];
+    let (mut lines_done, mut events_done) = (false, false);
+    while !(lines_done && events_done) {
+        let changed = chan::select! {
+            recv(lines) -> line => match line {
+                Ok(line) => {
+                    let changed = !structured && tracker.update(&line);
+                    log_lines.push(line);
+                    changed
+                }
+                Err(chan::RecvError) => {
+                    lines_done = true;
+                    lines = chan::never();
+                    false
+                }
+            },
+            recv(events) -> event => match event {
+                Ok(event) => tracker.update_event(&event),
+                Err(chan::RecvError) => {
+                    events_done = true;
+                    events = chan::never();
+                    false
+                }
+            },
+        };
+        if changed {
+            on_progress(tracker.progress());
+        }
+    }
+    log_lines
+}
+
+/// Add the (reduced) paths a build of `nix_file` referenced to the watch list;
+/// without a watcher, there is nothing to add them to.
+fn register_paths(
+    watch: &mut Option<Watch>,
+    nix_file: &NixFile,
+    paths: &[PathBuf],
+) -> Result<(), notify::Error> {
+    let original_paths_len = paths.len();
+    let paths = reduce_paths(paths);
+    debug!("paths reduced"; "from" => original_paths_len, "to" => paths.len());
+
+    if let Some(watch) = watch {
+        watch.extend(nix_file, &paths.into_iter().collect::<Vec<_>>())?;
+    }
 
-        let lines = rx.iter().collect();
+    Ok(())
+}
+
+/// Root the result of a build of `project` which was not cancelled.
+fn build_result(
+    project: &Project,
+    status: RunStatus,
+    lines: Vec<OsString>,
+) -> Result<BuildResults, BuildError> {
+    match status {
+        RunStatus::FailedAtInstantiation => Err(BuildError::Recoverable(BuildExitFailure {
+            log_lines: lines,
+        })),
+        RunStatus::FailedAtRealize => Err(BuildError::Recoverable(BuildExitFailure {
+            log_lines: lines,
+        })),
+        // only whoever cancelled the build sees this, and they don’t care about the result
+        RunStatus::Cancelled => Err(BuildError::Recoverable(BuildExitFailure {
+            log_lines: lines,
+        })),
+        RunStatus::Complete(path) => {
+            let roots = Roots::from_project(project);
 
-        match run_result.status {
-            RunStatus::FailedAtInstantiation => Err(BuildError::Recoverable(BuildExitFailure {
-                log_lines: lines,
-            })),
-            RunStatus::FailedAtRealize => Err(BuildError::Recoverable(BuildExitFailure {
-                log_lines: lines,
-            })),
-            RunStatus::Complete(path) => self.root_result(path),
+            Ok(BuildResults {
+                output_paths: roots.create_roots(path)?,
+            })
         }
     }
+}
+
+/// The result of `builder::run` in a job started by `build_job`,
+/// together with the log lines of the build.
+type BuildThreadResult = (Result<builder::RunResult, builder::Error>, Vec<OsString>);
 
-    fn register_paths(&mut self, paths: &[PathBuf]) -> Result<(), notify::Error> {
-        let original_paths_len = paths.len();
-        let paths = reduce_paths(&paths);
-        debug!("paths reduced"; "from" => original_paths_len, "to" => paths.len());
+/// Exponentially growing delays between retries after internal errors.
+struct Backoff {
+    next: Duration,
+}
 
-        // add all new (reduced) nix sources to the input source watchlist
-        self.watch.extend(&paths.into_iter().collect::<Vec<_>>())?;
+/// The first delay of a `Backoff`.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+/// The delays of a `Backoff` don’t grow beyond this.
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
 
-        Ok(())
+impl Backoff {
+    fn new() -> Backoff {
+        Backoff { next: MIN_BACKOFF }
     }
 
-    fn root_result(&mut self, build: builder::RootedPath) -> Result<BuildResults, BuildError> {
-        let roots = Roots::from_project(&self.project);
+    /// The delay before the next retry. Each call doubles the delay.
+    fn next(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (delay * 2).min(MAX_BACKOFF);
+        delay
+    }
 
-        Ok(BuildResults {
-            output_paths: roots.create_roots(build)?,
-        })
+    /// Start over with the shortest delay, after everything worked again.
+    fn reset(&mut self) {
+        self.next = MIN_BACKOFF;
+    }
+}
+
+/// The reason for a single build which replaces two builds.
+/// A requested rebuild wins, then a strange watcher event, then changed
+/// files, then the later reason. The changed files of both are kept.
+fn merge_reasons(earlier: Option<Reason>, later: Reason) -> Reason {
+    let earlier = match earlier {
+        Some(earlier) => earlier,
+        None => return later,
+    };
+    let mut files = earlier.files().to_vec();
+    for file in later.files() {
+        if !files.contains(file) {
+            files.push(file.clone());
+        }
+    }
+    let merged = match (earlier, later) {
+        // a requested rebuild must not lose its refresh
+        (
+            Reason::RebuildRequested { refresh, .. },
+            Reason::RebuildRequested { refresh: again, .. },
+        ) => Reason::RebuildRequested {
+            refresh: refresh || again,
+            files: vec![],
+        },
+        (rebuild @ Reason::RebuildRequested { .. }, _)
+        | (_, rebuild @ Reason::RebuildRequested { .. }) => rebuild,
+        (_, unknown @ Reason::UnknownEvent { .. }) | (unknown @ Reason::UnknownEvent { .. }, _) => {
+            unknown
+        }
+        (_, later) => later,
+    };
+    with_files(merged, files)
+}
+
+/// `reason` with `files` as its changed files.
+/// Other reasons without files turn into `Reason::FilesChanged`.
+fn with_files(reason: Reason, files: Vec<PathBuf>) -> Reason {
+    match reason {
+        Reason::RebuildRequested { refresh, .. } => Reason::RebuildRequested { refresh, files },
+        Reason::UnknownEvent { message, .. } => Reason::UnknownEvent { message, files },
+        other if files.is_empty() => other,
+        _ => Reason::FilesChanged(files),
     }
 }
 
@@ -212,3 +1148,280 @@ impl From<notify::Error> for BuildError {
         BuildError::Unrecoverable(UnrecoverableErrors::Notify(e))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ShellArgs;
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let mut backoff = Backoff::new();
+        assert_eq!(backoff.next(), MIN_BACKOFF);
+        assert_eq!(backoff.next(), MIN_BACKOFF * 2);
+        assert_eq!(backoff.next(), MIN_BACKOFF * 4);
+        while backoff.next() < MAX_BACKOFF {}
+        assert_eq!(backoff.next(), MAX_BACKOFF);
+        backoff.reset();
+        assert_eq!(backoff.next(), MIN_BACKOFF);
+    }
+
+    #[test]
+    fn requested_rebuild_is_not_merged_away() {
+        let rebuild = |refresh| Reason::RebuildRequested {
+            refresh,
+            files: vec![],
+        };
+        match merge_reasons(Some(rebuild(true)), changed()) {
+            Reason::RebuildRequested { refresh: true, .. } => {}
+            other => panic!("expected a refreshing rebuild, got {:?}", other),
+        }
+        match merge_reasons(Some(rebuild(false)), rebuild(true)) {
+            Reason::RebuildRequested { refresh: true, .. } => {}
+            other => panic!("expected a refreshing rebuild, got {:?}", other),
+        }
+        match merge_reasons(Some(changed()), rebuild(false)) {
+            Reason::RebuildRequested { refresh: false, .. } => {}
+            other => panic!("expected a rebuild, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn merged_reasons_keep_changed_files() {
+        let files = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<_>>();
+        let changed = |names: &[&str]| Reason::FilesChanged(files(names));
+        let unknown = || Reason::UnknownEvent {
+            message: DebugMessage::from(String::from("strange event")),
+            files: vec![],
+        };
+
+        let merged = merge_reasons(
+            Some(changed(&["a.nix", "b.nix"])),
+            changed(&["b.nix", "c.nix"]),
+        );
+        assert_eq!(merged.files().to_vec(), files(&["a.nix", "b.nix", "c.nix"]));
+
+        let merged = merge_reasons(Some(changed(&["a.nix"])), unknown());
+        match &merged {
+            Reason::UnknownEvent { .. } => {}
+            other => panic!("expected an unknown event, got {:?}", other),
+        }
+        assert_eq!(merged.files().to_vec(), files(&["a.nix"]));
+
+        let merged = merge_reasons(Some(changed(&["a.nix"])), Reason::EnvironmentChanged);
+        assert_eq!(merged.files().to_vec(), files(&["a.nix"]));
+
+        let merged = merge_reasons(
+            Some(Reason::RebuildRequested {
+                refresh: true,
+                files: vec![],
+            }),
+            merge_reasons(Some(changed(&["a.nix"])), unknown()),
+        );
+        let merged = merge_reasons(Some(merged), changed(&["b.nix"]));
+        match &merged {
+            Reason::RebuildRequested { refresh: true, .. } => {}
+            other => panic!("expected a refreshing rebuild, got {:?}", other),
+        }
+        assert_eq!(merged.files().to_vec(), files(&["a.nix", "b.nix"]));
+    }
+
+    /// Changes in quick succession cause a single build once they stop.
+    #[test]
+    fn changes_are_debounced() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let project = project(tempdir.path())?;
+        let nix_file = &project.nix_file;
+        let (tx, _rx) = chan::unbounded();
+        let debounce = Duration::from_millis(200);
+        let mut loops = BuildLoops::new(1, debounce, None, tx);
+        loops.ping(&project, Ping::Activity { env: None });
+        fail_build(&mut loops, nix_file);
+        let changed = |name| Reason::FilesChanged(vec![PathBuf::from(name)]);
+
+        loops.inputs_changed(nix_file, changed("a.nix"));
+        thread::sleep(debounce / 4);
+        loops.inputs_changed(nix_file, changed("b.nix"));
+        thread::sleep(debounce / 4);
+        loops.tick();
+        assert!(
+            loops.loops[nix_file].build.is_none(),
+            "the second change did not restart the quiet period"
+        );
+
+        loops
+            .timer()
+            .recv_timeout(debounce * 2)
+            .expect("the quiet period did not end");
+        loops.tick();
+        match &loops.loops[nix_file].build {
+            Some(Build {
+                reason: Reason::FilesChanged(files),
+                ..
+            }) => assert_eq!(files, &vec![PathBuf::from("a.nix"), PathBuf::from("b.nix")]),
+            _ => panic!("expected a single build for both changes"),
+        }
+        loops.shutdown();
+        Ok(())
+    }
+
+    /// The build loop stops instead of panicking once nobody receives its events.
+    #[test]
+    fn stops_when_nobody_listens() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let project = project(tempdir.path())?;
+
+        let (tx, rx) = chan::unbounded();
+        drop(rx);
+        let (_ping_tx, ping_rx) = chan::unbounded();
+        BuildLoop::new(&project).forever(tx, ping_rx);
+        Ok(())
+    }
+
+    /// A project with an empty shell.nix in `dir`.
+    fn project(dir: &std::path::Path) -> std::io::Result<Project> {
+        let shell_nix = dir.join("shell.nix");
+        std::fs::write(&shell_nix, "{}")?;
+        let cas = crate::cas::ContentAddressable::new(dir.join("cas"))?;
+        Project::new(
+            NixFile::shell(shell_nix, ShellArgs::default()),
+            &dir.join("gc_roots"),
+            cas,
+        )
+    }
+
+    /// Report that the build of `nix_file` failed.
+    fn fail_build(loops: &mut BuildLoops, nix_file: &NixFile) {
+        let id = loops.loops[nix_file]
+            .build
+            .as_ref()
+            .expect("no build to finish")
+            .id;
+        let run_result = builder::RunResult {
+            referenced_paths: vec![],
+            status: RunStatus::FailedAtInstantiation,
+            durations: builder::Durations::default(),
+        };
+        loops.updated(BuildUpdate {
+            nix_file: nix_file.clone(),
+            id,
+            kind: UpdateKind::Finished((Ok(run_result), vec![])),
+        });
+    }
+
+    fn changed() -> Reason {
+        Reason::FilesChanged(vec![PathBuf::from("shell.nix")])
+    }
+
+    /// Projects which were not pinged for a while are built on the next ping,
+    /// not on file changes.
+    #[test]
+    fn stale_projects_are_built_on_the_next_ping() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let project = project(tempdir.path())?;
+        let nix_file = &project.nix_file;
+        let (tx, _rx) = chan::unbounded();
+        let stale_after = Duration::from_millis(10);
+        let mut loops = BuildLoops::new(1, DEFAULT_DEBOUNCE, Some(stale_after), tx);
+        loops.ping(&project, Ping::Activity { env: None });
+        fail_build(&mut loops, nix_file);
+        thread::sleep(stale_after * 2);
+
+        loops.inputs_changed(nix_file, changed());
+        assert!(loops.is_stale(nix_file));
+        assert!(loops.loops[nix_file].reason.is_none());
+
+        loops.ping(&project, Ping::Activity { env: None });
+        assert!(!loops.is_stale(nix_file));
+        match &loops.loops[nix_file].build {
+            Some(Build {
+                reason: Reason::PingReceived,
+                ..
+            }) => {}
+            _ => panic!("expected a build because of the ping"),
+        }
+        loops.shutdown();
+        Ok(())
+    }
+
+    /// Projects restored by the daemon keep the time of their latest ping.
+    #[test]
+    fn restored_projects_keep_their_last_ping() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let project = project(tempdir.path())?;
+        let nix_file = &project.nix_file;
+        let (tx, _rx) = chan::unbounded();
+        let stale_after = Duration::from_secs(60);
+        let mut loops = BuildLoops::new(1, DEFAULT_DEBOUNCE, Some(stale_after), tx);
+        loops.ping(&project, Ping::Activity { env: None });
+        loops.set_last_ping(nix_file, SystemTime::now() - stale_after * 60);
+        fail_build(&mut loops, nix_file);
+
+        loops.inputs_changed(nix_file, changed());
+        assert!(loops.is_stale(nix_file));
+        loops.shutdown();
+        Ok(())
+    }
+
+    /// Projects which are kept fresh are built on file changes without pings.
+    #[test]
+    fn fresh_projects_do_not_go_stale() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let project = project(tempdir.path())?;
+        let nix_file = &project.nix_file;
+        let (tx, _rx) = chan::unbounded();
+        let stale_after = Duration::from_millis(10);
+        let mut loops = BuildLoops::new(1, Duration::from_secs(0), Some(stale_after), tx);
+        loops.ping(&project, Ping::Activity { env: None });
+        loops.keep_fresh(nix_file, true);
+        fail_build(&mut loops, nix_file);
+        thread::sleep(stale_after * 2);
+
+        loops.inputs_changed(nix_file, changed());
+        loops.tick();
+        assert!(!loops.is_stale(nix_file));
+        match &loops.loops[nix_file].build {
+            Some(Build {
+                reason: Reason::FilesChanged(_),
+                ..
+            }) => {}
+            _ => panic!("expected a build because of the changed files"),
+        }
+        loops.shutdown();
+        Ok(())
+    }
+
+    /// Once a project is removed, its build is not reported anymore.
+    #[test]
+    fn builds_of_removed_projects_are_ignored() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let project = project(tempdir.path())?;
+        let (tx, rx) = chan::unbounded();
+        let mut loops = BuildLoops::new(1, DEFAULT_DEBOUNCE, None, tx);
+        loops.ping(&project, Ping::Activity { env: None });
+        let id = loops.loops[&project.nix_file]
+            .build
+            .as_ref()
+            .expect("the new project is built right away")
+            .id;
+        let started = || BuildUpdate {
+            nix_file: project.nix_file.clone(),
+            id,
+            kind: UpdateKind::Started,
+        };
+
+        loops.updated(started());
+        match rx.try_recv() {
+            Ok(Event::Started {
+                reason: Reason::ProjectAdded(_),
+                ..
+            }) => {}
+            other => panic!("expected the build to start, got {:?}", other),
+        }
+        assert!(loops.remove(&project.nix_file));
+        loops.updated(started());
+        assert!(rx.try_recv().is_err());
+        loops.shutdown();
+        Ok(())
+    }
+}
@@ -0,0 +1,280 @@
+//! Follow how far a build got, from the events nix logs in the
+//! internal-json format or, for older versions of nix, from the log
+//! lines it prints while building (see `builder::run`).
+
+use crate::nix::log::{ActivityType, Field, LogEvent, ResultType};
+use regex::Regex;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+
+/// How far a running build got.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BuildProgress {
+    /// How many derivations nix started building
+    pub builds_started: usize,
+    /// How many derivations nix is going to build, as far as it told us
+    pub builds_expected: usize,
+    /// How many paths nix started fetching from a binary cache
+    pub downloads_started: usize,
+    /// How many paths nix is going to fetch, as far as it told us
+    pub downloads_expected: usize,
+}
+
+impl std::fmt::Display for BuildProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "started building {} of {} derivations and fetching {} of {} paths",
+            self.builds_started,
+            self.builds_expected,
+            self.downloads_started,
+            self.downloads_expected
+        )
+    }
+}
+
+/// Which list nix is printing, see `Tracker::update`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Listing {
+    Nothing,
+    Builds,
+    Downloads,
+}
+
+/// Tracks the `BuildProgress` of a single build.
+#[derive(Debug)]
+pub struct Tracker {
+    progress: BuildProgress,
+    listing: Listing,
+    /// The types of the activities nix started, by id.
+    activities: HashMap<u64, ActivityType>,
+}
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Tracker {
+            progress: BuildProgress::default(),
+            listing: Listing::Nothing,
+            activities: HashMap::new(),
+        }
+    }
+}
+
+impl Tracker {
+    /// The progress so far.
+    pub fn progress(&self) -> BuildProgress {
+        self.progress
+    }
+
+    /// Look at a log line of the build. Returns whether nix started
+    /// building or fetching another path.
+    ///
+    /// Before it builds anything, nix lists what it is going to do:
+    ///
+    /// ```text
+    /// these derivations will be built:
+    ///   /nix/store/...-hello.drv
+    /// these paths will be fetched (0.04 MiB download, 0.19 MiB unpacked):
+    ///   /nix/store/...-glibc
+    /// building '/nix/store/...-hello.drv'...
+    /// copying path '/nix/store/...-glibc' from 'https://cache.nixos.org'...
+    /// ```
+    ///
+    /// Only useful for nix without the internal-json log format;
+    /// otherwise see `Tracker::update_event`.
+    pub fn update(&mut self, line: &OsStr) -> bool {
+        lazy_static! {
+            static ref BUILDS_LIST: Regex =
+                Regex::new(r"^(these (\d+ )?derivations|this derivation) will be built:$")
+                    .expect("invalid regex!");
+            static ref DOWNLOADS_LIST: Regex =
+                Regex::new(r"^(these (\d+ )?paths|this path) will be fetched( \(.*\))?:$")
+                    .expect("invalid regex!");
+            static ref LISTED_PATH: Regex =
+                Regex::new(r"^ +/nix/store/\S+$").expect("invalid regex!");
+            static ref BUILDING: Regex =
+                Regex::new(r"^building '/nix/store/[^']+\.drv'").expect("invalid regex!");
+            static ref COPYING: Regex =
+                Regex::new(r"^copying path '/nix/store/[^']+' from '").expect("invalid regex!");
+        }
+
+        let line = line.to_string_lossy();
+        let progress = &mut self.progress;
+        if self.listing != Listing::Nothing && LISTED_PATH.is_match(&line) {
+            match self.listing {
+                Listing::Builds => progress.builds_expected += 1,
+                Listing::Downloads => progress.downloads_expected += 1,
+                Listing::Nothing => {}
+            }
+            return false;
+        }
+        self.listing = Listing::Nothing;
+
+        if BUILDS_LIST.is_match(&line) {
+            self.listing = Listing::Builds;
+            false
+        } else if DOWNLOADS_LIST.is_match(&line) {
+            self.listing = Listing::Downloads;
+            false
+        } else if BUILDING.is_match(&line) {
+            progress.builds_started += 1;
+            // e.g. import-from-derivation builds during the evaluation
+            progress.builds_expected = progress.builds_expected.max(progress.builds_started);
+            true
+        } else if COPYING.is_match(&line) {
+            progress.downloads_started += 1;
+            progress.downloads_expected =
+                progress.downloads_expected.max(progress.downloads_started);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Look at an event nix logged in the internal-json format.
+    /// Returns whether the progress changed.
+    ///
+    /// Nix starts a `Build` or `Substitute` activity for every derivation
+    /// it builds or path it fetches, and reports how many it expects in all
+    /// as the `Progress` of its `Builds` and `CopyPaths` activities (or,
+    /// in some versions, as `SetExpected` results).
+    pub fn update_event(&mut self, event: &LogEvent) -> bool {
+        let before = self.progress;
+        let progress = &mut self.progress;
+        match event {
+            LogEvent::Start { id, activity, .. } => {
+                self.activities.insert(*id, *activity);
+                match activity {
+                    ActivityType::Build => progress.builds_started += 1,
+                    ActivityType::Substitute => progress.downloads_started += 1,
+                    _ => {}
+                }
+            }
+            LogEvent::Stop { id } => {
+                self.activities.remove(id);
+            }
+            LogEvent::Result {
+                id,
+                result: ResultType::Progress,
+                fields,
+            } => {
+                // done, expected, running, failed
+                if let Some(Field::Int(expected)) = fields.get(1) {
+                    match self.activities.get(id) {
+                        Some(ActivityType::Builds) => progress.builds_expected = *expected as usize,
+                        Some(ActivityType::CopyPaths) => {
+                            progress.downloads_expected = *expected as usize
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            LogEvent::Result {
+                result: ResultType::SetExpected,
+                fields,
+                ..
+            } => {
+                if let (Some(Field::Int(activity)), Some(Field::Int(expected))) =
+                    (fields.first(), fields.get(1))
+                {
+                    match ActivityType::from(*activity) {
+                        ActivityType::Build => progress.builds_expected = *expected as usize,
+                        ActivityType::Substitute => {
+                            progress.downloads_expected = *expected as usize
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            LogEvent::Msg { .. } | LogEvent::Result { .. } => {}
+        }
+        // e.g. import-from-derivation builds during the evaluation
+        progress.builds_expected = progress.builds_expected.max(progress.builds_started);
+        progress.downloads_expected = progress.downloads_expected.max(progress.downloads_started);
+        self.progress != before
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(lines: &[&str]) -> (BuildProgress, usize) {
+        let mut tracker = Tracker::default();
+        let updates = lines
+            .iter()
+            .filter(|line| tracker.update(OsStr::new(line)))
+            .count();
+        (tracker.progress(), updates)
+    }
+
+    #[test]
+    fn counts_listed_and_started_paths() {
+        let (progress, updates) = track(&[
+            "these derivations will be built:",
+            "  /nix/store/aaa-hello.drv",
+            "  /nix/store/bbb-shell.drv",
+            "these 3 paths will be fetched (0.04 MiB download, 0.19 MiB unpacked):",
+            "  /nix/store/ccc-glibc",
+            "  /nix/store/ddd-bash",
+            "  /nix/store/eee-coreutils",
+            "copying path '/nix/store/ccc-glibc' from 'https://cache.nixos.org'...",
+            "building '/nix/store/aaa-hello.drv'...",
+            "  /nix/store/fff-not-listed",
+        ]);
+        assert_eq!(
+            progress,
+            BuildProgress {
+                builds_started: 1,
+                builds_expected: 2,
+                downloads_started: 1,
+                downloads_expected: 3,
+            }
+        );
+        assert_eq!(updates, 2);
+        assert_eq!(
+            progress.to_string(),
+            "started building 1 of 2 derivations and fetching 1 of 3 paths"
+        );
+    }
+
+    #[test]
+    fn counts_started_and_expected_activities() {
+        let mut tracker = Tracker::default();
+        let updates = [
+            r#"@nix {"action":"start","id":1,"level":0,"type":104,"text":"","fields":[],"parent":0}"#,
+            r#"@nix {"action":"start","id":2,"level":0,"type":103,"text":"","fields":[],"parent":0}"#,
+            r#"@nix {"action":"result","id":1,"type":105,"fields":[0,2,0,0]}"#,
+            r#"@nix {"action":"result","id":2,"type":105,"fields":[0,3,0,0]}"#,
+            r#"@nix {"action":"start","id":3,"level":4,"type":108,"text":"","fields":["/nix/store/ccc-glibc","https://cache.nixos.org"],"parent":2}"#,
+            r#"@nix {"action":"start","id":4,"level":3,"type":105,"text":"building '/nix/store/aaa-hello.drv'","fields":["/nix/store/aaa-hello.drv","",1,1],"parent":1}"#,
+            r#"@nix {"action":"result","id":4,"type":101,"fields":["compiling hello.c"]}"#,
+            r#"@nix {"action":"result","id":1,"type":105,"fields":[0,2,1,0]}"#,
+        ]
+        .iter()
+        .filter(|line| tracker.update_event(&LogEvent::parse(OsStr::new(line)).unwrap()))
+        .count();
+        assert_eq!(
+            tracker.progress(),
+            BuildProgress {
+                builds_started: 1,
+                builds_expected: 2,
+                downloads_started: 1,
+                downloads_expected: 3,
+            }
+        );
+        assert_eq!(updates, 4);
+    }
+
+    #[test]
+    fn unlisted_builds_are_expected() {
+        let (progress, _) = track(&[
+            "this derivation will be built:",
+            "  /nix/store/aaa-hello.drv",
+            "building '/nix/store/zzz-import-from-derivation.drv'...",
+            "building '/nix/store/aaa-hello.drv'...",
+        ]);
+        assert_eq!(progress.builds_started, 2);
+        assert_eq!(progress.builds_expected, 2);
+    }
+}
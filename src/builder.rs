@@ -8,18 +8,21 @@
 //! `stderr`, like which source files are used by the evaluator.
 
 use crate::cas::ContentAddressable;
-use crate::nix::StorePath;
+use crate::nix::log::LogEvent;
+use crate::nix::{EvalEnv, StorePath};
 use crate::osstrlines;
-use crate::{DrvFile, NixFile};
+use crate::{DrvFile, NixFile, ShellArgs};
 use crossbeam_channel as chan;
 use regex::Regex;
 use slog_scope::debug;
 use std::any::Any;
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::thread;
+use std::time::{Duration, Instant};
 
 struct RootedDrv {
     _gc_handle: GcRootTempDir,
@@ -41,6 +44,8 @@ pub struct RootedPath {
 struct InstantiateOutput {
     referenced_paths: Vec<PathBuf>,
     output: Option<RootedDrv>,
+    /// nix-instantiate was killed, see `run`.
+    cancelled: bool,
 }
 
 /// Wraps io::Error with a special case for the nix executable
@@ -57,11 +62,67 @@ impl From<std::io::Error> for NixNotFoundError {
     }
 }
 
+/// The `shell.nix` next to a services nix file, if there is one.
+/// Its environment is exported together with the services.
+fn sibling_shell_nix(services: &Path) -> Option<PathBuf> {
+    services
+        .parent()
+        .map(|dir| dir.join("shell.nix"))
+        .filter(|shell| shell.is_file())
+}
+
+/// The arguments which make `./logged-evaluation.nix` get the shell
+/// environment out of its `shellSrc` like `args` say.
+fn shell_arguments(args: &ShellArgs) -> Vec<String> {
+    let mut ret = vec![];
+    if let Some(attr) = &args.attr {
+        ret.extend(vec!["--argstr".into(), "shellAttr".into(), attr.clone()]);
+    }
+    if !args.args.is_empty() {
+        let bindings: String = args
+            .args
+            .iter()
+            .map(|(name, expr)| format!("{} = ({}); ", nix_string(name), expr))
+            .collect();
+        ret.extend(vec![
+            "--arg".into(),
+            "shellArgs".into(),
+            format!("{{ {}}}", bindings),
+        ]);
+    }
+    if !args.argstrs.is_empty() {
+        ret.extend(vec![
+            "--argstr".into(),
+            "shellArgStrs".into(),
+            serde_json::to_string(&args.argstrs).expect("strings are always valid JSON"),
+        ]);
+    }
+    ret
+}
+
+/// `s` as a Nix string literal.
+fn nix_string(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace("${", "\\${")
+    )
+}
+
+/// Nix options which let `./logged-evaluation.nix` evaluate flakes.
+const FLAKE_OPTIONS: &[&str] = &["--option", "extra-experimental-features", "flakes"];
+
+/// Nix options which make nix fetch unpinned inputs (like a `fetchTarball`
+/// without a hash) again, instead of using its cached downloads.
+const REFRESH_OPTIONS: &[(&str, &str)] = &[("tarball-ttl", "0")];
+
 fn instrumented_instantiation(
     tx: chan::Sender<OsString>,
     nix_file: &NixFile,
     cas: &ContentAddressable,
-) -> Result<InstantiateOutput, NixNotFoundError> {
+    opts: &RunOpts,
+) -> Result<InstantiateOutput, Error> {
     // We're looking for log lines matching:
     //
     //     copied source '...' -> '/nix/store/...'
@@ -80,6 +141,10 @@ fn instrumented_instantiation(
     cmd.args(&[
         // verbose mode prints the files we track
         OsStr::new("-vv"),
+    ]);
+    // structured logs if nix supports them, see `nix::log`
+    cmd.args(crate::nix::log::log_format_args());
+    cmd.args(&[
         // we add a temporary indirect GC root
         OsStr::new("--add-root"),
         gc_root_dir.path().join("result").as_os_str(),
@@ -92,9 +157,31 @@ fn instrumented_instantiation(
         OsStr::new("--argstr"),
     ]);
     match nix_file {
-        NixFile::Shell(shell) => cmd.args(&[OsStr::new("shellSrc"), shell.as_os_str()]),
-        NixFile::Services(services) => cmd.args(&[OsStr::new("servicesSrc"), services.as_os_str()]),
+        NixFile::Services(services) => {
+            cmd.args(&[OsStr::new("servicesSrc"), services.as_os_str()]);
+            // the services run in the environment of the project’s shell
+            match sibling_shell_nix(services) {
+                Some(shell) => cmd.args(&[
+                    OsStr::new("--argstr"),
+                    OsStr::new("shellSrc"),
+                    shell.as_os_str(),
+                ]),
+                None => &mut cmd,
+            }
+        }
+        NixFile::Shell { file, args } => cmd
+            .args(&[OsStr::new("shellSrc"), file.as_os_str()])
+            .args(shell_arguments(args)),
+        NixFile::Flake { dir, shell } => cmd
+            .args(&[OsStr::new("flakeSrc"), dir.as_os_str()])
+            .args(&["--argstr", "flakeShell", shell])
+            .args(FLAKE_OPTIONS),
     };
+    if opts.refresh {
+        for &(name, value) in REFRESH_OPTIONS {
+            cmd.args(&["--option", name, value]);
+        }
+    }
     cmd.args(&[
         // instrumented by `./logged-evaluation.nix`
         OsStr::new("--"),
@@ -103,6 +190,12 @@ fn instrumented_instantiation(
     .stdin(Stdio::null())
     .stdout(Stdio::piped())
     .stderr(Stdio::piped());
+    if let Some(env) = &opts.env {
+        env.apply(&mut cmd);
+    }
+    if opts.cancel_rx.is_some() {
+        crate::nix::own_process_group(&mut cmd);
+    }
 
     debug!("nix-instantiate"; "command" => ?cmd);
 
@@ -120,16 +213,34 @@ fn instrumented_instantiation(
         .take()
         .expect("we must be able to access the stderr of nix-instantiate");
 
+    let log_events = opts.log_events.clone();
     let stderr_results: thread::JoinHandle<std::io::Result<Vec<LogDatum>>> =
         thread::spawn(move || {
-            osstrlines::Lines::from(BufReader::new(stderr))
-                .map(|line| {
-                    line.map(|l| {
-                        tx.send(l.clone()).expect("Receiver hung up!");
-                        parse_evaluation_line(l)
-                    })
-                })
-                .collect::<Result<Vec<LogDatum>, _>>()
+            let mut results = vec![];
+            for line in osstrlines::Lines::from(BufReader::new(stderr)) {
+                let line = line?;
+                match LogEvent::parse(&line) {
+                    Some(event) => {
+                        let text = event.text();
+                        for line in text.iter().flat_map(|text| text.lines()) {
+                            tx.send(OsString::from(line)).expect("Receiver hung up!");
+                        }
+                        // only messages name the files nix reads, never build output
+                        if let (LogEvent::Msg { .. }, Some(msg)) = (&event, text) {
+                            results.push(parse_log_message(msg));
+                        }
+                        if let Some(log_events) = &log_events {
+                            log_events.send(event).expect("Receiver hung up!");
+                        }
+                    }
+                    // nix without the internal-json log format
+                    None => {
+                        tx.send(line.clone()).expect("Receiver hung up!");
+                        results.push(parse_evaluation_line(line));
+                    }
+                }
+            }
+            Ok(results)
         });
 
     let build_products: thread::JoinHandle<std::io::Result<Vec<DrvFile>>> =
@@ -140,20 +251,16 @@ fn instrumented_instantiation(
         });
 
     let (exec_result, mut build_products, results) = (
-        child.wait()?,
-        build_products
-            .join()
-            .expect("Failed to join stdout processing thread")?,
-        stderr_results
-            .join()
-            .expect("Failed to join stderr processing thread")?,
+        crate::nix::wait_or_cancel(&mut child, opts.cancel_rx.as_ref())?,
+        build_products.join()??,
+        stderr_results.join()??,
     );
 
     // TODO: this can move entirely into the stderr thread,
     // meaning we don’t have to keep the outputs in memory (fold directly)
 
     // iterate over all lines, parsing out the ones we are interested in
-    let (paths, _log_lines): (Vec<PathBuf>, Vec<OsString>) =
+    let (mut paths, _log_lines): (Vec<PathBuf>, Vec<OsString>) =
         results
             .into_iter()
             .fold((vec![], vec![]), |(mut paths, mut log_lines), result| {
@@ -181,20 +288,35 @@ fn instrumented_instantiation(
 
                 (paths, log_lines)
             });
+    if let NixFile::Flake { dir, .. } = nix_file {
+        paths.extend(flake_files(dir));
+    }
 
+    let exec_result = match exec_result {
+        Some(exec_result) => exec_result,
+        None => {
+            return Ok(InstantiateOutput {
+                referenced_paths: paths,
+                output: None,
+                cancelled: true,
+            })
+        }
+    };
     if !exec_result.success() {
         return Ok(InstantiateOutput {
             referenced_paths: paths,
             output: None,
+            cancelled: false,
         });
     }
 
-    assert!(
-        build_products.len() == 1,
-        "got more or less than one build product from logged_evaluation.nix: {:#?}",
-        build_products
-    );
-    let shell_gc_root = build_products.pop().unwrap();
+    if build_products.len() != 1 {
+        return Err(Error::UnexpectedResults(format!(
+            "got more or less than one build product from logged_evaluation.nix: {:#?}",
+            build_products
+        )));
+    }
+    let shell_gc_root = build_products.remove(0);
 
     Ok(InstantiateOutput {
         referenced_paths: paths,
@@ -202,45 +324,115 @@ fn instrumented_instantiation(
             _gc_handle: GcRootTempDir(gc_root_dir),
             path: shell_gc_root,
         }),
+        cancelled: false,
     })
 }
 
+/// The contents of a `flake.lock` file we care about.
+#[derive(Deserialize)]
+struct FlakeLock {
+    nodes: HashMap<String, FlakeLockNode>,
+}
+
+#[derive(Deserialize)]
+struct FlakeLockNode {
+    /// Not set for the flake itself
+    locked: Option<LockedInput>,
+}
+
+#[derive(Deserialize)]
+struct LockedInput {
+    #[serde(rename = "type")]
+    kind: String,
+    /// Only set for `path` inputs, relative to the flake or absolute
+    path: Option<PathBuf>,
+}
+
+/// The files of the flake in `dir` which nix reads without logging them:
+/// `flake.nix`, `flake.lock` and the inputs which are local paths.
+fn flake_files(dir: &Path) -> Vec<PathBuf> {
+    let lock_file = dir.join("flake.lock");
+    let mut files = vec![dir.join("flake.nix"), lock_file.clone()];
+    // without a lock file, nix creates one; then we are called again
+    let lock = match std::fs::read(&lock_file) {
+        Ok(contents) => contents,
+        Err(_) => return files,
+    };
+    match serde_json::from_slice::<FlakeLock>(&lock) {
+        Ok(lock) => files.extend(
+            lock.nodes
+                .into_iter()
+                .filter_map(|(_, node)| node.locked)
+                .filter(|input| input.kind == "path")
+                .filter_map(|input| input.path)
+                .map(|path| dir.join(path)),
+        ),
+        Err(e) => {
+            debug!("could not parse flake.lock"; "file" => lock_file.display(), "error" => %e)
+        }
+    }
+    files
+}
+
 struct BuildOutput {
     output: Option<RootedPath>,
+    /// nix-build was killed, see `run`.
+    cancelled: bool,
 }
 
 /// Builds the Nix expression in `root_nix_file`.
 ///
 /// Instruments the nix file to gain extra information,
 /// which is valuable even if the build fails.
-fn build(tx: chan::Sender<OsString>, drv_path: DrvFile) -> Result<BuildOutput, NixNotFoundError> {
-    //let drv_path = s.path.clone();
-    match crate::nix::CallOpts::file(drv_path.as_path())
-        .set_stderr_sender(tx)
-        .path()
-    {
+fn build(
+    tx: chan::Sender<OsString>,
+    drv_path: DrvFile,
+    opts: &RunOpts,
+) -> Result<BuildOutput, Error> {
+    let mut call = crate::nix::CallOpts::file(drv_path.as_path());
+    call.set_stderr_sender(tx);
+    if let Some(log_events) = &opts.log_events {
+        call.set_log_event_sender(log_events.clone());
+    }
+    if let Some(env) = &opts.env {
+        call.set_env(env.clone());
+    }
+    if let Some(cancel_rx) = &opts.cancel_rx {
+        call.set_cancel_receiver(cancel_rx.clone());
+    }
+    match call.path() {
         Ok(realized) => Ok(BuildOutput {
             output: Some(RootedPath {
                 gc_handle: realized.1,
                 path: realized.0,
             }),
+            cancelled: false,
         }),
         Err(crate::nix::OnePathError::TooManyResults) => {
-            panic!(
-                "Too many results from building the instrumented, instantiated, shell environment."
-            );
+            Err(Error::UnexpectedResults(String::from(
+                "Too many results from building the instrumented, instantiated, shell environment.",
+            )))
         }
         Err(crate::nix::OnePathError::Build(crate::nix::BuildError::NoResult)) => {
-            panic!("No results from building the instrumented, instantiated, shell environment.");
-        }
-        Err(crate::nix::OnePathError::Build(crate::nix::BuildError::Io(e))) => {
-            Err(NixNotFoundError::from(e))
+            Err(Error::UnexpectedResults(String::from(
+                "No results from building the instrumented, instantiated, shell environment.",
+            )))
         }
+        Err(crate::nix::OnePathError::Build(crate::nix::BuildError::Io(e))) => Err(Error::from(e)),
         Err(crate::nix::OnePathError::Build(crate::nix::BuildError::ExecutionFailed(_))) => {
-            Ok(BuildOutput { output: None })
+            Ok(BuildOutput {
+                output: None,
+                cancelled: false,
+            })
+        }
+        Err(crate::nix::OnePathError::Build(crate::nix::BuildError::Cancelled)) => {
+            Ok(BuildOutput {
+                output: None,
+                cancelled: true,
+            })
         }
         Err(crate::nix::OnePathError::Build(crate::nix::BuildError::NixNotFound)) => {
-            Err(NixNotFoundError::NixNotFound)
+            Err(Error::NixNotFound)
         }
     }
 }
@@ -258,6 +450,18 @@ pub struct RunResult {
     pub referenced_paths: Vec<PathBuf>,
     /// The status of the build attempt
     pub status: RunStatus,
+    /// How long the instantiation and realize took
+    pub durations: Durations,
+}
+
+/// Time spent in each phase of `run`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Durations {
+    /// Evaluating the Nix expression with nix-instantiate
+    pub instantiate: Duration,
+    /// Building the derivation with nix-store --realize.
+    /// `None` if the instantiation failed or was cancelled.
+    pub realize: Option<Duration>,
 }
 
 /// How far along we got in the instantiate then realize.
@@ -269,38 +473,89 @@ pub enum RunStatus {
     FailedAtRealize,
     /// The instantiation and realize succeeded and yielded a result path.
     Complete(RootedPath),
+    /// The instantiation or realize was cancelled, see `run`.
+    Cancelled,
 }
 
-/// Builds the Nix expression in `root_nix_file`.
+/// How `run` builds a project, using the builder pattern of `nix::CallOpts`.
+/// The default runs nix in lorri’s own environment, with nix’s cache and
+/// without a way to cancel it.
+#[derive(Clone, Default)]
+pub struct RunOpts {
+    log_events: Option<chan::Sender<LogEvent>>,
+    refresh: bool,
+    env: Option<EvalEnv>,
+    cancel_rx: Option<chan::Receiver<()>>,
+}
+
+impl RunOpts {
+    /// If nix supports the internal-json log format, send the events it
+    /// logs to `sender` as well, e.g. to follow the progress of the build.
+    pub fn set_log_event_sender(&mut self, sender: chan::Sender<LogEvent>) -> &mut Self {
+        self.log_events = Some(sender);
+        self
+    }
+
+    /// If `refresh` is set, unpinned inputs (like a `fetchTarball` without
+    /// a hash) are fetched again instead of taken from nix’s cache.
+    pub fn set_refresh(&mut self, refresh: bool) -> &mut Self {
+        self.refresh = refresh;
+        self
+    }
+
+    /// Run nix in `env` instead of lorri’s own environment.
+    pub fn set_env(&mut self, env: EvalEnv) -> &mut Self {
+        self.env = Some(env);
+        self
+    }
+
+    /// If a message arrives on `cancel_rx`, the running nix process and its
+    /// children are killed, its temporary GC root is removed and `run`
+    /// returns `RunStatus::Cancelled`.
+    pub fn set_cancel_receiver(&mut self, cancel_rx: chan::Receiver<()>) -> &mut Self {
+        self.cancel_rx = Some(cancel_rx);
+        self
+    }
+}
+
+/// Builds the Nix expression in `root_nix_file`, as set up by `opts`.
 ///
 /// Instruments the nix file to gain extra information,
 /// which is valuable even if the build fails.
+///
+/// The output of nix is sent to `tx` line by line, in its human-readable
+/// format.
 pub fn run(
     tx: chan::Sender<OsString>,
     root_nix_file: &NixFile,
     cas: &ContentAddressable,
+    opts: &RunOpts,
 ) -> Result<RunResult, Error> {
-    let inst_info = instrumented_instantiation(tx.clone(), root_nix_file, cas)?;
-    if let Some(inst_output) = inst_info.output {
-        let buildoutput = build(tx, inst_output.path)?;
-
-        if let Some(build_output) = buildoutput.output {
-            Ok(RunResult {
-                referenced_paths: inst_info.referenced_paths,
-                status: RunStatus::Complete(build_output),
-            })
-        } else {
-            Ok(RunResult {
-                referenced_paths: inst_info.referenced_paths,
-                status: RunStatus::FailedAtRealize,
-            })
+    let started = Instant::now();
+    let inst_info = instrumented_instantiation(tx.clone(), root_nix_file, cas, opts)?;
+    let mut durations = Durations {
+        instantiate: started.elapsed(),
+        realize: None,
+    };
+    let status = match inst_info.output {
+        _ if inst_info.cancelled => RunStatus::Cancelled,
+        None => RunStatus::FailedAtInstantiation,
+        Some(inst_output) => {
+            let started = Instant::now();
+            let build_output = build(tx, inst_output.path, opts)?;
+            durations.realize = Some(started.elapsed());
+            match build_output.output {
+                _ if build_output.cancelled => RunStatus::Cancelled,
+                Some(path) => RunStatus::Complete(path),
+                None => RunStatus::FailedAtRealize,
+            }
         }
-    } else {
-        Ok(RunResult {
-            referenced_paths: inst_info.referenced_paths,
-            status: RunStatus::FailedAtInstantiation,
-        })
-    }
+    };
+    Ok(RunResult {
+        referenced_paths: inst_info.referenced_paths,
+        status,
+        durations,
+    })
 }
 
 /// Classifies the output of nix-instantiate -vv.
@@ -361,6 +616,38 @@ where
     }
 }
 
+/// Classify a log message nix printed in the internal-json format (see
+/// `nix::log`), like `parse_evaluation_line` does for the lines of its
+/// human-readable output, which older versions of nix print.
+fn parse_log_message(msg: String) -> LogDatum {
+    // `'<path>'` after `prefix` and before `suffix`
+    let quoted = |prefix: &str, suffix: &str| -> Option<PathBuf> {
+        let prefix = format!("{}'", prefix);
+        let suffix = format!("'{}", suffix);
+        if msg.len() >= prefix.len() + suffix.len()
+            && msg.starts_with(&prefix)
+            && msg.ends_with(&suffix)
+        {
+            Some(PathBuf::from(&msg[prefix.len()..msg.len() - suffix.len()]))
+        } else {
+            None
+        }
+    };
+    if let Some(source) = quoted("evaluating file ", "") {
+        LogDatum::NixSourceFile(source)
+    } else if let Some(source) = quoted("trace: lorri read: ", "") {
+        LogDatum::ReadFileOrDir(source)
+    } else if msg.starts_with("copied source '") {
+        // the store path never contains the separator, the source might
+        match msg.rfind("' -> '") {
+            Some(end) => LogDatum::CopiedSource(PathBuf::from(&msg["copied source '".len()..end])),
+            None => LogDatum::Text(msg),
+        }
+    } else {
+        LogDatum::Text(msg)
+    }
+}
+
 /// Output paths generated by `logged-evaluation.nix`
 #[derive(Debug, Clone)]
 pub struct OutputPaths<T> {
@@ -379,6 +666,9 @@ pub enum Error {
 
     /// Failed to spawn a log processing thread
     ThreadFailure(std::boxed::Box<(dyn std::any::Any + std::marker::Send + 'static)>),
+
+    /// The instrumented evaluation or the build did not yield exactly one result
+    UnexpectedResults(String),
 }
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Error {
@@ -409,6 +699,72 @@ mod tests {
     use std::os::unix::ffi::OsStrExt;
     use std::path::PathBuf;
 
+    #[test]
+    fn shell_arguments_for_logged_evaluation() {
+        let mut args = ShellArgs::default();
+        assert!(shell_arguments(&args).is_empty());
+
+        args.attr = Some(String::from("shells.docs"));
+        args.args
+            .insert(String::from("withDocs"), String::from("true"));
+        args.args
+            .insert(String::from("odd\"${name}"), String::from("1 + 1"));
+        args.argstrs
+            .insert(String::from("version"), String::from("1.0 \"beta\""));
+        assert_eq!(
+            shell_arguments(&args),
+            vec![
+                "--argstr",
+                "shellAttr",
+                "shells.docs",
+                "--arg",
+                "shellArgs",
+                r#"{ "odd\"\${name}" = (1 + 1); "withDocs" = (true); }"#,
+                "--argstr",
+                "shellArgStrs",
+                r#"{"version":"1.0 \"beta\""}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn flake_files_include_local_inputs() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let dir = tmp.path();
+        assert_eq!(
+            flake_files(dir),
+            vec![dir.join("flake.nix"), dir.join("flake.lock")]
+        );
+
+        std::fs::write(
+            dir.join("flake.lock"),
+            r#"{
+  "nodes": {
+    "lib": {
+      "locked": { "lastModified": 1, "narHash": "sha256-x", "path": "./lib", "type": "path" },
+      "original": { "path": "./lib", "type": "path" }
+    },
+    "nixpkgs": {
+      "locked": { "owner": "NixOS", "repo": "nixpkgs", "rev": "abc", "type": "github" },
+      "original": { "owner": "NixOS", "repo": "nixpkgs", "type": "github" }
+    },
+    "root": { "inputs": { "lib": "lib", "nixpkgs": "nixpkgs" } }
+  },
+  "root": "root",
+  "version": 7
+}"#,
+        )?;
+        assert_eq!(
+            flake_files(dir),
+            vec![
+                dir.join("flake.nix"),
+                dir.join("flake.lock"),
+                dir.join("./lib")
+            ]
+        );
+        Ok(())
+    }
+
     /// Parsing of `LogDatum`.
     #[test]
     fn evaluation_line_to_log_datum() {
@@ -441,6 +797,41 @@ mod tests {
         );
     }
 
+    /// Parsing of `LogDatum` from log messages in the internal-json format.
+    #[test]
+    fn log_message_to_log_datum() {
+        let datum = |line: &str| match LogEvent::parse(OsStr::new(line)) {
+            Some(event @ LogEvent::Msg { .. }) => parse_log_message(event.text().unwrap()),
+            other => panic!("not a message: {:?}", other),
+        };
+        assert_eq!(
+            datum(
+                r#"@nix {"action":"msg","level":5,"msg":"evaluating file '/project/shell.nix'"}"#
+            ),
+            LogDatum::NixSourceFile(PathBuf::from("/project/shell.nix"))
+        );
+        assert_eq!(
+            datum(
+                r#"@nix {"action":"msg","level":5,"msg":"copied source '/project/a' -> 'b' -> '/nix/store/abc-a'"}"#
+            ),
+            LogDatum::CopiedSource(PathBuf::from("/project/a' -> 'b"))
+        );
+        assert_eq!(
+            datum(
+                r#"@nix {"action":"msg","level":0,"msg":"\u001b[35;1mtrace:\u001b[0m lorri read: '/project/data.json'"}"#
+            ),
+            LogDatum::ReadFileOrDir(PathBuf::from("/project/data.json"))
+        );
+        assert_eq!(
+            datum(
+                r#"@nix {"action":"msg","level":0,"msg":"error: evaluating file '/project/shell.nix'\nfailed"}"#
+            ),
+            LogDatum::Text(String::from(
+                "error: evaluating file '/project/shell.nix'\nfailed"
+            ))
+        );
+    }
+
     /// Create a locally built base derivation expression.
     /// `args` is just interpolated into the derivation fields.
     fn drv(name: &str, args: &str) -> String {
@@ -495,8 +886,9 @@ in {}
         let (tx, rx) = chan::unbounded();
         let info = run(
             tx,
-            &crate::NixFile::Shell(cas.file_from_string(&nix_drv)?),
+            &crate::NixFile::shell(cas.file_from_string(&nix_drv)?, ShellArgs::default()),
             &cas,
+            &RunOpts::default(),
         )
         .unwrap();
         let stderr = rx.iter().collect::<Vec<OsString>>();
@@ -530,13 +922,16 @@ in {}
         let tmp = tempfile::tempdir()?;
         let cas = ContentAddressable::new(tmp.path().to_owned())?;
 
-        let d = crate::NixFile::Shell(cas.file_from_string(&drv(
-            "shell",
-            &format!("dep = {};", drv("dep", r##"args = [ "-c" "exit 1" ];"##)),
-        ))?);
+        let d = crate::NixFile::shell(
+            cas.file_from_string(&drv(
+                "shell",
+                &format!("dep = {};", drv("dep", r##"args = [ "-c" "exit 1" ];"##)),
+            ))?,
+            ShellArgs::default(),
+        );
 
         let (tx, _rx) = chan::unbounded();
-        run(tx, &d, &cas).expect("build can fail, but must not panic");
+        run(tx, &d, &cas, &RunOpts::default()).expect("build can fail, but must not panic");
         Ok(())
     }
 
@@ -588,7 +983,13 @@ dir-as-source = ./dir;
         let cas = ContentAddressable::new(cas_tmp.path().join("cas"))?;
 
         let (tx, rx) = chan::unbounded();
-        let inst_info = instrumented_instantiation(tx, &NixFile::Shell(shell), &cas).unwrap();
+        let inst_info = instrumented_instantiation(
+            tx,
+            &NixFile::shell(shell, ShellArgs::default()),
+            &cas,
+            &RunOpts::default(),
+        )
+        .unwrap();
         let ends_with = |end| inst_info.referenced_paths.iter().any(|p| p.ends_with(end));
         assert!(
             inst_info.output.is_some(),
@@ -646,7 +1047,8 @@ in
         let RunResult {
             status,
             referenced_paths: _,
-        } = run(tx, &NixFile::Services(services), &cas).unwrap();
+            durations: _,
+        } = run(tx, &NixFile::Services(services), &cas, &RunOpts::default()).unwrap();
 
         let path = match &status {
             RunStatus::Complete(RootedPath { path, gc_handle: _ }) => path.as_path(),
@@ -702,7 +1104,9 @@ in
         let cas = ContentAddressable::new(cas_tmp.path().join("cas"))?;
 
         let (tx, rx) = chan::unbounded();
-        let inst_info = instrumented_instantiation(tx, &NixFile::Services(services), &cas).unwrap();
+        let inst_info =
+            instrumented_instantiation(tx, &NixFile::Services(services), &cas, &RunOpts::default())
+                .unwrap();
         assert!(
             inst_info.output.is_some(),
             "instantiation failed to produce an output"
@@ -1,5 +1,7 @@
 //! Defines the CLI interface using structopt.
 
+use crate::logging::LogFormat;
+use crate::ShellArgs;
 use std::path::PathBuf;
 
 #[derive(StructOpt, Debug)]
@@ -12,6 +14,10 @@ pub struct Arguments {
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     pub verbosity: u8,
 
+    /// How to print log messages: `human` or `json` (one JSON object per line)
+    #[structopt(long = "log-format", env = "LORRI_LOG_FORMAT", default_value = "human")]
+    pub log_format: LogFormat,
+
     /// Sub-command to execute
     #[structopt(subcommand)]
     pub command: Command,
@@ -35,7 +41,35 @@ pub enum Command {
 
     /// Start the multi-project daemon. Replaces `lorri watch`
     #[structopt(name = "daemon")]
-    Daemon,
+    Daemon(DaemonOptions),
+
+    /// Run the services defined in `services.nix` via the daemon, in the
+    /// environment of the `shell.nix` next to it, restarting them whenever
+    /// their definition changes. They stop when this command exits.
+    #[structopt(name = "services")]
+    Services(ServicesOptions),
+
+    /// Show the projects watched by the daemon and their build state
+    #[structopt(name = "status")]
+    Status(StatusOptions),
+
+    /// Make the daemon build the project again, even if nothing changed
+    #[structopt(name = "rebuild")]
+    Rebuild(RebuildOptions),
+
+    /// Make the daemon stop watching the project. If its nix file does not
+    /// exist anymore, the first `--shell-file` in the current directory is forgotten
+    #[structopt(name = "forget")]
+    Forget(ForgetOptions),
+
+    /// Wait until the daemon finished building the project, then print its
+    /// GC root (or the output of the failed build)
+    #[structopt(name = "wait")]
+    Wait(WaitOptions),
+
+    /// Print the daemon's build events as newline-delimited JSON
+    #[structopt(name = "stream-events")]
+    StreamEvents,
 
     /// (plumbing) Tell the lorri daemon to care about the current directory's project
     #[structopt(name = "ping_")]
@@ -53,28 +87,217 @@ pub enum Command {
 /// Options for `watch` subcommand.
 #[derive(StructOpt, Debug)]
 pub struct DirenvOptions {
-    /// The .nix file in the current directory to use
-    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
-    pub nix_file: PathBuf,
+    #[structopt(flatten)]
+    #[allow(missing_docs)]
+    pub shell_file: ShellFileOptions,
+    /// Use a development shell of a flake instead, given as `<dir>#<name>`
+    /// like `.#default` (the name defaults to `default`)
+    #[structopt(long = "flake")]
+    pub flake: Option<String>,
 }
 
 /// Options for `watch` subcommand.
 #[derive(StructOpt, Debug)]
 pub struct InfoOptions {
-    /// The .nix file in the current directory to use
-    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
-    pub nix_file: PathBuf,
+    #[structopt(flatten)]
+    #[allow(missing_docs)]
+    pub shell_file: ShellFileOptions,
 }
 
 /// Options for `watch` subcommand.
 #[derive(StructOpt, Debug)]
 pub struct WatchOptions {
-    /// The .nix file in the current directory to use
-    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
-    pub nix_file: PathBuf,
+    #[structopt(flatten)]
+    #[allow(missing_docs)]
+    pub shell_file: ShellFileOptions,
     /// Exit after a the first build
     #[structopt(long = "once")]
     pub once: bool,
+    /// Wait until no input file changed for this many milliseconds before building
+    #[structopt(long = "debounce", default_value = "200")]
+    pub debounce: u64,
+    #[structopt(flatten)]
+    #[allow(missing_docs)]
+    pub hooks: HookOptions,
+}
+
+/// The nix files tried by `ShellFileOptions` if none is given.
+///
+/// `default.nix` is deliberately missing: in a monorepo, it usually builds
+/// a package of a subdirectory, and would be picked over the `shell.nix` at
+/// the root of the repository. Use `--shell-file shell.nix --shell-file default.nix`
+/// to opt in.
+pub const DEFAULT_SHELL_FILES: &[&str] = &["shell.nix"];
+
+/// Options for finding the nix file of a project (see `locate_file::upwards`)
+/// and getting the shell environment out of it.
+#[derive(StructOpt, Debug)]
+pub struct ShellFileOptions {
+    /// The .nix file to use. It is looked for in the current directory, then
+    /// in its parents up to the root of the repository. Can be given multiple
+    /// times; in each directory, the files are tried in the given order.
+    /// `default.nix` is only tried if given explicitly [default: shell.nix]
+    #[structopt(
+        long = "shell-file",
+        value_name = "file",
+        parse(from_os_str),
+        number_of_values = 1
+    )]
+    pub candidates: Vec<PathBuf>,
+    /// Use this attribute path of the evaluated file as the shell, like `nix-shell --attr`
+    #[structopt(long = "attr", short = "A")]
+    pub attr: Option<String>,
+    /// Call the file with the Nix expression <expr> as argument <name>, like `nix-shell --arg`.
+    /// Can be given multiple times
+    #[structopt(
+        long = "arg",
+        raw(number_of_values = "2", value_names = r#"&["name", "expr"]"#)
+    )]
+    pub args: Vec<String>,
+    /// Call the file with the string <value> as argument <name>, like `nix-shell --argstr`.
+    /// Can be given multiple times
+    #[structopt(
+        long = "argstr",
+        raw(number_of_values = "2", value_names = r#"&["name", "value"]"#)
+    )]
+    pub argstrs: Vec<String>,
+}
+
+impl ShellFileOptions {
+    /// How to get the shell environment out of the nix file.
+    pub fn shell_args(&self) -> ShellArgs {
+        let pairs = |values: &[String]| {
+            values
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect()
+        };
+        ShellArgs {
+            attr: self.attr.clone(),
+            args: pairs(&self.args),
+            argstrs: pairs(&self.argstrs),
+        }
+    }
+
+    /// The nix files to look for, in order.
+    pub fn candidates(&self) -> Vec<PathBuf> {
+        if self.candidates.is_empty() {
+            DEFAULT_SHELL_FILES.iter().map(PathBuf::from).collect()
+        } else {
+            self.candidates.clone()
+        }
+    }
+}
+
+/// Options for running commands on build events, see `hooks`.
+#[derive(StructOpt, Debug)]
+pub struct HookOptions {
+    /// Run this shell command whenever a build starts, completes or fails.
+    /// It gets the event as JSON on stdin and in `LORRI_*` environment variables.
+    /// Can be given multiple times
+    #[structopt(long = "hook", number_of_values = 1)]
+    pub hooks: Vec<String>,
+    /// Kill hooks which are still running after this many seconds
+    #[structopt(long = "hook-timeout", default_value = "30")]
+    pub hook_timeout: u64,
+}
+
+/// Options for `daemon` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct DaemonOptions {
+    /// The maximum number of nix evaluations and builds running at the same time.
+    /// Recently pinged projects are built first.
+    #[structopt(long = "max-builds", default_value = "2")]
+    pub max_builds: usize,
+    /// Stop building a project on file changes if it was not pinged
+    /// (e.g. by `lorri direnv`) for this many seconds; build it on the next ping instead
+    #[structopt(long = "stale-after")]
+    pub stale_after: Option<u64>,
+    /// Stop watching a project if it was not pinged for this many seconds
+    #[structopt(long = "evict-after")]
+    pub evict_after: Option<u64>,
+    /// When the daemon starts, watch the projects again which were pinged
+    /// within this many seconds before it stopped
+    #[structopt(long = "restore-within", default_value = "604800")]
+    pub restore_within: u64,
+    /// Wait until no input file of a project changed for this many
+    /// milliseconds before building it
+    #[structopt(long = "debounce", default_value = "200")]
+    pub debounce: u64,
+    /// Write build metrics to this file (at most every 15 seconds), in the Prometheus text
+    /// format. Point the node exporter's textfile collector at its directory to collect them
+    #[structopt(long = "metrics-file", parse(from_os_str))]
+    pub metrics_file: Option<PathBuf>,
+    /// Also serve clients running as the user with this id.
+    /// By default, only the user running the daemon may use it.
+    /// Can be given multiple times
+    #[structopt(long = "allow-uid", number_of_values = 1)]
+    pub allow_uids: Vec<u32>,
+    #[structopt(flatten)]
+    #[allow(missing_docs)]
+    pub hooks: HookOptions,
+}
+
+/// Options for `services` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct ServicesOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(
+        long = "services-file",
+        parse(from_os_str),
+        default_value = "services.nix"
+    )]
+    pub nix_file: PathBuf,
+}
+
+/// Options for `status` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct StatusOptions {
+    /// Print the project list as JSON
+    #[structopt(long = "json")]
+    pub json: bool,
+}
+
+/// Options for `rebuild` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct RebuildOptions {
+    #[structopt(flatten)]
+    #[allow(missing_docs)]
+    pub shell_file: ShellFileOptions,
+    /// Fetch unpinned inputs (like a `fetchTarball` without a hash) again,
+    /// instead of using nix's cached downloads
+    #[structopt(long = "refresh")]
+    pub refresh: bool,
+}
+
+/// Options for `forget` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct ForgetOptions {
+    #[structopt(flatten)]
+    #[allow(missing_docs)]
+    pub shell_file: ShellFileOptions,
+    /// Forget the development shell of this name of the flake in the
+    /// current directory instead
+    #[structopt(long = "flake", conflicts_with = "hash")]
+    pub flake_shell: Option<String>,
+    /// Forget the project with this hash (as shown by `lorri status`) instead.
+    /// Works for every kind of project, including services
+    #[structopt(long = "hash")]
+    pub hash: Option<String>,
+    /// Also delete the project's GC roots, so its environment can be garbage collected
+    #[structopt(long = "delete-gc-roots")]
+    pub delete_gc_roots: bool,
+}
+
+/// Options for `wait` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct WaitOptions {
+    #[structopt(flatten)]
+    #[allow(missing_docs)]
+    pub shell_file: ShellFileOptions,
+    /// Give up if the build did not finish after this many seconds
+    #[structopt(long = "timeout")]
+    pub timeout: Option<u64>,
 }
 
 /// Send a message with a lorri project.
@@ -84,9 +307,13 @@ pub struct WatchOptions {
 /// get pinged for a long time, it may stop watching the project for changes.
 #[derive(StructOpt, Debug)]
 pub struct Ping_ {
-    /// The .nix file to watch and build on changes.
-    #[structopt(parse(from_os_str))]
-    pub nix_file: PathBuf,
+    /// The .nix file to watch and build on changes. If missing, it is
+    /// looked for like with `--shell-file`
+    #[structopt(parse(from_os_str), conflicts_with = "candidates")]
+    pub nix_file: Option<PathBuf>,
+    #[structopt(flatten)]
+    #[allow(missing_docs)]
+    pub shell_file: ShellFileOptions,
 }
 
 /// A stub struct to represent how what we want to upgrade to.
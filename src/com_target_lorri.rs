@@ -11,12 +11,26 @@ use varlink::{self, CallTrait};
 pub enum ErrorKind {
     Varlink_Error,
     VarlinkReply_Error,
+    GcRootsNotDeleted(Option<GcRootsNotDeleted_Args>),
+    NotWatched(Option<NotWatched_Args>),
+    PermissionDenied(Option<PermissionDenied_Args>),
+    ShuttingDown(Option<ShuttingDown_Args>),
+    Timeout(Option<Timeout_Args>),
 }
 impl ::std::fmt::Display for ErrorKind {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         match self {
             ErrorKind::Varlink_Error => write!(f, "Varlink Error"),
             ErrorKind::VarlinkReply_Error => write!(f, "Varlink error reply"),
+            ErrorKind::GcRootsNotDeleted(v) => {
+                write!(f, "com.target.lorri.GcRootsNotDeleted: {:#?}", v)
+            }
+            ErrorKind::NotWatched(v) => write!(f, "com.target.lorri.NotWatched: {:#?}", v),
+            ErrorKind::PermissionDenied(v) => {
+                write!(f, "com.target.lorri.PermissionDenied: {:#?}", v)
+            }
+            ErrorKind::ShuttingDown(v) => write!(f, "com.target.lorri.ShuttingDown: {:#?}", v),
+            ErrorKind::Timeout(v) => write!(f, "com.target.lorri.Timeout: {:#?}", v),
         }
     }
 }
@@ -102,18 +116,207 @@ impl From<&varlink::Reply> for ErrorKind {
     #[allow(unused_variables)]
     fn from(e: &varlink::Reply) -> Self {
         match e {
+            varlink::Reply {
+                error: Some(ref t), ..
+            } if t == "com.target.lorri.GcRootsNotDeleted" => match e {
+                varlink::Reply {
+                    parameters: Some(p),
+                    ..
+                } => match serde_json::from_value(p.clone()) {
+                    Ok(v) => ErrorKind::GcRootsNotDeleted(v),
+                    Err(_) => ErrorKind::GcRootsNotDeleted(None),
+                },
+                _ => ErrorKind::GcRootsNotDeleted(None),
+            },
+            varlink::Reply {
+                error: Some(ref t), ..
+            } if t == "com.target.lorri.NotWatched" => match e {
+                varlink::Reply {
+                    parameters: Some(p),
+                    ..
+                } => match serde_json::from_value(p.clone()) {
+                    Ok(v) => ErrorKind::NotWatched(v),
+                    Err(_) => ErrorKind::NotWatched(None),
+                },
+                _ => ErrorKind::NotWatched(None),
+            },
+            varlink::Reply {
+                error: Some(ref t), ..
+            } if t == "com.target.lorri.PermissionDenied" => match e {
+                varlink::Reply {
+                    parameters: Some(p),
+                    ..
+                } => match serde_json::from_value(p.clone()) {
+                    Ok(v) => ErrorKind::PermissionDenied(v),
+                    Err(_) => ErrorKind::PermissionDenied(None),
+                },
+                _ => ErrorKind::PermissionDenied(None),
+            },
+            varlink::Reply {
+                error: Some(ref t), ..
+            } if t == "com.target.lorri.ShuttingDown" => match e {
+                varlink::Reply {
+                    parameters: Some(p),
+                    ..
+                } => match serde_json::from_value(p.clone()) {
+                    Ok(v) => ErrorKind::ShuttingDown(v),
+                    Err(_) => ErrorKind::ShuttingDown(None),
+                },
+                _ => ErrorKind::ShuttingDown(None),
+            },
+            varlink::Reply {
+                error: Some(ref t), ..
+            } if t == "com.target.lorri.Timeout" => match e {
+                varlink::Reply {
+                    parameters: Some(p),
+                    ..
+                } => match serde_json::from_value(p.clone()) {
+                    Ok(v) => ErrorKind::Timeout(v),
+                    Err(_) => ErrorKind::Timeout(None),
+                },
+                _ => ErrorKind::Timeout(None),
+            },
             _ => ErrorKind::VarlinkReply_Error,
         }
     }
 }
-pub trait VarlinkCallError: varlink::CallTrait {}
+pub trait VarlinkCallError: varlink::CallTrait {
+    fn reply_gc_roots_not_deleted(&mut self, r#message: String) -> varlink::Result<()> {
+        self.reply_struct(varlink::Reply::error(
+            "com.target.lorri.GcRootsNotDeleted",
+            Some(
+                serde_json::to_value(GcRootsNotDeleted_Args { r#message })
+                    .map_err(varlink::map_context!())?,
+            ),
+        ))
+    }
+    fn reply_not_watched(&mut self) -> varlink::Result<()> {
+        self.reply_struct(varlink::Reply::error(
+            "com.target.lorri.NotWatched",
+            Some(serde_json::to_value(NotWatched_Args {}).map_err(varlink::map_context!())?),
+        ))
+    }
+    fn reply_permission_denied(&mut self, r#uid: i64) -> varlink::Result<()> {
+        self.reply_struct(varlink::Reply::error(
+            "com.target.lorri.PermissionDenied",
+            Some(
+                serde_json::to_value(PermissionDenied_Args { r#uid })
+                    .map_err(varlink::map_context!())?,
+            ),
+        ))
+    }
+    fn reply_shutting_down(&mut self) -> varlink::Result<()> {
+        self.reply_struct(varlink::Reply::error(
+            "com.target.lorri.ShuttingDown",
+            Some(serde_json::to_value(ShuttingDown_Args {}).map_err(varlink::map_context!())?),
+        ))
+    }
+    fn reply_timeout(&mut self) -> varlink::Result<()> {
+        self.reply_struct(varlink::Reply::error(
+            "com.target.lorri.Timeout",
+            Some(serde_json::to_value(Timeout_Args {}).map_err(varlink::map_context!())?),
+        ))
+    }
+}
 impl<'a> VarlinkCallError for varlink::Call<'a> {}
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct r#BuildOutcome {
+    pub r#kind: BuildOutcomeKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#gc_root: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#log_tail: Option<Vec<String>>,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum r#BuildOutcomeKind {
+    r#success,
+    r#failure,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct r#BuildProgress {
+    pub r#builds_started: i64,
+    pub r#builds_expected: i64,
+    pub r#downloads_started: i64,
+    pub r#downloads_expected: i64,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct r#Command {
     pub r#program: String,
     pub r#args: Vec<String>,
 }
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct r#Event {
+    pub r#kind: EventKind,
+    pub r#nix_file: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#reason: Option<Reason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#gc_root: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#log_lines: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#progress: Option<BuildProgress>,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum r#EventKind {
+    r#started,
+    r#progress,
+    r#cancelled,
+    r#completed,
+    r#failure,
+    r#error,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct r#Project {
+    pub r#nix_file: String,
+    pub r#hash: String,
+    pub r#state: ProjectState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#last_build_time: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#last_gc_root: Option<String>,
+    pub r#stale: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#progress: Option<BuildProgress>,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct r#ProjectMetrics {
+    pub r#nix_file: String,
+    pub r#builds_started: i64,
+    pub r#builds_succeeded: i64,
+    pub r#builds_failed: i64,
+    pub r#builds_cancelled: i64,
+    pub r#build_errors: i64,
+    pub r#watched_paths: i64,
+    pub r#instantiate: TimeSpent,
+    pub r#realize: TimeSpent,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum r#ProjectState {
+    r#idle,
+    r#building,
+    r#failed,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct r#Reason {
+    pub r#kind: ReasonKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#files: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#debug: Option<String>,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum r#ReasonKind {
+    r#project_added,
+    r#ping_received,
+    r#rebuild_requested,
+    r#environment_changed,
+    r#files_changed,
+    r#unknown,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct r#Service {
     pub r#name: String,
     pub r#command: Command,
@@ -125,7 +328,186 @@ pub struct r#ServicesNix {
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct r#ShellNix {
     pub r#path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#flake_shell: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#attr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#args: Option<varlink::StringHashMap<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#argstrs: Option<varlink::StringHashMap<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#env: Option<varlink::StringHashMap<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#cwd: Option<String>,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct r#TimeSpent {
+    pub r#count: i64,
+    pub r#seconds: f64,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct GcRootsNotDeleted_Args {
+    pub r#message: String,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct NotWatched_Args {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct PermissionDenied_Args {
+    pub r#uid: i64,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ShuttingDown_Args {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Timeout_Args {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct GetMetrics_Reply {
+    pub r#projects: Vec<ProjectMetrics>,
+}
+impl varlink::VarlinkReply for GetMetrics_Reply {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct GetMetrics_Args {}
+pub trait Call_GetMetrics: VarlinkCallError {
+    fn reply(&mut self, r#projects: Vec<ProjectMetrics>) -> varlink::Result<()> {
+        self.reply_struct(GetMetrics_Reply { r#projects }.into())
+    }
+}
+impl<'a> Call_GetMetrics for varlink::Call<'a> {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct GetVersion_Reply {
+    pub r#protocol: i64,
+    pub r#build_rev: i64,
+}
+impl varlink::VarlinkReply for GetVersion_Reply {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct GetVersion_Args {}
+pub trait Call_GetVersion: VarlinkCallError {
+    fn reply(&mut self, r#protocol: i64, r#build_rev: i64) -> varlink::Result<()> {
+        self.reply_struct(
+            GetVersion_Reply {
+                r#protocol,
+                r#build_rev,
+            }
+            .into(),
+        )
+    }
+}
+impl<'a> Call_GetVersion for varlink::Call<'a> {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ListProjects_Reply {
+    pub r#projects: Vec<Project>,
+}
+impl varlink::VarlinkReply for ListProjects_Reply {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ListProjects_Args {}
+pub trait Call_ListProjects: VarlinkCallError {
+    fn reply(&mut self, r#projects: Vec<Project>) -> varlink::Result<()> {
+        self.reply_struct(ListProjects_Reply { r#projects }.into())
+    }
+}
+impl<'a> Call_ListProjects for varlink::Call<'a> {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Rebuild_Reply {}
+impl varlink::VarlinkReply for Rebuild_Reply {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Rebuild_Args {
+    pub r#shell_nix: ShellNix,
+    pub r#refresh: bool,
 }
+pub trait Call_Rebuild: VarlinkCallError {
+    fn reply(&mut self) -> varlink::Result<()> {
+        self.reply_struct(varlink::Reply::parameters(None))
+    }
+}
+impl<'a> Call_Rebuild for varlink::Call<'a> {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Shutdown_Reply {}
+impl varlink::VarlinkReply for Shutdown_Reply {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Shutdown_Args {}
+pub trait Call_Shutdown: VarlinkCallError {
+    fn reply(&mut self) -> varlink::Result<()> {
+        self.reply_struct(varlink::Reply::parameters(None))
+    }
+}
+impl<'a> Call_Shutdown for varlink::Call<'a> {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct StreamEvents_Reply {
+    pub r#event: Event,
+}
+impl varlink::VarlinkReply for StreamEvents_Reply {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct StreamEvents_Args {}
+pub trait Call_StreamEvents: VarlinkCallError {
+    fn reply(&mut self, r#event: Event) -> varlink::Result<()> {
+        self.reply_struct(StreamEvents_Reply { r#event }.into())
+    }
+}
+impl<'a> Call_StreamEvents for varlink::Call<'a> {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct UnwatchProject_Reply {
+    pub r#was_watched: bool,
+}
+impl varlink::VarlinkReply for UnwatchProject_Reply {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct UnwatchProject_Args {
+    pub r#hash: String,
+    pub r#delete_gc_roots: bool,
+}
+pub trait Call_UnwatchProject: VarlinkCallError {
+    fn reply(&mut self, r#was_watched: bool) -> varlink::Result<()> {
+        self.reply_struct(UnwatchProject_Reply { r#was_watched }.into())
+    }
+}
+impl<'a> Call_UnwatchProject for varlink::Call<'a> {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct UnwatchShell_Reply {
+    pub r#was_watched: bool,
+}
+impl varlink::VarlinkReply for UnwatchShell_Reply {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct UnwatchShell_Args {
+    pub r#shell_nix: ShellNix,
+    pub r#delete_gc_roots: bool,
+}
+pub trait Call_UnwatchShell: VarlinkCallError {
+    fn reply(&mut self, r#was_watched: bool) -> varlink::Result<()> {
+        self.reply_struct(UnwatchShell_Reply { r#was_watched }.into())
+    }
+}
+impl<'a> Call_UnwatchShell for varlink::Call<'a> {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct WaitForBuild_Reply {
+    pub r#outcome: BuildOutcome,
+}
+impl varlink::VarlinkReply for WaitForBuild_Reply {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct WaitForBuild_Args {
+    pub r#shell_nix: ShellNix,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#timeout: Option<i64>,
+}
+pub trait Call_WaitForBuild: VarlinkCallError {
+    fn reply(&mut self, r#outcome: BuildOutcome) -> varlink::Result<()> {
+        self.reply_struct(WaitForBuild_Reply { r#outcome }.into())
+    }
+}
+impl<'a> Call_WaitForBuild for varlink::Call<'a> {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct WatchServices_Reply {
+    pub r#service: Service,
+}
+impl varlink::VarlinkReply for WatchServices_Reply {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct WatchServices_Args {
+    pub r#services_nix: ServicesNix,
+}
+pub trait Call_WatchServices: VarlinkCallError {
+    fn reply(&mut self, r#service: Service) -> varlink::Result<()> {
+        self.reply_struct(WatchServices_Reply { r#service }.into())
+    }
+}
+impl<'a> Call_WatchServices for varlink::Call<'a> {}
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct WatchShell_Reply {}
 impl varlink::VarlinkReply for WatchShell_Reply {}
@@ -140,6 +522,40 @@ pub trait Call_WatchShell: VarlinkCallError {
 }
 impl<'a> Call_WatchShell for varlink::Call<'a> {}
 pub trait VarlinkInterface {
+    fn get_metrics(&self, call: &mut dyn Call_GetMetrics) -> varlink::Result<()>;
+    fn get_version(&self, call: &mut dyn Call_GetVersion) -> varlink::Result<()>;
+    fn list_projects(&self, call: &mut dyn Call_ListProjects) -> varlink::Result<()>;
+    fn rebuild(
+        &self,
+        call: &mut dyn Call_Rebuild,
+        r#shell_nix: ShellNix,
+        r#refresh: bool,
+    ) -> varlink::Result<()>;
+    fn shutdown(&self, call: &mut dyn Call_Shutdown) -> varlink::Result<()>;
+    fn stream_events(&self, call: &mut dyn Call_StreamEvents) -> varlink::Result<()>;
+    fn unwatch_project(
+        &self,
+        call: &mut dyn Call_UnwatchProject,
+        r#hash: String,
+        r#delete_gc_roots: bool,
+    ) -> varlink::Result<()>;
+    fn unwatch_shell(
+        &self,
+        call: &mut dyn Call_UnwatchShell,
+        r#shell_nix: ShellNix,
+        r#delete_gc_roots: bool,
+    ) -> varlink::Result<()>;
+    fn wait_for_build(
+        &self,
+        call: &mut dyn Call_WaitForBuild,
+        r#shell_nix: ShellNix,
+        r#timeout: Option<i64>,
+    ) -> varlink::Result<()>;
+    fn watch_services(
+        &self,
+        call: &mut dyn Call_WatchServices,
+        r#services_nix: ServicesNix,
+    ) -> varlink::Result<()>;
     fn watch_shell(
         &self,
         call: &mut dyn Call_WatchShell,
@@ -154,6 +570,39 @@ pub trait VarlinkInterface {
     }
 }
 pub trait VarlinkClientInterface {
+    fn get_metrics(&mut self) -> varlink::MethodCall<GetMetrics_Args, GetMetrics_Reply, Error>;
+    fn get_version(&mut self) -> varlink::MethodCall<GetVersion_Args, GetVersion_Reply, Error>;
+    fn list_projects(
+        &mut self,
+    ) -> varlink::MethodCall<ListProjects_Args, ListProjects_Reply, Error>;
+    fn rebuild(
+        &mut self,
+        r#shell_nix: ShellNix,
+        r#refresh: bool,
+    ) -> varlink::MethodCall<Rebuild_Args, Rebuild_Reply, Error>;
+    fn shutdown(&mut self) -> varlink::MethodCall<Shutdown_Args, Shutdown_Reply, Error>;
+    fn stream_events(
+        &mut self,
+    ) -> varlink::MethodCall<StreamEvents_Args, StreamEvents_Reply, Error>;
+    fn unwatch_project(
+        &mut self,
+        r#hash: String,
+        r#delete_gc_roots: bool,
+    ) -> varlink::MethodCall<UnwatchProject_Args, UnwatchProject_Reply, Error>;
+    fn unwatch_shell(
+        &mut self,
+        r#shell_nix: ShellNix,
+        r#delete_gc_roots: bool,
+    ) -> varlink::MethodCall<UnwatchShell_Args, UnwatchShell_Reply, Error>;
+    fn wait_for_build(
+        &mut self,
+        r#shell_nix: ShellNix,
+        r#timeout: Option<i64>,
+    ) -> varlink::MethodCall<WaitForBuild_Args, WaitForBuild_Reply, Error>;
+    fn watch_services(
+        &mut self,
+        r#services_nix: ServicesNix,
+    ) -> varlink::MethodCall<WatchServices_Args, WatchServices_Reply, Error>;
     fn watch_shell(
         &mut self,
         r#shell_nix: ShellNix,
@@ -170,6 +619,111 @@ impl VarlinkClient {
     }
 }
 impl VarlinkClientInterface for VarlinkClient {
+    fn get_metrics(&mut self) -> varlink::MethodCall<GetMetrics_Args, GetMetrics_Reply, Error> {
+        varlink::MethodCall::<GetMetrics_Args, GetMetrics_Reply, Error>::new(
+            self.connection.clone(),
+            "com.target.lorri.GetMetrics",
+            GetMetrics_Args {},
+        )
+    }
+    fn get_version(&mut self) -> varlink::MethodCall<GetVersion_Args, GetVersion_Reply, Error> {
+        varlink::MethodCall::<GetVersion_Args, GetVersion_Reply, Error>::new(
+            self.connection.clone(),
+            "com.target.lorri.GetVersion",
+            GetVersion_Args {},
+        )
+    }
+    fn list_projects(
+        &mut self,
+    ) -> varlink::MethodCall<ListProjects_Args, ListProjects_Reply, Error> {
+        varlink::MethodCall::<ListProjects_Args, ListProjects_Reply, Error>::new(
+            self.connection.clone(),
+            "com.target.lorri.ListProjects",
+            ListProjects_Args {},
+        )
+    }
+    fn rebuild(
+        &mut self,
+        r#shell_nix: ShellNix,
+        r#refresh: bool,
+    ) -> varlink::MethodCall<Rebuild_Args, Rebuild_Reply, Error> {
+        varlink::MethodCall::<Rebuild_Args, Rebuild_Reply, Error>::new(
+            self.connection.clone(),
+            "com.target.lorri.Rebuild",
+            Rebuild_Args {
+                r#shell_nix,
+                r#refresh,
+            },
+        )
+    }
+    fn shutdown(&mut self) -> varlink::MethodCall<Shutdown_Args, Shutdown_Reply, Error> {
+        varlink::MethodCall::<Shutdown_Args, Shutdown_Reply, Error>::new(
+            self.connection.clone(),
+            "com.target.lorri.Shutdown",
+            Shutdown_Args {},
+        )
+    }
+    fn stream_events(
+        &mut self,
+    ) -> varlink::MethodCall<StreamEvents_Args, StreamEvents_Reply, Error> {
+        varlink::MethodCall::<StreamEvents_Args, StreamEvents_Reply, Error>::new(
+            self.connection.clone(),
+            "com.target.lorri.StreamEvents",
+            StreamEvents_Args {},
+        )
+    }
+    fn unwatch_project(
+        &mut self,
+        r#hash: String,
+        r#delete_gc_roots: bool,
+    ) -> varlink::MethodCall<UnwatchProject_Args, UnwatchProject_Reply, Error> {
+        varlink::MethodCall::<UnwatchProject_Args, UnwatchProject_Reply, Error>::new(
+            self.connection.clone(),
+            "com.target.lorri.UnwatchProject",
+            UnwatchProject_Args {
+                r#hash,
+                r#delete_gc_roots,
+            },
+        )
+    }
+    fn unwatch_shell(
+        &mut self,
+        r#shell_nix: ShellNix,
+        r#delete_gc_roots: bool,
+    ) -> varlink::MethodCall<UnwatchShell_Args, UnwatchShell_Reply, Error> {
+        varlink::MethodCall::<UnwatchShell_Args, UnwatchShell_Reply, Error>::new(
+            self.connection.clone(),
+            "com.target.lorri.UnwatchShell",
+            UnwatchShell_Args {
+                r#shell_nix,
+                r#delete_gc_roots,
+            },
+        )
+    }
+    fn wait_for_build(
+        &mut self,
+        r#shell_nix: ShellNix,
+        r#timeout: Option<i64>,
+    ) -> varlink::MethodCall<WaitForBuild_Args, WaitForBuild_Reply, Error> {
+        varlink::MethodCall::<WaitForBuild_Args, WaitForBuild_Reply, Error>::new(
+            self.connection.clone(),
+            "com.target.lorri.WaitForBuild",
+            WaitForBuild_Args {
+                r#shell_nix,
+                r#timeout,
+            },
+        )
+    }
+    fn watch_services(
+        &mut self,
+        r#services_nix: ServicesNix,
+    ) -> varlink::MethodCall<WatchServices_Args, WatchServices_Reply, Error> {
+        varlink::MethodCall::<WatchServices_Args, WatchServices_Reply, Error>::new(
+            self.connection.clone(),
+            "com.target.lorri.WatchServices",
+            WatchServices_Args { r#services_nix },
+        )
+    }
     fn watch_shell(
         &mut self,
         r#shell_nix: ShellNix,
@@ -191,7 +745,7 @@ pub fn new(inner: Box<dyn VarlinkInterface + Send + Sync>) -> VarlinkInterfacePr
 }
 impl varlink::Interface for VarlinkInterfaceProxy {
     fn get_description(&self) -> &'static str {
-        "# The interface `lorri daemon` exposes.\ninterface com.target.lorri\n\n# WatchShell instructs the daemon to evaluate a Nix expression and re-evaluate\n# it when it or its dependencies change.\nmethod WatchShell(shell_nix: ShellNix) -> ()\n\n# ShellNix describes the Nix expression which evaluates to a development\n# environment.\ntype ShellNix (\n  # The absolute path of a Nix file specifying the project environment.\n  path: string\n)\n\n# WatchServices establishes a stream with the daemon. Initially, the daemon\n# evaluates the given services definition to an array of Command objects and\n# sends a reply for each of them. After this initial evaluation, the daemon\n# watches the services definition and its dependencies for changes,\n# re-evaluates it as appropriate and sends a reply for each Command again.\n#\n# This is a streaming RPC. The daemon only accepts client calls with the \"more\"\n# property set - see https://varlink.org/Method-Call.\n# TODO: Implement WatchServices\n#method WatchServices(services_nix: ServicesNix) -> (service: Service)\n\n# ServicesNix describes the Nix expression which evaluates to a list of\n# services.\ntype ServicesNix (\n  # The absolute path of a Nix file specifying the services to be run. This Nix\n  # file must evaluate to a JSON document of type []Command, that is, an array\n  # of objects whose properties are described by the Command type.\n  path: string\n)\n\n# Service describes an individual service to be run.\ntype Service (\n  # The user-friendly name of the service. This is used for identification\n  # purposes too: only a single instance of a service with a particular name is\n  # run at any one time.\n  name: string,\n\n  # How to run the service.\n  command: Command\n)\n\n# Command describes how to run a terminal application.\ntype Command (\n  # The path of the command binary.\n  program: string,\n\n  # Arguments to be passed to the binary.\n  args: []string\n)\n"
+        "# The interface `lorri daemon` exposes.\ninterface com.target.lorri\n\n# GetVersion returns the version of the daemon. Clients compare it to their\n# own version, to notice a daemon which was not restarted after an upgrade.\n#\n# `protocol` is increased whenever this interface changes, `build_rev` is the\n# build revision of the daemon's lorri.\nmethod GetVersion() -> (protocol: int, build_rev: int)\n\n# WatchShell instructs the daemon to evaluate a Nix expression and re-evaluate\n# it when it or its dependencies change.\nmethod WatchShell(shell_nix: ShellNix) -> ()\n\n# UnwatchShell makes the daemon stop watching and building the project. A\n# running build is cancelled. The Nix file does not need to exist anymore.\n#\n# If `delete_gc_roots` is set, the daemon also deletes the GC roots of the\n# project, so its environment can be garbage collected. The reply says whether\n# the daemon watched the project.\nmethod UnwatchShell(shell_nix: ShellNix, delete_gc_roots: bool) -> (was_watched: bool)\n\n# UnwatchProject is like UnwatchShell, but identifies the project by the `hash`\n# ListProjects reports for it. This works for every kind of project, including\n# services and projects whose Nix file was called with arguments.\n#\n# If `delete_gc_roots` is set, the GC roots of the project are deleted even if\n# the daemon does not watch it anymore.\nmethod UnwatchProject(hash: string, delete_gc_roots: bool) -> (was_watched: bool)\n\n# GcRootsNotDeleted is returned by UnwatchShell and UnwatchProject if the\n# daemon stopped watching the project, but failed to delete its GC roots.\nerror GcRootsNotDeleted (message: string)\n\n# ShellNix describes the Nix expression which evaluates to a development\n# environment.\ntype ShellNix (\n  # The absolute path of a Nix file specifying the project environment, or of\n  # the directory of a flake if `flake_shell` is set.\n  path: string,\n\n  # The name of the development shell of the flake in `path`: the project\n  # environment is `devShells.<system>.<flake_shell>` of that flake.\n  flake_shell: ?string,\n\n  # The attribute path of the project environment in the Nix file, like\n  # `nix-shell --attr`. Not considered for flakes.\n  attr: ?string,\n\n  # Nix expressions the Nix file is called with, by argument name, like\n  # `nix-shell --arg`. Not considered for flakes.\n  args: ?[string]string,\n\n  # Strings the Nix file is called with, by argument name, like\n  # `nix-shell --argstr`. Not considered for flakes.\n  argstrs: ?[string]string,\n\n  # Environment variables which influence the evaluation, like NIX_PATH. If\n  # set, the daemon evaluates the project with these values of the variables\n  # it knows (the missing ones are unset) instead of its own values, and builds\n  # the project again when they change. Other variables are ignored. Only\n  # considered by WatchShell.\n  env: ?[string]string,\n\n  # The absolute path of the directory which relative paths in NIX_PATH refer\n  # to. Only considered together with `env`.\n  cwd: ?string\n)\n\n# Rebuild makes the daemon build the project again, even if none of its inputs\n# changed. If a build is running, the rebuild starts once it finished. Like\n# WatchShell, it makes the daemon watch the project.\n#\n# If `refresh` is set, unpinned inputs (like a `fetchTarball` without a hash)\n# are fetched again instead of taken from Nix's cache.\nmethod Rebuild(shell_nix: ShellNix, refresh: bool) -> ()\n\n# WaitForBuild waits until the running build of the project finishes and\n# returns its outcome. If no build is running, it waits for the next one,\n# unless the project was built before and none of its inputs changed since:\n# then the outcome of the latest build is returned right away. Like\n# WatchShell, it makes the daemon watch the project.\n#\n# If the build does not finish within `timeout` seconds, the daemon replies\n# with the Timeout error. Without a timeout, it waits as long as it takes.\n# If the daemon stops watching the project before the build finishes (it was\n# forgotten or evicted), it replies with the NotWatched error.\nmethod WaitForBuild(shell_nix: ShellNix, timeout: ?int) -> (outcome: BuildOutcome)\n\n# BuildOutcome describes how a build ended.\ntype BuildOutcome (\n  # Whether the build succeeded.\n  kind: BuildOutcomeKind,\n\n  # The absolute path of the GC root of the build result. Only set for\n  # \"success\" outcomes.\n  gc_root: ?string,\n\n  # The last lines of the output of the failed build. Only set for \"failure\"\n  # outcomes.\n  log_tail: ?[]string\n)\n\n# BuildOutcomeKind distinguishes the different BuildOutcomes.\ntype BuildOutcomeKind (success, failure)\n\n# Timeout is returned by WaitForBuild if the build did not finish in time.\nerror Timeout ()\n\n# NotWatched is returned by WaitForBuild if the daemon stopped watching the\n# project before its build finished.\nerror NotWatched ()\n\n# WatchServices establishes a stream with the daemon. Initially, the daemon\n# evaluates the given services definition to an array of Command objects and\n# sends a reply for each of them. After this initial evaluation, the daemon\n# watches the services definition and its dependencies for changes,\n# re-evaluates it as appropriate and sends a reply for each Command again.\n#\n# This is a streaming RPC. The daemon only accepts client calls with the \"more\"\n# property set - see https://varlink.org/Method-Call.\nmethod WatchServices(services_nix: ServicesNix) -> (service: Service)\n\n# StreamEvents establishes a stream with the daemon, over which the daemon\n# sends an Event whenever a build of any of its projects starts, gets further,\n# completes or fails, or when an internal error keeps a project from being\n# built.\n#\n# This is a streaming RPC. The daemon only accepts client calls with the \"more\"\n# property set - see https://varlink.org/Method-Call.\nmethod StreamEvents() -> (event: Event)\n\n# Event describes a change of the build state of a project.\ntype Event (\n  # What happened.\n  kind: EventKind,\n\n  # The absolute path of the Nix file of the project.\n  nix_file: string,\n\n  # Why the build was started. Only set for \"started\" events.\n  reason: ?Reason,\n\n  # The absolute path of the GC root of the build result. Only set for\n  # \"completed\" events.\n  gc_root: ?string,\n\n  # The output of the failed build. Only set for \"failure\" events.\n  log_lines: ?[]string,\n\n  # A description of the internal error. The daemon tries again later. Only\n  # set for \"error\" events.\n  error: ?string,\n\n  # How far the running build got. Only set for \"progress\" events, which are\n  # sent whenever Nix starts building or fetching another path.\n  progress: ?BuildProgress\n)\n\n# EventKind distinguishes the different Events. A \"cancelled\" build was\n# superseded because its inputs changed or a rebuild was requested; a\n# \"started\" event for the next build follows.\ntype EventKind (started, progress, cancelled, completed, failure, error)\n\n# BuildProgress describes how far a running build got.\ntype BuildProgress (\n  # How many derivations Nix started building.\n  builds_started: int,\n\n  # How many derivations Nix is going to build, as far as it told so far.\n  builds_expected: int,\n\n  # How many paths Nix started fetching from a binary cache.\n  downloads_started: int,\n\n  # How many paths Nix is going to fetch, as far as it told so far.\n  downloads_expected: int\n)\n\n# Reason describes why a build was started.\ntype Reason (\n  # Why the build was started.\n  kind: ReasonKind,\n\n  # The files which changed. Always set for \"files_changed\" reasons; set for\n  # \"rebuild_requested\" and \"unknown\" reasons if files changed as well.\n  files: ?[]string,\n\n  # A description of an event the file watcher did not understand. Only set\n  # for \"unknown\" reasons.\n  debug: ?string\n)\n\n# ReasonKind distinguishes the different Reasons.\ntype ReasonKind (project_added, ping_received, rebuild_requested, environment_changed, files_changed, unknown)\n\n# ListProjects returns every project the daemon currently watches.\nmethod ListProjects() -> (projects: []Project)\n\n# Project describes a project watched by the daemon.\ntype Project (\n  # The absolute path of the Nix file of the project.\n  nix_file: string,\n\n  # The identifier of the project, derived from the path of its Nix file and\n  # the attribute and arguments the file is evaluated with.\n  hash: string,\n\n  # The build state of the project.\n  state: ProjectState,\n\n  # When the latest build finished, in seconds since the Unix epoch. Not set\n  # if no build has finished yet.\n  last_build_time: ?int,\n\n  # The absolute path of the GC root of the latest successful build. Not set\n  # if no build has succeeded yet.\n  last_gc_root: ?string,\n\n  # Whether the project was not pinged for a while, so the daemon only builds\n  # it again on the next ping instead of on every file change.\n  stale: bool,\n\n  # How far the running build got. Only set for \"building\" projects.\n  progress: ?BuildProgress\n)\n\n# ProjectState is the build state of a project.\ntype ProjectState (idle, building, failed)\n\n# GetMetrics returns build statistics of every project the daemon currently\n# watches. They are counted from when the daemon started watching the project.\nmethod GetMetrics() -> (projects: []ProjectMetrics)\n\n# ProjectMetrics describes how the builds of a project went.\ntype ProjectMetrics (\n  # The absolute path of the Nix file of the project.\n  nix_file: string,\n\n  # How many builds were started.\n  builds_started: int,\n\n  # How many builds succeeded.\n  builds_succeeded: int,\n\n  # How many builds failed because of the Nix expression.\n  builds_failed: int,\n\n  # How many builds were cancelled, because their inputs changed while they\n  # were running.\n  builds_cancelled: int,\n\n  # How many builds could not run because of an internal error.\n  build_errors: int,\n\n  # How many paths were watched for changes after the latest finished build.\n  watched_paths: int,\n\n  # The time spent evaluating the Nix file, summed over all finished builds.\n  instantiate: TimeSpent,\n\n  # The time spent building the evaluated derivation, summed over all\n  # finished builds which got that far.\n  realize: TimeSpent\n)\n\n# TimeSpent describes how long a build phase took in total.\ntype TimeSpent (\n  # How often the phase ran.\n  count: int,\n\n  # How long it took in total, in seconds.\n  seconds: float\n)\n\n# ServicesNix describes the Nix expression which evaluates to a list of\n# services.\ntype ServicesNix (\n  # The absolute path of a Nix file specifying the services to be run. This Nix\n  # file must evaluate to a JSON document of type []Command, that is, an array\n  # of objects whose properties are described by the Command type.\n  path: string\n)\n\n# Service describes an individual service to be run.\ntype Service (\n  # The user-friendly name of the service. This is used for identification\n  # purposes too: only a single instance of a service with a particular name is\n  # run at any one time.\n  name: string,\n\n  # How to run the service.\n  command: Command\n)\n\n# Command describes how to run a terminal application.\ntype Command (\n  # The path of the command binary.\n  program: string,\n\n  # Arguments to be passed to the binary.\n  args: []string\n)\n\n# Shutdown stops the daemon. It stops its services, cancels the builds which\n# are running and exits. The reply is sent as soon as the daemon starts\n# shutting down.\nmethod Shutdown() -> ()\n\n# ShuttingDown is returned by every method once the daemon is shutting down.\nerror ShuttingDown ()\n\n# PermissionDenied is returned by every method if the client runs as another\n# user than the daemon, unless the daemon allows that user with `--allow-uid`.\n# `uid` is the user id of the client.\nerror PermissionDenied (uid: int)\n"
     }
     fn get_name(&self) -> &'static str {
         "com.target.lorri"
@@ -206,6 +760,121 @@ impl varlink::Interface for VarlinkInterfaceProxy {
     fn call(&self, call: &mut varlink::Call) -> varlink::Result<()> {
         let req = call.request.unwrap();
         match req.method.as_ref() {
+            "com.target.lorri.GetMetrics" => {
+                self.inner.get_metrics(call as &mut dyn Call_GetMetrics)
+            }
+            "com.target.lorri.GetVersion" => {
+                self.inner.get_version(call as &mut dyn Call_GetVersion)
+            }
+            "com.target.lorri.ListProjects" => {
+                self.inner.list_projects(call as &mut dyn Call_ListProjects)
+            }
+            "com.target.lorri.Rebuild" => {
+                if let Some(args) = req.parameters.clone() {
+                    let args: Rebuild_Args = match serde_json::from_value(args) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            let es = format!("{}", e);
+                            let _ = call.reply_invalid_parameter(es.clone());
+                            return Err(
+                                varlink::context!(varlink::ErrorKind::SerdeJsonDe(es)).into()
+                            );
+                        }
+                    };
+                    self.inner.rebuild(
+                        call as &mut dyn Call_Rebuild,
+                        args.r#shell_nix,
+                        args.r#refresh,
+                    )
+                } else {
+                    call.reply_invalid_parameter("parameters".into())
+                }
+            }
+            "com.target.lorri.Shutdown" => self.inner.shutdown(call as &mut dyn Call_Shutdown),
+            "com.target.lorri.StreamEvents" => {
+                self.inner.stream_events(call as &mut dyn Call_StreamEvents)
+            }
+            "com.target.lorri.UnwatchProject" => {
+                if let Some(args) = req.parameters.clone() {
+                    let args: UnwatchProject_Args = match serde_json::from_value(args) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            let es = format!("{}", e);
+                            let _ = call.reply_invalid_parameter(es.clone());
+                            return Err(
+                                varlink::context!(varlink::ErrorKind::SerdeJsonDe(es)).into()
+                            );
+                        }
+                    };
+                    self.inner.unwatch_project(
+                        call as &mut dyn Call_UnwatchProject,
+                        args.r#hash,
+                        args.r#delete_gc_roots,
+                    )
+                } else {
+                    call.reply_invalid_parameter("parameters".into())
+                }
+            }
+            "com.target.lorri.UnwatchShell" => {
+                if let Some(args) = req.parameters.clone() {
+                    let args: UnwatchShell_Args = match serde_json::from_value(args) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            let es = format!("{}", e);
+                            let _ = call.reply_invalid_parameter(es.clone());
+                            return Err(
+                                varlink::context!(varlink::ErrorKind::SerdeJsonDe(es)).into()
+                            );
+                        }
+                    };
+                    self.inner.unwatch_shell(
+                        call as &mut dyn Call_UnwatchShell,
+                        args.r#shell_nix,
+                        args.r#delete_gc_roots,
+                    )
+                } else {
+                    call.reply_invalid_parameter("parameters".into())
+                }
+            }
+            "com.target.lorri.WaitForBuild" => {
+                if let Some(args) = req.parameters.clone() {
+                    let args: WaitForBuild_Args = match serde_json::from_value(args) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            let es = format!("{}", e);
+                            let _ = call.reply_invalid_parameter(es.clone());
+                            return Err(
+                                varlink::context!(varlink::ErrorKind::SerdeJsonDe(es)).into()
+                            );
+                        }
+                    };
+                    self.inner.wait_for_build(
+                        call as &mut dyn Call_WaitForBuild,
+                        args.r#shell_nix,
+                        args.r#timeout,
+                    )
+                } else {
+                    call.reply_invalid_parameter("parameters".into())
+                }
+            }
+            "com.target.lorri.WatchServices" => {
+                if let Some(args) = req.parameters.clone() {
+                    let args: WatchServices_Args = match serde_json::from_value(args) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            let es = format!("{}", e);
+                            let _ = call.reply_invalid_parameter(es.clone());
+                            return Err(
+                                varlink::context!(varlink::ErrorKind::SerdeJsonDe(es)).into()
+                            );
+                        }
+                    };
+                    self.inner
+                        .watch_services(call as &mut dyn Call_WatchServices, args.r#services_nix)
+                } else {
+                    call.reply_invalid_parameter("parameters".into())
+                }
+            }
             "com.target.lorri.WatchShell" => {
                 if let Some(args) = req.parameters.clone() {
                     let args: WatchShell_Args = match serde_json::from_value(args) {
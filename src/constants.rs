@@ -9,6 +9,7 @@ use std::path::{Path, PathBuf};
 /// Path constants like the GC root directory.
 pub struct Paths {
     gc_root_dir: PathBuf,
+    daemon_registry_file: PathBuf,
     daemon_socket_file: PathBuf,
     cas_store: ContentAddressable,
 }
@@ -53,6 +54,7 @@ impl Paths {
             .to_owned();
         let cas_dir = pd.cache_dir().join("cas");
         Ok(Paths {
+            daemon_registry_file: pd.cache_dir().join("daemon_projects.json"),
             gc_root_dir: create_dir(gc_root_dir.clone()).map_err(|err| {
                 PathsInitError::GcRootsDirectoryCantBeCreated { gc_root_dir, err }
             })?,
@@ -73,6 +75,11 @@ impl Paths {
         &self.gc_root_dir
     }
 
+    /// File in which the daemon remembers the projects it watches.
+    pub fn daemon_registry_file(&self) -> &Path {
+        &self.daemon_registry_file
+    }
+
     /// Path to the socket file.
     ///
     /// The daemon uses this path to create its Unix socket.
@@ -1,15 +1,27 @@
 //! The lorri daemon, watches multiple projects in the background.
 
-use crate::build_loop::BuildLoop;
+use crate::build_loop::{
+    self, BuildExitFailure, BuildLoops, BuildProgress, BuildResults, Event, Ping,
+};
+use crate::nix::EvalEnv;
 use crate::ops::error::ExitError;
+use crate::project::roots::RootPath;
 use crate::project::Project;
 use crate::socket::SocketPath;
 use crate::NixFile;
 use crossbeam_channel as chan;
+use slog_scope::{debug, error, info, warn};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
+mod metrics;
+mod registry;
 mod rpc;
+mod services;
+mod systemd;
+
+pub use self::metrics::ProjectMetrics;
 
 /// Indicate that the user is interested in a specific nix file.
 /// Usually a nix file describes the environment of a project,
@@ -26,89 +38,1020 @@ mod rpc;
 pub struct IndicateActivity {
     /// This nix file should be build/watched by the daemon.
     pub nix_file: NixFile,
+    /// Evaluate the nix file in this environment, see `Ping::Activity`.
+    pub env: Option<EvalEnv>,
+}
+
+/// A client wants the daemon to build a project again, even though
+/// none of its inputs changed.
+///
+/// `lorri rebuild` is the command which triggers this signal.
+pub struct Rebuild {
+    /// The nix file of the project; the daemon starts watching it if necessary.
+    pub nix_file: NixFile,
+    /// Fetch unpinned inputs again, see `builder::RunOpts::set_refresh`.
+    pub refresh: bool,
+}
+
+/// A client wants the daemon to stop watching a project.
+///
+/// `lorri forget` is the command which triggers this signal.
+pub struct UnwatchShell {
+    /// The nix file of the project, which does not need to exist anymore.
+    pub nix_file: NixFile,
+    /// Also delete the GC roots of the project.
+    pub delete_gc_roots: bool,
+    /// Receives whether the project was watched, once the daemon
+    /// stopped watching it (and deleted its GC roots).
+    pub tx: chan::Sender<std::io::Result<bool>>,
+}
+
+/// A client wants the daemon to stop watching a project,
+/// identified by its hash (see `Project::hash`).
+///
+/// `lorri forget --hash` is the command which triggers this signal.
+pub struct UnwatchProject {
+    /// The hash of the project, as reported by `Daemon::list_projects`.
+    pub hash: String,
+    /// Also delete the GC roots of the project, even if it is not watched.
+    pub delete_gc_roots: bool,
+    /// Receives whether the project was watched, once the daemon
+    /// stopped watching it (and deleted its GC roots).
+    pub tx: chan::Sender<std::io::Result<bool>>,
+}
+
+/// A client wants to know how the running or next build of a project ends.
+///
+/// `lorri wait` is the command which triggers this signal.
+pub struct WaitForBuild {
+    /// The nix file of the project; the daemon starts watching it if necessary.
+    pub nix_file: NixFile,
+    /// Receives the outcome of the build, see `Daemon::wait_for_build`,
+    /// or `None` if the daemon stops watching the project before.
+    pub tx: chan::Sender<Option<BuildOutcome>>,
+}
+
+/// How a build ended.
+pub type BuildOutcome = Result<BuildResults, BuildExitFailure>;
+
+/// A client wants to run the services in a services nix file
+/// and be told about them every time they are evaluated.
+///
+/// `lorri services` is the command which triggers this signal.
+pub struct WatchServices {
+    /// The services nix file to build/watch and run.
+    pub nix_file: NixFile,
+    /// Receives the list of services after each evaluation.
+    pub tx: chan::Sender<Vec<crate::rpc::Service>>,
+    /// The client is gone once the sender of this channel is dropped.
+    pub hangup: chan::Receiver<()>,
+}
+
+/// A client stopped watching the services in a services nix file,
+/// usually because it hung up. Once no client is left, the services stop.
+pub struct UnwatchServices {
+    /// The services nix file the client watched.
+    pub nix_file: NixFile,
+}
+
+/// A client wants to be told about every build event of every project.
+///
+/// `lorri stream-events` is the command which triggers this signal.
+pub struct StreamEvents {
+    /// Receives all build events from now on.
+    pub tx: chan::Sender<Event>,
+}
+
+/// A client wants to know which projects the daemon watches.
+///
+/// `lorri status` is the command which triggers this signal.
+pub struct ListProjects {
+    /// Receives the status of every watched project.
+    pub tx: chan::Sender<Vec<ProjectStatus>>,
+}
+
+/// A client wants to know how the builds of the watched projects went.
+pub struct GetMetrics {
+    /// Receives the metrics of every watched project.
+    pub tx: chan::Sender<Vec<ProjectMetrics>>,
+}
+
+/// Daemon settings, see `cli::DaemonOptions`.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// How many builds may run at the same time.
+    pub max_builds: usize,
+    /// Projects which were not pinged for this long are not built
+    /// on file changes anymore, only on the next ping.
+    /// Services which clients run are always built.
+    pub stale_after: Option<Duration>,
+    /// Projects which were not pinged for this long are not watched anymore.
+    pub evict_after: Option<Duration>,
+    /// Remember the watched projects in this file, to watch them
+    /// again after the daemon is restarted.
+    pub registry_file: Option<PathBuf>,
+    /// Only projects which were pinged within this time are watched
+    /// again after the daemon is restarted.
+    pub restore_within: Duration,
+    /// Wait until the input files of a project didn’t change
+    /// for this long before building it.
+    pub debounce: Duration,
+    /// Write the build metrics of all projects to this file
+    /// (see `METRICS_SAVE_INTERVAL`), in the Prometheus text format.
+    pub metrics_file: Option<PathBuf>,
+    /// Besides the daemon’s own user, clients running as these users
+    /// may connect to the daemon.
+    pub allowed_uids: Vec<u32>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            max_builds: DEFAULT_MAX_BUILDS,
+            stale_after: None,
+            evict_after: None,
+            registry_file: None,
+            restore_within: DEFAULT_RESTORE_WITHIN,
+            debounce: build_loop::DEFAULT_DEBOUNCE,
+            metrics_file: None,
+            allowed_uids: vec![],
+        }
+    }
+}
+
+/// The version of the daemon’s varlink interface, see `GetVersion`.
+/// Increase it whenever a method is added or changed.
+pub const PROTOCOL_VERSION: i64 = 7;
+
+/// The default for `Config::max_builds`.
+pub const DEFAULT_MAX_BUILDS: usize = 2;
+
+/// The default for `Config::restore_within`: a week.
+pub const DEFAULT_RESTORE_WITHIN: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How often to check for projects to evict.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often to write the latest pings to the registry file.
+/// Changes to the set of watched projects are written right away.
+const REGISTRY_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often to write the build metrics to the metrics file, if they changed.
+/// Scrapers don’t look much more often, and every write is synced to disk.
+const METRICS_SAVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// The build state of a project.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProjectState {
+    /// No build is running, and the latest build (if any) succeeded.
+    Idle,
+    /// A build is running.
+    Building,
+    /// No build is running, and the latest build failed or could not run.
+    Failed,
+}
+
+/// What the daemon knows about a project it watches.
+#[derive(Clone, Debug)]
+pub struct ProjectStatus {
+    /// The nix file of the project.
+    pub nix_file: NixFile,
+    /// See `Project::hash`.
+    pub hash: String,
+    /// The current build state.
+    pub state: ProjectState,
+    /// When the latest build finished, successful or not.
+    pub last_build_time: Option<SystemTime>,
+    /// The GC root of the latest successful build.
+    pub last_gc_root: Option<RootPath>,
+    /// Whether the project is not built on file changes anymore,
+    /// because it was not pinged for a while (see `Config::stale_after`).
+    pub stale: bool,
+    /// How far the running build got, if one is running.
+    pub progress: Option<BuildProgress>,
+}
+
+impl ProjectStatus {
+    fn new(project: &Project) -> ProjectStatus {
+        ProjectStatus {
+            nix_file: project.nix_file.clone(),
+            hash: project.hash().to_string(),
+            state: ProjectState::Idle,
+            last_build_time: None,
+            last_gc_root: None,
+            stale: false,
+            progress: None,
+        }
+    }
+
+    /// Track the state change caused by a build event of this project.
+    fn update(&mut self, event: &Event) {
+        match event {
+            Event::Started { .. } => {
+                self.state = ProjectState::Building;
+                self.progress = Some(BuildProgress::default());
+            }
+            Event::Progress { progress, .. } => self.progress = Some(*progress),
+            // the next build starts right away
+            Event::Cancelled { .. } => {}
+            Event::Completed { result, .. } => {
+                self.state = ProjectState::Idle;
+                self.progress = None;
+                self.last_build_time = Some(SystemTime::now());
+                self.last_gc_root = Some(result.output_paths.shell_gc_root.clone());
+            }
+            Event::Failure { .. } | Event::Error { .. } => {
+                self.state = ProjectState::Failed;
+                self.progress = None;
+                self.last_build_time = Some(SystemTime::now());
+            }
+        }
+    }
+}
+
+/// Instructions the RPC server passes on to the daemon.
+pub enum Request {
+    /// See `IndicateActivity`.
+    IndicateActivity(IndicateActivity),
+    /// See `WaitForBuild`.
+    WaitForBuild(WaitForBuild),
+    /// See `Rebuild`.
+    Rebuild(Rebuild),
+    /// See `UnwatchShell`.
+    UnwatchShell(UnwatchShell),
+    /// See `UnwatchProject`.
+    UnwatchProject(UnwatchProject),
+    /// See `WatchServices`.
+    WatchServices(WatchServices),
+    /// See `UnwatchServices`.
+    UnwatchServices(UnwatchServices),
+    /// See `StreamEvents`.
+    StreamEvents(StreamEvents),
+    /// See `ListProjects`.
+    ListProjects(ListProjects),
+    /// See `GetMetrics`.
+    GetMetrics(GetMetrics),
+    /// Stop the daemon, see `Daemon::shutdown`.
+    ///
+    /// Sent by the `Shutdown` RPC and on SIGTERM/SIGINT.
+    Shutdown,
 }
 
 struct Handler {
-    tx: chan::Sender<()>,
-    _handle: std::thread::JoinHandle<()>,
+    status: ProjectStatus,
+    last_ping: SystemTime,
+    /// The outcome of the latest build.
+    last_outcome: Option<BuildOutcome>,
+    /// Clients waiting for the running or next build to finish.
+    waiters: Vec<chan::Sender<Option<BuildOutcome>>>,
+    metrics: ProjectMetrics,
+}
+
+impl Handler {
+    fn idle_for_longer_than(&self, duration: Option<Duration>) -> bool {
+        duration.map_or(false, |d| idle_for_longer_than(self.last_ping, d))
+    }
+
+    /// Tell the waiting clients that the project is not watched anymore.
+    fn unwatched(self) {
+        for waiter in self.waiters {
+            // the client might have given up already
+            let _ = waiter.send(None);
+        }
+    }
+}
+
+/// Whether `last_ping` is more than `duration` ago.
+/// A `last_ping` in the future (the clock was changed) is never idle.
+fn idle_for_longer_than(last_ping: SystemTime, duration: Duration) -> bool {
+    last_ping
+        .elapsed()
+        .map_or(false, |elapsed| elapsed > duration)
 }
 
 /// Keeps all state of the running `lorri daemon` service, watches nix files and runs builds.
 pub struct Daemon {
-    /// A thread for each `BuildLoop`, keyed by the nix files listened on.
-    handler_threads: HashMap<NixFile, Handler>,
-    /// Sending end that we pass to every `BuildLoop` the daemon controls.
-    // TODO: this needs to transmit information to identify the builder with
-    build_tx: chan::Sender<crate::build_loop::Event>,
+    config: Config,
+    /// The state of each watched project, keyed by its nix file.
+    handlers: HashMap<NixFile, Handler>,
+    /// Watches and builds all projects.
+    build_loops: BuildLoops,
+    /// The services run by the daemon, keyed by their services nix file.
+    services: HashMap<NixFile, services::Services>,
+    /// Clients which receive all build events.
+    event_subscribers: Vec<chan::Sender<Event>>,
+    /// Receives the events of `build_loops`.
+    build_events_rx: chan::Receiver<Event>,
+    /// All build events are passed on to this channel, see `Daemon::new`.
+    build_tx: chan::Sender<Event>,
+    /// Projects were added or removed since the registry was last written.
+    projects_changed: bool,
+    /// Projects were pinged since the registry was last written.
+    pings_unsaved: bool,
+    /// The metrics changed since the metrics file was last written.
+    metrics_unsaved: bool,
 }
 
 impl Daemon {
     /// Create a new daemon. Also return an `chan::Receiver` that
-    /// receives `build_loop::Event`s for all builders this daemon
-    /// supervises.
-    pub fn new() -> (Daemon, chan::Receiver<crate::build_loop::Event>) {
+    /// receives `build_loop::Event`s for all projects this daemon
+    /// watches.
+    pub fn new(config: Config) -> (Daemon, chan::Receiver<Event>) {
+        let (build_events_tx, build_events_rx) = chan::unbounded();
         let (build_tx, build_rx) = chan::unbounded();
         (
             Daemon {
-                handler_threads: HashMap::new(),
+                build_loops: BuildLoops::new(
+                    config.max_builds,
+                    config.debounce,
+                    config.stale_after,
+                    build_events_tx,
+                ),
+                config,
+                handlers: HashMap::new(),
+                services: HashMap::new(),
+                event_subscribers: Vec::new(),
+                build_events_rx,
                 build_tx,
+                projects_changed: false,
+                pings_unsaved: false,
+                metrics_unsaved: false,
             },
             build_rx,
         )
     }
 
     /// Serve the daemon's RPC endpoint.
+    ///
+    /// Returns after the daemon was shut down, by a `Shutdown` call or
+    /// a message on `shutdown_rx` (e.g. sent on SIGTERM/SIGINT).
+    /// The socket file (unless it was passed in by systemd)
+    /// and its lock file are removed by then.
     pub fn serve(
-        mut self,
+        self,
         socket_path: SocketPath,
         gc_root_dir: PathBuf,
         cas: crate::cas::ContentAddressable,
+        shutdown_rx: chan::Receiver<()>,
     ) -> Result<(), ExitError> {
-        let (activity_tx, activity_rx) = chan::unbounded();
-        let server = rpc::Server::new(socket_path, activity_tx)?;
+        let (request_tx, request_rx) = chan::unbounded();
+        let socket_files = SocketPath::from(socket_path.path());
+        let server = rpc::Server::new(
+            socket_path,
+            request_tx.clone(),
+            self.config.allowed_uids.clone(),
+        )?;
+        let remove_socket = server.bound_socket();
+        systemd::notify("READY=1");
+
+        // dropping `stop_tx` stops the accept loop
+        let (stop_tx, stop_rx) = chan::bounded::<()>(0);
+        let accept_loop = std::thread::Builder::new()
+            .name(String::from("accept-loop"))
+            .spawn(move || {
+                if let Err(e) = server.serve(stop_rx) {
+                    error!("failed to serve daemon server endpoint, shutting down"; "error" => e.message());
+                    let _ = request_tx.send(Request::Shutdown);
+                }
+            })?;
         let mut pool = crate::thread::Pool::new();
-        pool.spawn("accept-loop", move || {
-            server
-                .serve()
-                .expect("failed to serve daemon server endpoint");
-        })?;
         pool.spawn("build-instruction-handler", move || {
-            // For each build instruction, add the corresponding file
-            // to the watch list.
-            for start_build in activity_rx {
-                let project =
-                    crate::project::Project::new(start_build.nix_file, &gc_root_dir, cas.clone())
-                        // TODO: the project needs to create its gc root dir
-                        .unwrap();
-                self.add(project)
-            }
+            self.handle_requests(request_rx, shutdown_rx, &gc_root_dir, cas)
         })?;
         pool.join_all_or_panic();
 
+        // The accept loop holds the socket lock, so it has to stop
+        // before the lock file is removed. It is blocked accepting
+        // the next client, so we wake it up by connecting.
+        drop(stop_tx);
+        let _ = std::os::unix::net::UnixStream::connect(socket_files.path());
+        if accept_loop.join().is_err() {
+            error!("accept loop thread panicked");
+        }
+        if remove_socket {
+            socket_files.remove_socket();
+        }
+        socket_files.remove_lockfile();
         Ok(())
     }
 
+    /// Handle the requests coming in from the RPC server and the events
+    /// coming in from the build loops, until the RPC server goes away.
+    #[allow(clippy::drop_copy, clippy::zero_ptr)] // triggered by `select!`
+    fn handle_requests(
+        mut self,
+        request_rx: chan::Receiver<Request>,
+        mut shutdown_rx: chan::Receiver<()>,
+        gc_root_dir: &Path,
+        cas: crate::cas::ContentAddressable,
+    ) {
+        let build_events_rx = self.build_events_rx.clone();
+        let eviction_tick = match self.config.evict_after {
+            Some(_) => chan::tick(EVICTION_INTERVAL),
+            None => chan::never(),
+        };
+        let watchdog_tick = systemd::watchdog_interval().map_or_else(chan::never, chan::tick);
+        let registry_tick = match self.config.registry_file {
+            Some(_) => chan::tick(REGISTRY_SAVE_INTERVAL),
+            None => chan::never(),
+        };
+        let metrics_tick = match self.config.metrics_file {
+            Some(_) => chan::tick(METRICS_SAVE_INTERVAL),
+            None => chan::never(),
+        };
+        let mut status = String::new();
+        let project = |nix_file| {
+            crate::project::Project::new(nix_file, gc_root_dir, cas.clone())
+                // TODO: the project needs to create its gc root dir
+                .unwrap()
+        };
+        for entry in self.projects_to_restore() {
+            info!("restoring project"; "nix_file" => &entry.nix_file);
+            // its services only start once a client subscribes to them
+            if let NixFile::Services(_) = entry.nix_file {
+                self.services
+                    .entry(entry.nix_file.clone())
+                    .or_insert_with(|| services::Services::new(&entry.nix_file));
+            }
+            self.add(project(entry.nix_file.clone()));
+            // the project was not actually pinged, keep its idle time and priority
+            if let Some(handler) = self.handlers.get_mut(&entry.nix_file) {
+                handler.last_ping = entry.last_ping();
+            }
+            self.build_loops
+                .set_last_ping(&entry.nix_file, entry.last_ping());
+        }
+        self.save_registry();
+        'requests: loop {
+            let notifications = self.build_loops.notifications();
+            let updates = self.build_loops.updates();
+            let build_timer = self.build_loops.timer();
+            chan::select! {
+                recv(request_rx) -> msg => match msg {
+                    // For each build instruction, add the corresponding file
+                    // to the watch list.
+                    Ok(Request::IndicateActivity(IndicateActivity { nix_file, env })) => {
+                        self.ping(project(nix_file), Ping::Activity { env })
+                    }
+                    Ok(Request::Rebuild(Rebuild { nix_file, refresh })) => {
+                        self.ping(project(nix_file), Ping::Rebuild { refresh })
+                    }
+                    Ok(Request::UnwatchShell(UnwatchShell { nix_file, delete_gc_roots, tx })) => {
+                        let was_watched = self.unwatch(&nix_file);
+                        let result = if delete_gc_roots {
+                            info!("deleting GC roots"; "nix_file" => &nix_file);
+                            crate::project::Project::new(nix_file, gc_root_dir, cas.clone())
+                                .and_then(|project| project.remove_gc_roots())
+                                .map(|()| was_watched)
+                        } else {
+                            Ok(was_watched)
+                        };
+                        // the client might have hung up already
+                        let _ = tx.send(result);
+                    }
+                    Ok(Request::UnwatchProject(UnwatchProject { hash, delete_gc_roots, tx })) => {
+                        let was_watched = match self.find_project(&hash) {
+                            Some(nix_file) => self.unwatch(&nix_file),
+                            None => false,
+                        };
+                        let result = if delete_gc_roots {
+                            info!("deleting GC roots"; "hash" => &hash);
+                            crate::project::remove_gc_roots(gc_root_dir, &hash)
+                                .map(|()| was_watched)
+                        } else {
+                            Ok(was_watched)
+                        };
+                        // the client might have hung up already
+                        let _ = tx.send(result);
+                    }
+                    Ok(Request::WaitForBuild(WaitForBuild { nix_file, tx })) => {
+                        self.add(project(nix_file.clone()));
+                        self.wait_for_build(&nix_file, tx)
+                    }
+                    Ok(Request::WatchServices(WatchServices { nix_file, tx, hangup })) => {
+                        self.services
+                            .entry(nix_file.clone())
+                            .or_insert_with(|| services::Services::new(&nix_file))
+                            .subscribe(tx, hangup);
+                        self.add(project(nix_file.clone()));
+                        // the services run, so they are kept up to date
+                        self.build_loops.keep_fresh(&nix_file, true)
+                    }
+                    Ok(Request::UnwatchServices(UnwatchServices { nix_file })) => {
+                        if let Some(services) = self.services.get_mut(&nix_file) {
+                            services.remove_hung_up_subscribers();
+                            self.build_loops.keep_fresh(&nix_file, services.has_subscribers())
+                        }
+                    }
+                    Ok(Request::StreamEvents(StreamEvents { tx })) => {
+                        self.event_subscribers.push(tx)
+                    }
+                    Ok(Request::ListProjects(ListProjects { tx })) => {
+                        // the client might have hung up already
+                        let _ = tx.send(self.list_projects());
+                    }
+                    Ok(Request::GetMetrics(GetMetrics { tx })) => {
+                        // the client might have hung up already
+                        let _ = tx.send(self.metrics());
+                    }
+                    Ok(Request::Shutdown) | Err(chan::RecvError) => break 'requests,
+                },
+                recv(shutdown_rx) -> msg => match msg {
+                    Ok(()) => break 'requests,
+                    // nobody can ask for a shutdown this way anymore
+                    Err(chan::RecvError) => shutdown_rx = chan::never(),
+                },
+                recv(notifications) -> msg => self.build_loops.notified(msg),
+                recv(updates) -> msg => if let Ok(update) = msg {
+                    self.build_loops.updated(update)
+                },
+                recv(build_timer) -> _ => self.build_loops.tick(),
+                recv(build_events_rx) -> msg => if let Ok(event) = msg {
+                    self.handle_build_event(event)
+                },
+                recv(eviction_tick) -> _ => self.evict_idle_projects(),
+                recv(registry_tick) -> _ => if self.pings_unsaved {
+                    self.save_registry()
+                },
+                recv(metrics_tick) -> _ => if self.metrics_unsaved {
+                    self.save_metrics()
+                },
+                // we only get here if this loop is not stuck
+                recv(watchdog_tick) -> _ => systemd::notify("WATCHDOG=1"),
+            }
+            if self.projects_changed {
+                self.save_registry();
+            }
+            let new_status = self.status_line();
+            if new_status != status {
+                systemd::notify(&format!("STATUS={}", new_status));
+                status = new_status;
+            }
+        }
+        // Clients are told that the daemon shuts down from now on,
+        // instead of getting an answer from a daemon which forgets
+        // everything right away.
+        drop(request_rx);
+        self.shutdown()
+    }
+
+    /// Stop all builds and services. Running builds are cancelled,
+    /// which also cleans up their temporary GC roots.
+    /// The registry keeps all projects, so they are restored on the next start.
+    fn shutdown(mut self) {
+        info!("shutting down"; "projects" => self.handlers.len());
+        systemd::notify("STOPPING=1");
+        if self.projects_changed || self.pings_unsaved {
+            self.save_registry();
+        }
+        // stops all service processes
+        self.services.clear();
+        // waits until the cancelled builds stopped
+        self.build_loops.shutdown();
+        // pass on the events of the builds which were still running
+        let events: Vec<Event> = self.build_events_rx.try_iter().collect();
+        for event in events {
+            self.handle_build_event(event)
+        }
+        if self.metrics_unsaved {
+            self.save_metrics();
+        }
+    }
+
+    /// Send the outcome of the running build of `nix_file` to `tx` once it
+    /// finishes. If no build is running or pending (e.g. because the inputs
+    /// changed), the outcome of the latest build is sent right away;
+    /// if there is none yet, that of the next build.
+    fn wait_for_build(&mut self, nix_file: &NixFile, tx: chan::Sender<Option<BuildOutcome>>) {
+        let pending = self.build_loops.has_pending_build(nix_file);
+        match self.handlers.get_mut(nix_file) {
+            Some(handler) => match &handler.last_outcome {
+                Some(outcome) if !pending => {
+                    // the client might have hung up already
+                    let _ = tx.send(Some(outcome.clone()));
+                }
+                _ => handler.waiters.push(tx),
+            },
+            None => {
+                let _ = tx.send(None);
+            }
+        }
+    }
+
+    /// A one-line summary of what the daemon is doing, for `systemctl status`.
+    fn status_line(&self) -> String {
+        let count = |state| {
+            self.handlers
+                .values()
+                .filter(|handler| handler.status.state == state)
+                .count()
+        };
+        format!(
+            "watching {} projects, {} building, {} failed",
+            self.handlers.len(),
+            count(ProjectState::Building),
+            count(ProjectState::Failed)
+        )
+    }
+
+    /// The status of every project this daemon watches.
+    pub fn list_projects(&self) -> Vec<ProjectStatus> {
+        self.handlers
+            .values()
+            .map(|handler| ProjectStatus {
+                stale: self.build_loops.is_stale(&handler.status.nix_file),
+                ..handler.status.clone()
+            })
+            .collect()
+    }
+
+    /// The build metrics of every project this daemon watches,
+    /// ordered by their nix file.
+    pub fn metrics(&self) -> Vec<ProjectMetrics> {
+        let mut metrics: Vec<ProjectMetrics> = self
+            .handlers
+            .values()
+            .map(|handler| handler.metrics.clone())
+            .collect();
+        metrics.sort_by_key(|m| PathBuf::from(&m.nix_file));
+        metrics
+    }
+
+    /// Stop watching the projects which were not pinged
+    /// for longer than `Config::evict_after`.
+    /// Services which clients are still subscribed to are kept.
+    fn evict_idle_projects(&mut self) {
+        let evict_after = self.config.evict_after;
+        let services = &self.services;
+        let idle: Vec<NixFile> = self
+            .handlers
+            .iter()
+            .filter(|(nix_file, handler)| {
+                handler.idle_for_longer_than(evict_after)
+                    && !services
+                        .get(nix_file)
+                        .map_or(false, services::Services::has_subscribers)
+            })
+            .map(|(nix_file, _)| nix_file.clone())
+            .collect();
+        for nix_file in idle {
+            info!("evicting idle project"; "nix_file" => &nix_file);
+            if let Some(handler) = self.handlers.remove(&nix_file) {
+                handler.unwatched();
+            }
+            self.build_loops.remove(&nix_file);
+            self.services.remove(&nix_file);
+            self.projects_changed = true;
+            self.metrics_unsaved = true;
+        }
+    }
+
+    /// The nix file of the watched project with the given `hash`.
+    fn find_project(&self, hash: &str) -> Option<NixFile> {
+        self.handlers
+            .iter()
+            .find(|(_, handler)| handler.status.hash == hash)
+            .map(|(nix_file, _)| nix_file.clone())
+    }
+
+    /// Stop watching `nix_file`. Its running build is cancelled,
+    /// and no GC roots are created for it anymore.
+    /// Returns whether the project was watched.
+    fn unwatch(&mut self, nix_file: &NixFile) -> bool {
+        match self.handlers.remove(nix_file) {
+            Some(handler) => handler.unwatched(),
+            None => return false,
+        }
+        info!("no longer watching project"; "nix_file" => nix_file);
+        self.build_loops.remove(nix_file);
+        self.services.remove(nix_file);
+        self.projects_changed = true;
+        self.metrics_unsaved = true;
+        true
+    }
+
+    /// The registered projects which still exist, were pinged
+    /// recently enough and would not be evicted yet.
+    fn projects_to_restore(&self) -> Vec<registry::Entry> {
+        let registry = match &self.config.registry_file {
+            Some(file) => registry::Registry::new(file.clone()),
+            None => return vec![],
+        };
+        match registry.load() {
+            Ok(entries) => entries
+                .into_iter()
+                .filter(|entry| PathBuf::from(&entry.nix_file).is_file())
+                .filter(|entry| {
+                    !idle_for_longer_than(entry.last_ping(), self.config.restore_within)
+                })
+                .filter(|entry| {
+                    self.config
+                        .evict_after
+                        .map_or(true, |d| !idle_for_longer_than(entry.last_ping(), d))
+                })
+                .collect(),
+            Err(e) => {
+                warn!("failed to read the project registry, starting without projects"; "error" => %e);
+                vec![]
+            }
+        }
+    }
+
+    /// Write the watched projects to the registry file, if there is one.
+    fn save_registry(&mut self) {
+        self.projects_changed = false;
+        self.pings_unsaved = false;
+        if let Some(file) = &self.config.registry_file {
+            let entries: Vec<registry::Entry> = self
+                .handlers
+                .iter()
+                .map(|(nix_file, handler)| {
+                    registry::Entry::new(nix_file.clone(), handler.last_ping)
+                })
+                .collect();
+            if let Err(e) = registry::Registry::new(file.clone()).save(&entries) {
+                warn!("failed to write the project registry"; "file" => file.display(), "error" => %e);
+            }
+        }
+    }
+
+    /// Write the build metrics to the metrics file, if there is one.
+    fn save_metrics(&mut self) {
+        self.metrics_unsaved = false;
+        if let Some(file) = &self.config.metrics_file {
+            if let Err(e) = metrics::write_textfile(file, &self.metrics()) {
+                warn!("failed to write the metrics file"; "file" => file.display(), "error" => %e);
+            }
+        }
+    }
+
+    /// Update the project status and metrics and restart the services of a services
+    /// nix file after it was built, then pass the event on to all subscribers.
+    fn handle_build_event(&mut self, event: Event) {
+        let nix_file = match &event {
+            Event::Started { nix_file, .. }
+            | Event::Progress { nix_file, .. }
+            | Event::Cancelled { nix_file }
+            | Event::Completed { nix_file, .. }
+            | Event::Failure { nix_file, .. }
+            | Event::Error { nix_file, .. } => nix_file,
+        };
+        if let Some(handler) = self.handlers.get_mut(nix_file) {
+            handler.status.update(&event);
+            handler.metrics.update(&event);
+            let outcome = match &event {
+                // waiting clients get the outcome of the next build
+                Event::Started { .. } | Event::Progress { .. } | Event::Cancelled { .. } => None,
+                Event::Completed { result, .. } => Some(Ok(result.clone())),
+                Event::Failure { failure, .. } => Some(Err(failure.clone())),
+                // the build loop tries again, but waiting clients should know
+                Event::Error { error, .. } => Some(Err(BuildExitFailure {
+                    log_lines: vec![error.to_string().into()],
+                })),
+            };
+            if let Some(outcome) = outcome {
+                for waiter in handler.waiters.drain(..) {
+                    // the client might have given up already
+                    let _ = waiter.send(Some(outcome.clone()));
+                }
+                handler.last_outcome = Some(outcome);
+            }
+        }
+        if let Event::Completed {
+            nix_file, result, ..
+        } = &event
+        {
+            if let Some(services) = self.services.get_mut(nix_file) {
+                match services::read(result.output_paths.shell_gc_root.as_path()) {
+                    Ok(evaluated) => services.update(
+                        evaluated,
+                        result.output_paths.shell_gc_root.as_path().to_path_buf(),
+                    ),
+                    Err(e) => {
+                        error!("failed to read the evaluated services"; "nix_file" => nix_file, "error" => %e)
+                    }
+                }
+            }
+        }
+        match event {
+            // progress doesn’t change the metrics
+            Event::Progress { .. } => {}
+            _ => self.metrics_unsaved = true,
+        }
+        // subscribers which hung up are dropped
+        self.event_subscribers
+            .retain(|tx| tx.send(event.clone()).is_ok());
+        if self.build_tx.send(event).is_err() {
+            // nobody listens anymore, e.g. while shutting down
+            debug!("dropped a build event without receiver");
+        }
+    }
+
     /// Add nix file to the set of files this daemon watches
     /// & build if they change.
     pub fn add(&mut self, project: Project) {
-        let (tx, rx) = chan::unbounded();
-        let build_tx = self.build_tx.clone();
+        self.ping(project, Ping::Activity { env: None })
+    }
+
+    /// Pass `ping` to the build loop of `project`, which is added first
+    /// if the daemon does not watch the project yet.
+    fn ping(&mut self, project: Project, ping: Ping) {
+        let now = SystemTime::now();
+        if self.handlers.contains_key(&project.nix_file) {
+            self.pings_unsaved = true;
+        } else {
+            self.projects_changed = true;
+        }
 
-        self.handler_threads
+        let handler = self
+            .handlers
             .entry(project.nix_file.clone())
             .or_insert_with(|| Handler {
-                tx,
-                _handle: std::thread::spawn(move || {
-                    let mut build_loop = BuildLoop::new(&project);
-
-                    // cloning the tx means the daemon’s rx gets all
-                    // messages from all builders.
-                    build_loop.forever(build_tx, rx);
-                }),
-            })
-            // Notify the handler, whether or not it was newly added
-            .tx
-            .send(())
-            .unwrap();
+                status: ProjectStatus::new(&project),
+                last_ping: now,
+                last_outcome: None,
+                waiters: Vec::new(),
+                metrics: ProjectMetrics::new(project.nix_file.clone(), project.hash()),
+            });
+        handler.last_ping = now;
+        self.build_loops.ping(&project, ping);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ShellArgs;
+
+    /// Only existing projects which were pinged recently enough are restored.
+    #[test]
+    fn restores_recently_pinged_projects() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let shell_nix = |name: &str| -> std::io::Result<NixFile> {
+            let file = tempdir.path().join(name);
+            std::fs::write(&file, "{}")?;
+            Ok(NixFile::shell(file, ShellArgs::default()))
+        };
+        let recent = shell_nix("recent.nix")?;
+        let old = shell_nix("old.nix")?;
+        let deleted = NixFile::shell(tempdir.path().join("deleted.nix"), ShellArgs::default());
+        let now = SystemTime::now();
+        let a_day = Duration::from_secs(24 * 60 * 60);
+        let registry_file = tempdir.path().join("registry.json");
+        registry::Registry::new(registry_file.clone()).save(&[
+            registry::Entry::new(recent.clone(), now - a_day),
+            registry::Entry::new(old, now - a_day * 30),
+            registry::Entry::new(deleted, now),
+        ])?;
+
+        let (daemon, _build_rx) = Daemon::new(Config {
+            registry_file: Some(registry_file),
+            ..Config::default()
+        });
+        let restored: Vec<NixFile> = daemon
+            .projects_to_restore()
+            .into_iter()
+            .map(|entry| entry.nix_file)
+            .collect();
+        assert_eq!(restored, vec![recent]);
+        Ok(())
+    }
+
+    /// Projects which were not pinged for a while are evicted,
+    /// unless clients run their services.
+    #[test]
+    fn evicts_idle_projects_without_subscribers() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let cas = crate::cas::ContentAddressable::new(tempdir.path().join("cas"))?;
+        let a_day = Duration::from_secs(24 * 60 * 60);
+        let (mut daemon, _build_rx) = Daemon::new(Config {
+            evict_after: Some(a_day),
+            ..Config::default()
+        });
+        let mut add = |nix_file: NixFile, last_ping| -> std::io::Result<NixFile> {
+            std::fs::write(PathBuf::from(&nix_file), "{}")?;
+            daemon.add(Project::new(
+                nix_file.clone(),
+                &tempdir.path().join("gc_roots"),
+                cas.clone(),
+            )?);
+            daemon.handlers.get_mut(&nix_file).unwrap().last_ping = last_ping;
+            Ok(nix_file)
+        };
+        let now = SystemTime::now();
+        let recent = add(
+            NixFile::shell(tempdir.path().join("recent.nix"), ShellArgs::default()),
+            now,
+        )?;
+        let idle = add(
+            NixFile::shell(tempdir.path().join("idle.nix"), ShellArgs::default()),
+            now - a_day * 2,
+        )?;
+        let subscribed = add(
+            NixFile::Services(tempdir.path().join("services.nix")),
+            now - a_day * 2,
+        )?;
+        let (services_tx, _services_rx) = chan::unbounded();
+        let (_hangup_tx, hangup_rx) = chan::bounded(0);
+        daemon
+            .services
+            .entry(subscribed.clone())
+            .or_insert_with(|| services::Services::new(&subscribed))
+            .subscribe(services_tx, hangup_rx);
+
+        daemon.evict_idle_projects();
+        let mut watched: Vec<NixFile> = daemon.handlers.keys().cloned().collect();
+        watched.sort_by_key(|nix_file| PathBuf::from(nix_file));
+        assert_eq!(watched, vec![recent, subscribed]);
+        assert!(!daemon.handlers.contains_key(&idle));
+        assert!(daemon.metrics_unsaved);
+
+        // without idle projects there is nothing to save
+        daemon.metrics_unsaved = false;
+        daemon.evict_idle_projects();
+        assert!(!daemon.metrics_unsaved);
+        daemon.shutdown();
+        Ok(())
+    }
+
+    /// Shutting down cancels running builds instead of waiting for them.
+    #[test]
+    fn shutdown_cancels_running_builds() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let cas = crate::cas::ContentAddressable::new(tempdir.path().join("cas"))?;
+        let shell_nix = tempdir.path().join("shell.nix");
+        // the shell depends on a derivation whose build never ends
+        std::fs::write(
+            &shell_nix,
+            r#"
+let
+  drv = name: attrs: derivation ({
+    inherit name;
+    builder = "/bin/sh";
+    allowSubstitutes = false;
+    preferLocalBuild = true;
+    system = builtins.currentSystem;
+    # this is to make nix rebuild for every test
+    random = builtins.currentTime;
+  } // attrs);
+in
+drv "shell" { dep = drv "dep" { args = [ "-c" "while :; do :; done" ]; }; }
+"#,
+        )?;
+        let nix_file = NixFile::shell(shell_nix, ShellArgs::default());
+        let (mut daemon, _build_rx) = Daemon::new(Config::default());
+        daemon.add(Project::new(
+            nix_file,
+            &tempdir.path().join("gc_roots"),
+            cas,
+        )?);
+        // a worker picked up the build
+        let update = daemon
+            .build_loops
+            .updates()
+            .recv_timeout(Duration::from_secs(60))
+            .expect("the build did not start");
+        daemon.build_loops.updated(update);
+        std::thread::sleep(Duration::from_secs(1));
+
+        let (done_tx, done_rx) = chan::bounded(1);
+        std::thread::spawn(move || {
+            daemon.shutdown();
+            done_tx.send(()).unwrap();
+        });
+        assert_eq!(
+            done_rx.recv_timeout(Duration::from_secs(30)),
+            Ok(()),
+            "the daemon waited for the running build"
+        );
+        Ok(())
+    }
+
+    /// Clients waiting for a build don’t get the outcome of an older build
+    /// while the next one is pending, and are told if the project is forgotten.
+    #[test]
+    fn waiters_get_the_pending_build_or_not_watched() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let cas = crate::cas::ContentAddressable::new(tempdir.path().join("cas"))?;
+        let shell_nix = tempdir.path().join("shell.nix");
+        std::fs::write(&shell_nix, "{}")?;
+        let nix_file = NixFile::shell(shell_nix, ShellArgs::default());
+        let (mut daemon, _build_rx) = Daemon::new(Config::default());
+        daemon.add(Project::new(
+            nix_file.clone(),
+            &tempdir.path().join("gc_roots"),
+            cas,
+        )?);
+        daemon.handlers.get_mut(&nix_file).unwrap().last_outcome =
+            Some(Err(BuildExitFailure { log_lines: vec![] }));
+
+        let (tx, rx) = chan::bounded(1);
+        daemon.wait_for_build(&nix_file, tx);
+        assert!(rx.try_recv().is_err(), "the new project is built first");
+
+        assert!(daemon.unwatch(&nix_file));
+        assert_eq!(rx.try_recv().map(|outcome| outcome.is_none()), Ok(true));
+        daemon.shutdown();
+        Ok(())
     }
 }
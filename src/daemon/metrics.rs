@@ -0,0 +1,281 @@
+//! Count the builds of the projects the daemon watches and measure
+//! how long they take.
+//!
+//! The metrics are served by the `GetMetrics` call and can be written
+//! to a file in the text format of Prometheus, to be picked up by the
+//! textfile collector of the node exporter.
+
+use crate::build_loop::{BuildMetrics, Event, LoopError};
+use crate::NixFile;
+use atomicwrites::{AtomicFile, OverwriteBehavior};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The time spent in one phase of the builds of a project.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TimeSpent {
+    /// How often the phase ran
+    pub count: u64,
+    /// How long it took in total
+    pub total: Duration,
+}
+
+impl TimeSpent {
+    fn add(&mut self, duration: Duration) {
+        self.count += 1;
+        self.total += duration;
+    }
+}
+
+/// Build statistics of a project, since the daemon started watching it.
+#[derive(Clone, Debug)]
+pub struct ProjectMetrics {
+    /// The nix file of the project.
+    pub nix_file: NixFile,
+    /// The hash of the project, which tells apart the projects of one nix file.
+    pub hash: String,
+    /// How many builds were started.
+    pub builds_started: u64,
+    /// How many builds succeeded.
+    pub builds_succeeded: u64,
+    /// How many builds failed because of the Nix expression.
+    pub builds_failed: u64,
+    /// How many builds were cancelled because their inputs changed.
+    pub builds_cancelled: u64,
+    /// How many builds could not run because of an internal error.
+    pub build_errors: u64,
+    /// How many paths were watched after the latest finished build.
+    pub watched_paths: usize,
+    /// Time spent evaluating the nix file.
+    pub instantiate: TimeSpent,
+    /// Time spent building the evaluated derivation.
+    pub realize: TimeSpent,
+}
+
+impl ProjectMetrics {
+    /// Metrics of a project which was not built yet.
+    pub fn new(nix_file: NixFile, hash: &str) -> ProjectMetrics {
+        ProjectMetrics {
+            nix_file,
+            hash: hash.to_string(),
+            builds_started: 0,
+            builds_succeeded: 0,
+            builds_failed: 0,
+            builds_cancelled: 0,
+            build_errors: 0,
+            watched_paths: 0,
+            instantiate: TimeSpent::default(),
+            realize: TimeSpent::default(),
+        }
+    }
+
+    /// Count a build event of this project.
+    pub fn update(&mut self, event: &Event) {
+        match event {
+            Event::Started { .. } => self.builds_started += 1,
+            Event::Cancelled { .. } => self.builds_cancelled += 1,
+            Event::Completed { metrics, .. } => {
+                self.builds_succeeded += 1;
+                self.finish(metrics);
+            }
+            Event::Failure { metrics, .. } => {
+                self.builds_failed += 1;
+                self.finish(metrics);
+            }
+            Event::Error {
+                error: LoopError::Build(_),
+                ..
+            } => self.build_errors += 1,
+            // not related to a build, or not finished yet
+            Event::Error {
+                error: LoopError::Watch(_),
+                ..
+            }
+            | Event::Progress { .. } => {}
+        }
+    }
+
+    fn finish(&mut self, metrics: &BuildMetrics) {
+        self.watched_paths = metrics.watched_paths;
+        self.instantiate.add(metrics.durations.instantiate);
+        if let Some(realize) = metrics.durations.realize {
+            self.realize.add(realize);
+        }
+    }
+}
+
+/// Render the metrics of all `projects` in the Prometheus text format.
+/// Every sample is labelled with the hash and the nix file of its project;
+/// the hash tells apart the projects of one nix file called with different arguments.
+pub fn prometheus(projects: &[ProjectMetrics]) -> String {
+    let mut out = String::new();
+    {
+        let mut family =
+            |name: &str,
+             kind: &str,
+             help: &str,
+             samples: &[(&str, &dyn Fn(&ProjectMetrics) -> f64)]| {
+                out.push_str(&format!("# HELP {} {}\n", name, help));
+                out.push_str(&format!("# TYPE {} {}\n", name, kind));
+                for (suffix, value) in samples {
+                    for project in projects {
+                        out.push_str(&format!(
+                            "{}{}{{project=\"{}\",nix_file=\"{}\"}} {}\n",
+                            name,
+                            suffix,
+                            project.hash,
+                            escape_label_value(&PathBuf::from(&project.nix_file)),
+                            value(project)
+                        ));
+                    }
+                }
+            };
+        family(
+            "lorri_builds_started_total",
+            "counter",
+            "Builds started.",
+            &[("", &|p| p.builds_started as f64)],
+        );
+        family(
+            "lorri_builds_succeeded_total",
+            "counter",
+            "Builds which succeeded.",
+            &[("", &|p| p.builds_succeeded as f64)],
+        );
+        family(
+            "lorri_builds_failed_total",
+            "counter",
+            "Builds which failed because of the Nix expression.",
+            &[("", &|p| p.builds_failed as f64)],
+        );
+        family(
+            "lorri_builds_cancelled_total",
+            "counter",
+            "Builds which were cancelled because their inputs changed.",
+            &[("", &|p| p.builds_cancelled as f64)],
+        );
+        family(
+            "lorri_build_errors_total",
+            "counter",
+            "Builds which could not run because of an internal error.",
+            &[("", &|p| p.build_errors as f64)],
+        );
+        family(
+            "lorri_watched_paths",
+            "gauge",
+            "Paths watched for changes after the latest finished build.",
+            &[("", &|p| p.watched_paths as f64)],
+        );
+        family(
+            "lorri_instantiate_seconds",
+            "summary",
+            "Time spent evaluating the Nix expression.",
+            &[
+                ("_sum", &|p| seconds(p.instantiate.total)),
+                ("_count", &|p| p.instantiate.count as f64),
+            ],
+        );
+        family(
+            "lorri_realize_seconds",
+            "summary",
+            "Time spent building the evaluated derivation.",
+            &[
+                ("_sum", &|p| seconds(p.realize.total)),
+                ("_count", &|p| p.realize.count as f64),
+            ],
+        );
+    }
+    out
+}
+
+/// Atomically replace `file` with the metrics of all `projects`,
+/// in the Prometheus text format.
+pub fn write_textfile(file: &Path, projects: &[ProjectMetrics]) -> std::io::Result<()> {
+    let text = prometheus(projects);
+    AtomicFile::new_with_tmpdir(
+        file,
+        OverwriteBehavior::AllowOverwrite,
+        file.parent().unwrap_or_else(|| Path::new("/")),
+    )
+    .write(|f| f.write_all(text.as_bytes()))
+    .map_err(std::io::Error::from)
+}
+
+/// `duration` in (fractional) seconds.
+pub fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+fn escape_label_value(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_loop::BuildExitFailure;
+    use crate::builder::Durations;
+    use crate::watch::Reason;
+    use crate::ShellArgs;
+
+    fn started(nix_file: &NixFile) -> Event {
+        Event::Started {
+            nix_file: nix_file.clone(),
+            reason: Reason::PingReceived,
+        }
+    }
+
+    #[test]
+    fn counts_builds_and_renders_them() {
+        let nix_file = NixFile::shell(
+            PathBuf::from("/project \"a\"/shell.nix"),
+            ShellArgs::default(),
+        );
+        let mut metrics = ProjectMetrics::new(nix_file.clone(), "0123abcd");
+        metrics.update(&started(&nix_file));
+        // the first build is cancelled by the second one
+        metrics.update(&Event::Cancelled {
+            nix_file: nix_file.clone(),
+        });
+        metrics.update(&started(&nix_file));
+        metrics.update(&Event::Failure {
+            nix_file: nix_file.clone(),
+            failure: BuildExitFailure { log_lines: vec![] },
+            metrics: BuildMetrics {
+                durations: Durations {
+                    instantiate: Duration::from_millis(1500),
+                    realize: Some(Duration::from_secs(3)),
+                },
+                watched_paths: 7,
+            },
+        });
+
+        assert_eq!(metrics.builds_started, 2);
+        assert_eq!(metrics.builds_cancelled, 1);
+        assert_eq!(metrics.builds_failed, 1);
+        assert_eq!(metrics.watched_paths, 7);
+
+        let text = prometheus(&[metrics]);
+        let label = r#"{project="0123abcd",nix_file="/project \"a\"/shell.nix"}"#;
+        for line in &[
+            "# TYPE lorri_builds_started_total counter".to_string(),
+            format!("lorri_builds_started_total{} 2", label),
+            format!("lorri_builds_cancelled_total{} 1", label),
+            format!("lorri_builds_succeeded_total{} 0", label),
+            format!("lorri_watched_paths{} 7", label),
+            format!("lorri_instantiate_seconds_sum{} 1.5", label),
+            format!("lorri_realize_seconds_count{} 1", label),
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing line `{}` in:\n{}",
+                line,
+                text
+            );
+        }
+    }
+}
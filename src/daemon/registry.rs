@@ -0,0 +1,127 @@
+//! Remember the projects the daemon watches across restarts.
+//!
+//! The registry is a JSON file listing every watched `NixFile`
+//! together with the time it was last pinged. The daemon rewrites
+//! it whenever the set of watched projects changes (and every now and
+//! then to record the latest pings) and reads it on startup to re-add
+//! the recently used projects.
+
+use crate::NixFile;
+use atomicwrites::{AtomicFile, OverwriteBehavior};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A watched project, as written to the registry file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The nix file of the project.
+    pub nix_file: NixFile,
+    /// When the project was last pinged, in seconds since the unix epoch.
+    last_ping: u64,
+}
+
+impl Entry {
+    /// Create an entry for `nix_file`, last pinged at `last_ping`.
+    pub fn new(nix_file: NixFile, last_ping: SystemTime) -> Entry {
+        Entry {
+            nix_file,
+            last_ping: last_ping
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+
+    /// When the project was last pinged.
+    pub fn last_ping(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.last_ping)
+    }
+}
+
+/// The registry file of a daemon.
+#[derive(Clone, Debug)]
+pub struct Registry {
+    file: PathBuf,
+}
+
+impl Registry {
+    /// A registry stored in `file`. The file does not need to exist yet.
+    pub fn new(file: PathBuf) -> Registry {
+        Registry { file }
+    }
+
+    /// Read all entries. A missing registry file is treated as empty.
+    pub fn load(&self) -> std::io::Result<Vec<Entry>> {
+        match std::fs::File::open(&self.file) {
+            Ok(file) => Ok(serde_json::from_reader(file)?),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Atomically replace the registry with `entries`.
+    pub fn save(&self, entries: &[Entry]) -> std::io::Result<()> {
+        let json = serde_json::to_vec_pretty(entries)?;
+        AtomicFile::new_with_tmpdir(
+            &self.file,
+            OverwriteBehavior::AllowOverwrite,
+            self.file.parent().unwrap_or_else(|| Path::new("/")),
+        )
+        .write(|f| f.write_all(&json))
+        .map_err(std::io::Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ShellArgs;
+
+    #[test]
+    fn save_and_load() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let registry = Registry::new(tempdir.path().join("projects.json"));
+        assert_eq!(registry.load()?, vec![]);
+
+        let entries = vec![
+            Entry::new(
+                NixFile::shell(PathBuf::from("/project/shell.nix"), ShellArgs::default()),
+                UNIX_EPOCH + Duration::from_secs(1000),
+            ),
+            Entry::new(
+                NixFile::Services(PathBuf::from("/project/services.nix")),
+                UNIX_EPOCH + Duration::from_secs(2000),
+            ),
+        ];
+        registry.save(&entries)?;
+        assert_eq!(registry.load()?, entries);
+        assert_eq!(
+            registry.load()?[1].last_ping(),
+            UNIX_EPOCH + Duration::from_secs(2000)
+        );
+        Ok(())
+    }
+
+    /// Registries are shared by daemons of different lorri versions.
+    #[test]
+    fn stable_format() -> serde_json::Result<()> {
+        let args = ShellArgs {
+            attr: Some(String::from("shells.docs")),
+            ..ShellArgs::default()
+        };
+        let shell = |args| NixFile::shell(PathBuf::from("/project/default.nix"), args);
+
+        let plain = r#"{"Shell":"/project/default.nix"}"#;
+        assert_eq!(serde_json::to_string(&shell(ShellArgs::default()))?, plain);
+        assert_eq!(
+            serde_json::from_str::<NixFile>(plain)?,
+            shell(ShellArgs::default())
+        );
+
+        let with_args = r#"{"ShellWithArgs":{"file":"/project/default.nix","args":{"attr":"shells.docs","args":{},"argstrs":{}}}}"#;
+        assert_eq!(serde_json::to_string(&shell(args.clone()))?, with_args);
+        assert_eq!(serde_json::from_str::<NixFile>(with_args)?, shell(args));
+        Ok(())
+    }
+}
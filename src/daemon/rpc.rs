@@ -1,78 +1,738 @@
 //! The daemon's RPC server.
 
-use super::IndicateActivity;
+use super::systemd;
+use super::{
+    BuildOutcome, GetMetrics, IndicateActivity, ListProjects, ProjectMetrics, ProjectState,
+    ProjectStatus, Rebuild, Request, StreamEvents, UnwatchProject, UnwatchServices, UnwatchShell,
+    WaitForBuild, WatchServices,
+};
+use crate::build_loop::{BuildProgress, Event};
+use crate::nix::EvalEnv;
 use crate::ops::error::ExitError;
 use crate::rpc;
 use crate::socket::{BindLock, SocketPath};
-use crate::NixFile;
+use crate::watch::Reason;
+use crate::{NixFile, ShellArgs};
 use crossbeam_channel as chan;
+use slog_scope::{debug, info, warn};
 use std::convert::TryFrom;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+/// How many lines of the output of a failed build `WaitForBuild` returns.
+const LOG_TAIL_LINES: usize = 50;
+
+/// How many clients are served at the same time. Further clients
+/// wait in the socket’s backlog until a connection is closed.
+const MAX_CONNECTIONS: usize = 64;
+
+/// How long a rejected client gets to send its call, see `deny_connection`.
+const REJECT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How often a streaming call checks whether its client hung up
+/// while there is nothing to send.
+const HANGUP_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 /// The daemon server.
 pub struct Server {
-    activity_tx: chan::Sender<IndicateActivity>,
-    socket_path: SocketPath,
+    request_tx: chan::Sender<Request>,
+    listener: UnixListener,
+    /// Whether we bound the socket ourselves, instead of getting it from systemd.
+    bound_socket: bool,
+    /// Only clients running as one of these users are served.
+    allowed_uids: Vec<u32>,
     _lock: BindLock,
 }
 
 impl Server {
     /// Create a new Server. Locks the Unix socket path, so there can be only one Server instance
     /// per socket path at any time.
+    ///
+    /// If the daemon was started by systemd socket activation, the passed socket is used,
+    /// otherwise the server binds to `socket_path` itself.
+    ///
+    /// Only clients running as the same user as the daemon or as one of
+    /// `allowed_uids` are served, see `Server::serve`.
     pub fn new(
         socket_path: SocketPath,
-        activity_tx: chan::Sender<IndicateActivity>,
+        request_tx: chan::Sender<Request>,
+        mut allowed_uids: Vec<u32>,
     ) -> Result<Server, ExitError> {
         let lock = socket_path.lock()?;
+        let (listener, bound_socket) = match systemd::take_listener() {
+            Some(listener) => {
+                info!("using the socket passed by systemd");
+                (listener, false)
+            }
+            None => (socket_path.bind(&lock)?, true),
+        };
+        allowed_uids.push(nix::unistd::geteuid().as_raw());
         Ok(Server {
-            socket_path,
-            activity_tx,
+            request_tx,
+            listener,
+            bound_socket,
+            allowed_uids,
             _lock: lock,
         })
     }
 
+    /// Whether the server bound the socket file itself. If so, it should
+    /// remove the file when the daemon stops; a socket passed in by systemd
+    /// belongs to systemd.
+    pub fn bound_socket(&self) -> bool {
+        self.bound_socket
+    }
+
     /// Serve the daemon endpoint.
-    pub fn serve(self) -> Result<(), ExitError> {
-        let address = &self.socket_path.address();
-        let service = varlink::VarlinkService::new(
-            /* vendor */ "com.target",
-            /* product */ "lorri",
-            /* version */ "0.1",
-            /* url */ "https://github.com/target/lorri",
-            vec![Box::new(rpc::new(Box::new(self)))],
-        );
-        let initial_worker_threads = 1;
-        let max_worker_threads = 1;
-        let idle_timeout = 0;
-        varlink::listen(
-            service,
-            address,
-            initial_worker_threads,
-            max_worker_threads,
-            idle_timeout,
-        )
-        .map_err(|e| ExitError::temporary(format!("{}", e)))
+    ///
+    /// Every client connection is handled in its own thread, because
+    /// streaming calls (like `WatchServices`) occupy the connection
+    /// for as long as the client stays connected. At most `MAX_CONNECTIONS`
+    /// clients are served at the same time.
+    ///
+    /// Anyone who can connect could make the daemon evaluate arbitrary
+    /// nix files, so clients of other users are rejected right away:
+    /// they get a single `PermissionDenied` error, and the connection
+    /// is closed without ever occupying a thread.
+    ///
+    /// Returns once `stop_rx` receives a message or is disconnected.
+    /// The server only notices that when it accepts the next client,
+    /// so whoever stops it should connect to the socket afterwards.
+    #[allow(clippy::drop_copy, clippy::zero_ptr)] // triggered by `select!`
+    pub fn serve(self, stop_rx: chan::Receiver<()>) -> Result<(), ExitError> {
+        // every connection thread holds one message in this channel
+        let (slots_tx, slots_rx) = chan::bounded(MAX_CONNECTIONS);
+        loop {
+            // blocks until a connection thread finishes, if there are too many
+            chan::select! {
+                send(slots_tx, ()) -> _ => {},
+                recv(stop_rx) -> _ => return Ok(()),
+            }
+            let slot = ConnectionSlot(slots_rx.clone());
+            let (stream, _) = self
+                .listener
+                .accept()
+                .map_err(|e| ExitError::temporary(format!("failed to accept a client: {}", e)))?;
+            match stop_rx.try_recv() {
+                Err(chan::TryRecvError::Empty) => {}
+                Ok(()) | Err(chan::TryRecvError::Disconnected) => return Ok(()),
+            }
+            match peer_uid(&stream) {
+                Ok(uid) if self.allowed_uids.contains(&uid) => {}
+                Ok(uid) => {
+                    warn!("rejecting a client of another user"; "uid" => uid);
+                    if let Err(e) = deny_connection(stream, uid) {
+                        debug!("failed to reject client"; "error" => %e);
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    warn!("rejecting a client which could not be identified"; "error" => %e);
+                    continue;
+                }
+            }
+            let request_tx = self.request_tx.clone();
+            thread::Builder::new()
+                .name(String::from("rpc-connection"))
+                .spawn(move || {
+                    let _slot = slot;
+                    if let Err(e) = serve_connection(request_tx, stream) {
+                        debug!("client connection failed"; "error" => %e);
+                    }
+                })
+                .map_err(|e| {
+                    ExitError::temporary(format!("failed to spawn connection thread: {}", e))
+                })?;
+        }
+    }
+}
+
+/// Frees a slot for another connection when dropped, see `Server::serve`.
+struct ConnectionSlot(chan::Receiver<()>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        // there is a message for every slot in use
+        let _ = self.0.try_recv();
+    }
+}
+
+/// Answer the calls of a client until it hangs up.
+fn serve_connection(request_tx: chan::Sender<Request>, stream: UnixStream) -> std::io::Result<()> {
+    use varlink::ConnectionHandler;
+    let handler = varlink::VarlinkService::new(
+        /* vendor */ "com.target",
+        /* product */ "lorri",
+        /* version */ &crate::VERSION_BUILD_REV.to_string(),
+        /* url */ "https://github.com/target/lorri",
+        vec![Box::new(rpc::new(Box::new(Endpoint {
+            request_tx,
+            client: stream.try_clone()?,
+        })))],
+    );
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut upgraded_iface = None;
+    // nothing left to read means the client hung up
+    while !reader.fill_buf()?.is_empty() {
+        upgraded_iface = handler
+            .handle(&mut reader, &mut writer, upgraded_iface)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?
+            .1;
+    }
+    Ok(())
+}
+
+/// Answer the first call of a client with the `PermissionDenied` error
+/// and close the connection. Runs in the accept loop, so it waits for
+/// the call at most `REJECT_TIMEOUT`.
+fn deny_connection(mut stream: UnixStream, uid: u32) -> std::io::Result<()> {
+    let mut reply = serde_json::to_vec(&serde_json::json!({
+        "error": "com.target.lorri.PermissionDenied",
+        "parameters": { "uid": uid },
+    }))?;
+    // varlink messages are terminated by a NUL byte
+    reply.push(0);
+    // the reply fits into the empty send buffer of the new connection
+    stream.set_nonblocking(true)?;
+    stream.write_all(&reply)?;
+    // A client whose call did not arrive yet would fail to send it to
+    // the closed connection, instead of reading the reply.
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REJECT_TIMEOUT))?;
+    let _ = stream.read(&mut [0; 1024]);
+    stream.shutdown(std::net::Shutdown::Both)
+}
+
+/// The user id of the process on the other end of `stream`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> std::io::Result<u32> {
+    use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+    getsockopt(stream.as_raw_fd(), PeerCredentials)
+        .map(|credentials| credentials.uid())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+}
+
+/// The user id of the process on the other end of `stream`.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(stream: &UnixStream) -> std::io::Result<u32> {
+    let mut uid = 0;
+    let mut gid = 0;
+    // macOS and the BSDs have no `SO_PEERCRED`
+    match unsafe { nix::libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } {
+        0 => Ok(uid),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// Whether the client on the other end of `stream` closed the connection.
+fn hung_up(stream: &UnixStream) -> bool {
+    use nix::sys::socket::{recv, MsgFlags};
+    let mut buf = [0; 1];
+    match recv(
+        stream.as_raw_fd(),
+        &mut buf,
+        MsgFlags::MSG_PEEK | MsgFlags::MSG_DONTWAIT,
+    ) {
+        // end of file
+        Ok(0) => true,
+        // the client already sent its next call
+        Ok(_) => false,
+        Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => false,
+        Err(_) => true,
+    }
+}
+
+/// Passes the calls of a client on to the daemon.
+struct Endpoint {
+    request_tx: chan::Sender<Request>,
+    /// The connection to the client.
+    client: UnixStream,
+}
+
+impl Endpoint {
+    /// Pass `request` on to the daemon. Returns `false` if the daemon
+    /// does not accept requests anymore, because it is shutting down.
+    fn send(&self, request: Request) -> bool {
+        self.request_tx.send(request).is_ok()
+    }
+
+    /// Reply to `call` with every list of services `rx` receives, until
+    /// the client hangs up or the daemon stops sending.
+    fn stream_services(
+        &self,
+        call: &mut dyn rpc::Call_WatchServices,
+        rx: &chan::Receiver<Vec<crate::rpc::Service>>,
+    ) -> varlink::Result<()> {
+        call.set_continues(true);
+        loop {
+            match rx.recv_timeout(HANGUP_POLL_INTERVAL) {
+                Ok(services) => {
+                    for service in services {
+                        call.reply(service)?;
+                    }
+                }
+                Err(chan::RecvTimeoutError::Timeout) => {
+                    if hung_up(&self.client) {
+                        return Ok(());
+                    }
+                }
+                Err(chan::RecvTimeoutError::Disconnected) => {
+                    call.set_continues(false);
+                    return call.reply_shutting_down();
+                }
+            }
+        }
     }
 }
 
 /// The actual varlink server implementation. See com.target.lorri.varlink for the interface
 /// specification.
-impl rpc::VarlinkInterface for Server {
+impl rpc::VarlinkInterface for Endpoint {
+    fn get_version(&self, call: &mut dyn rpc::Call_GetVersion) -> varlink::Result<()> {
+        call.reply(super::PROTOCOL_VERSION, crate::VERSION_BUILD_REV as i64)
+    }
+
     fn watch_shell(
         &self,
         call: &mut dyn rpc::Call_WatchShell,
         shell_nix: rpc::ShellNix,
+    ) -> varlink::Result<()> {
+        let env = match eval_env(&shell_nix) {
+            Ok(env) => env,
+            Err(e) => return call.reply_invalid_parameter(e),
+        };
+        match NixFile::try_from(shell_nix) {
+            Ok(nix_file) => {
+                if self.send(Request::IndicateActivity(IndicateActivity {
+                    nix_file,
+                    env,
+                })) {
+                    call.reply()
+                } else {
+                    call.reply_shutting_down()
+                }
+            }
+            Err(e) => call.reply_invalid_parameter(e),
+        }
+    }
+
+    fn unwatch_shell(
+        &self,
+        call: &mut dyn rpc::Call_UnwatchShell,
+        shell_nix: rpc::ShellNix,
+        delete_gc_roots: bool,
+    ) -> varlink::Result<()> {
+        // the project might have been deleted already, so we don’t check that the file exists
+        let args = shell_args(&shell_nix);
+        let path = PathBuf::from(shell_nix.path);
+        if !path.is_absolute() {
+            return call.reply_invalid_parameter("shell_nix".into());
+        }
+        let nix_file = match shell_nix.flake_shell {
+            Some(shell) => NixFile::Flake { dir: path, shell },
+            None => NixFile::shell(path, args),
+        };
+        let (tx, rx) = chan::bounded(1);
+        if !self.send(Request::UnwatchShell(UnwatchShell {
+            nix_file,
+            delete_gc_roots,
+            tx,
+        })) {
+            return call.reply_shutting_down();
+        }
+        match rx.recv() {
+            Ok(Ok(was_watched)) => call.reply(was_watched),
+            Ok(Err(e)) => call.reply_gc_roots_not_deleted(e.to_string()),
+            // the daemon started shutting down before answering
+            Err(chan::RecvError) => call.reply_shutting_down(),
+        }
+    }
+
+    fn unwatch_project(
+        &self,
+        call: &mut dyn rpc::Call_UnwatchProject,
+        hash: String,
+        delete_gc_roots: bool,
+    ) -> varlink::Result<()> {
+        // the hash names a directory below the GC root directory
+        if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return call.reply_invalid_parameter("hash".into());
+        }
+        let (tx, rx) = chan::bounded(1);
+        if !self.send(Request::UnwatchProject(UnwatchProject {
+            hash,
+            delete_gc_roots,
+            tx,
+        })) {
+            return call.reply_shutting_down();
+        }
+        match rx.recv() {
+            Ok(Ok(was_watched)) => call.reply(was_watched),
+            Ok(Err(e)) => call.reply_gc_roots_not_deleted(e.to_string()),
+            // the daemon started shutting down before answering
+            Err(chan::RecvError) => call.reply_shutting_down(),
+        }
+    }
+
+    fn rebuild(
+        &self,
+        call: &mut dyn rpc::Call_Rebuild,
+        shell_nix: rpc::ShellNix,
+        refresh: bool,
     ) -> varlink::Result<()> {
         match NixFile::try_from(shell_nix) {
             Ok(nix_file) => {
-                self.activity_tx
-                    .send(IndicateActivity { nix_file })
-                    .expect("failed to indicate activity via channel");
-                call.reply()
+                if self.send(Request::Rebuild(Rebuild { nix_file, refresh })) {
+                    call.reply()
+                } else {
+                    call.reply_shutting_down()
+                }
             }
             Err(e) => call.reply_invalid_parameter(e),
         }
     }
+
+    fn wait_for_build(
+        &self,
+        call: &mut dyn rpc::Call_WaitForBuild,
+        shell_nix: rpc::ShellNix,
+        timeout: Option<i64>,
+    ) -> varlink::Result<()> {
+        let nix_file = match NixFile::try_from(shell_nix) {
+            Ok(nix_file) => nix_file,
+            Err(e) => return call.reply_invalid_parameter(e),
+        };
+        let (tx, rx) = chan::bounded(1);
+        if !self.send(Request::WaitForBuild(WaitForBuild { nix_file, tx })) {
+            return call.reply_shutting_down();
+        }
+        let outcome = match timeout {
+            None => rx.recv().ok(),
+            Some(seconds) => match rx.recv_timeout(Duration::from_secs(seconds.max(0) as u64)) {
+                Ok(outcome) => Some(outcome),
+                Err(chan::RecvTimeoutError::Timeout) => return call.reply_timeout(),
+                Err(chan::RecvTimeoutError::Disconnected) => None,
+            },
+        };
+        match outcome {
+            Some(Some(outcome)) => call.reply(rpc::BuildOutcome::from(&outcome)),
+            // the project was forgotten or evicted
+            Some(None) => call.reply_not_watched(),
+            None => call.reply_shutting_down(),
+        }
+    }
+
+    fn watch_services(
+        &self,
+        call: &mut dyn rpc::Call_WatchServices,
+        services_nix: rpc::ServicesNix,
+    ) -> varlink::Result<()> {
+        if !call.wants_more() {
+            return call.reply_invalid_parameter("more".into());
+        }
+        let nix_file = match NixFile::try_from(services_nix) {
+            Ok(nix_file) => nix_file,
+            Err(e) => return call.reply_invalid_parameter(e),
+        };
+
+        let (tx, rx) = chan::unbounded();
+        let (hangup_tx, hangup) = chan::bounded(0);
+        if !self.send(Request::WatchServices(WatchServices {
+            nix_file: nix_file.clone(),
+            tx,
+            hangup,
+        })) {
+            return call.reply_shutting_down();
+        }
+
+        // This stream never ends while the daemon is running. Once the
+        // client hangs up, we tell the daemon, so it can stop the
+        // services if nobody else watches them.
+        let result = self.stream_services(call, &rx);
+        drop(hangup_tx);
+        // the daemon might be shutting down, then it stops the services anyway
+        self.send(Request::UnwatchServices(UnwatchServices { nix_file }));
+        result
+    }
+
+    fn stream_events(&self, call: &mut dyn rpc::Call_StreamEvents) -> varlink::Result<()> {
+        if !call.wants_more() {
+            return call.reply_invalid_parameter("more".into());
+        }
+
+        let (tx, rx) = chan::unbounded();
+        if !self.send(Request::StreamEvents(StreamEvents { tx })) {
+            return call.reply_shutting_down();
+        }
+
+        // Like `watch_services`, this stream only ends when the client hangs up.
+        call.set_continues(true);
+        loop {
+            match rx.recv_timeout(HANGUP_POLL_INTERVAL) {
+                Ok(event) => call.reply(rpc::Event::from(&event))?,
+                // the daemon might not build anything for a long time
+                Err(chan::RecvTimeoutError::Timeout) => {
+                    if hung_up(&self.client) {
+                        return Ok(());
+                    }
+                }
+                Err(chan::RecvTimeoutError::Disconnected) => {
+                    call.set_continues(false);
+                    return call.reply_shutting_down();
+                }
+            }
+        }
+    }
+
+    fn list_projects(&self, call: &mut dyn rpc::Call_ListProjects) -> varlink::Result<()> {
+        let (tx, rx) = chan::bounded(1);
+        if !self.send(Request::ListProjects(ListProjects { tx })) {
+            return call.reply_shutting_down();
+        }
+        match rx.recv() {
+            Ok(projects) => call.reply(projects.iter().map(rpc::Project::from).collect()),
+            // the daemon started shutting down before answering
+            Err(chan::RecvError) => call.reply_shutting_down(),
+        }
+    }
+
+    fn get_metrics(&self, call: &mut dyn rpc::Call_GetMetrics) -> varlink::Result<()> {
+        let (tx, rx) = chan::bounded(1);
+        if !self.send(Request::GetMetrics(GetMetrics { tx })) {
+            return call.reply_shutting_down();
+        }
+        match rx.recv() {
+            Ok(metrics) => call.reply(metrics.iter().map(rpc::ProjectMetrics::from).collect()),
+            // the daemon started shutting down before answering
+            Err(chan::RecvError) => call.reply_shutting_down(),
+        }
+    }
+
+    fn shutdown(&self, call: &mut dyn rpc::Call_Shutdown) -> varlink::Result<()> {
+        if self.send(Request::Shutdown) {
+            call.reply()
+        } else {
+            call.reply_shutting_down()
+        }
+    }
+}
+
+impl From<&ProjectStatus> for rpc::Project {
+    fn from(status: &ProjectStatus) -> Self {
+        rpc::Project {
+            nix_file: path_to_string(PathBuf::from(&status.nix_file)),
+            hash: status.hash.clone(),
+            state: match status.state {
+                ProjectState::Idle => rpc::ProjectState::idle,
+                ProjectState::Building => rpc::ProjectState::building,
+                ProjectState::Failed => rpc::ProjectState::failed,
+            },
+            last_build_time: status.last_build_time.map(|time| {
+                time.duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_secs() as i64)
+                    .unwrap_or(0)
+            }),
+            last_gc_root: status
+                .last_gc_root
+                .as_ref()
+                .map(|root| path_to_string(root.as_path())),
+            stale: status.stale,
+            progress: status.progress.as_ref().map(rpc::BuildProgress::from),
+        }
+    }
+}
+
+impl From<&ProjectMetrics> for rpc::ProjectMetrics {
+    fn from(metrics: &ProjectMetrics) -> Self {
+        let time_spent = |time: &super::metrics::TimeSpent| rpc::TimeSpent {
+            count: time.count as i64,
+            seconds: super::metrics::seconds(time.total),
+        };
+        rpc::ProjectMetrics {
+            nix_file: path_to_string(PathBuf::from(&metrics.nix_file)),
+            builds_started: metrics.builds_started as i64,
+            builds_succeeded: metrics.builds_succeeded as i64,
+            builds_failed: metrics.builds_failed as i64,
+            builds_cancelled: metrics.builds_cancelled as i64,
+            build_errors: metrics.build_errors as i64,
+            watched_paths: metrics.watched_paths as i64,
+            instantiate: time_spent(&metrics.instantiate),
+            realize: time_spent(&metrics.realize),
+        }
+    }
+}
+
+fn path_to_string<P: AsRef<std::path::Path>>(path: P) -> String {
+    path.as_ref().to_string_lossy().into_owned()
+}
+
+impl From<&Event> for rpc::Event {
+    fn from(event: &Event) -> Self {
+        match event {
+            Event::Started { nix_file, reason } => rpc::Event {
+                kind: rpc::EventKind::started,
+                nix_file: path_to_string(PathBuf::from(nix_file)),
+                reason: Some(rpc::Reason::from(reason)),
+                gc_root: None,
+                log_lines: None,
+                error: None,
+                progress: None,
+            },
+            Event::Progress { nix_file, progress } => rpc::Event {
+                kind: rpc::EventKind::progress,
+                nix_file: path_to_string(PathBuf::from(nix_file)),
+                reason: None,
+                gc_root: None,
+                log_lines: None,
+                error: None,
+                progress: Some(rpc::BuildProgress::from(progress)),
+            },
+            Event::Cancelled { nix_file } => rpc::Event {
+                kind: rpc::EventKind::cancelled,
+                nix_file: path_to_string(PathBuf::from(nix_file)),
+                reason: None,
+                gc_root: None,
+                log_lines: None,
+                error: None,
+                progress: None,
+            },
+            Event::Completed {
+                nix_file, result, ..
+            } => rpc::Event {
+                kind: rpc::EventKind::completed,
+                nix_file: path_to_string(PathBuf::from(nix_file)),
+                reason: None,
+                gc_root: Some(path_to_string(result.output_paths.shell_gc_root.as_path())),
+                log_lines: None,
+                error: None,
+                progress: None,
+            },
+            Event::Failure {
+                nix_file, failure, ..
+            } => rpc::Event {
+                kind: rpc::EventKind::failure,
+                nix_file: path_to_string(PathBuf::from(nix_file)),
+                reason: None,
+                gc_root: None,
+                log_lines: Some(
+                    failure
+                        .log_lines
+                        .iter()
+                        .map(|line| line.to_string_lossy().into_owned())
+                        .collect(),
+                ),
+                error: None,
+                progress: None,
+            },
+            Event::Error { nix_file, error } => rpc::Event {
+                kind: rpc::EventKind::error,
+                nix_file: path_to_string(PathBuf::from(nix_file)),
+                reason: None,
+                gc_root: None,
+                log_lines: None,
+                error: Some(error.to_string()),
+                progress: None,
+            },
+        }
+    }
+}
+
+impl From<&BuildProgress> for rpc::BuildProgress {
+    fn from(progress: &BuildProgress) -> Self {
+        rpc::BuildProgress {
+            builds_started: progress.builds_started as i64,
+            builds_expected: progress.builds_expected as i64,
+            downloads_started: progress.downloads_started as i64,
+            downloads_expected: progress.downloads_expected as i64,
+        }
+    }
+}
+
+impl From<&rpc::BuildProgress> for BuildProgress {
+    fn from(progress: &rpc::BuildProgress) -> Self {
+        BuildProgress {
+            builds_started: progress.builds_started as usize,
+            builds_expected: progress.builds_expected as usize,
+            downloads_started: progress.downloads_started as usize,
+            downloads_expected: progress.downloads_expected as usize,
+        }
+    }
+}
+
+impl From<&Reason> for rpc::Reason {
+    fn from(reason: &Reason) -> Self {
+        match reason {
+            Reason::ProjectAdded(_) => rpc::Reason {
+                kind: rpc::ReasonKind::project_added,
+                files: None,
+                debug: None,
+            },
+            Reason::PingReceived => rpc::Reason {
+                kind: rpc::ReasonKind::ping_received,
+                files: None,
+                debug: None,
+            },
+            Reason::RebuildRequested { files, .. } => rpc::Reason {
+                kind: rpc::ReasonKind::rebuild_requested,
+                files: changed_files(files),
+                debug: None,
+            },
+            Reason::EnvironmentChanged => rpc::Reason {
+                kind: rpc::ReasonKind::environment_changed,
+                files: None,
+                debug: None,
+            },
+            Reason::FilesChanged(files) => rpc::Reason {
+                kind: rpc::ReasonKind::files_changed,
+                files: Some(files.iter().map(path_to_string).collect()),
+                debug: None,
+            },
+            Reason::UnknownEvent { message, files } => rpc::Reason {
+                kind: rpc::ReasonKind::unknown,
+                files: changed_files(files),
+                debug: Some(format!("{:?}", message)),
+            },
+        }
+    }
+}
+
+/// The `files` of an `rpc::Reason` other than `files_changed`, which are optional.
+fn changed_files(files: &[std::path::PathBuf]) -> Option<Vec<String>> {
+    if files.is_empty() {
+        None
+    } else {
+        Some(files.iter().map(path_to_string).collect())
+    }
+}
+
+impl From<&BuildOutcome> for rpc::BuildOutcome {
+    fn from(outcome: &BuildOutcome) -> Self {
+        match outcome {
+            Ok(result) => rpc::BuildOutcome {
+                kind: rpc::BuildOutcomeKind::success,
+                gc_root: Some(path_to_string(result.output_paths.shell_gc_root.as_path())),
+                log_tail: None,
+            },
+            Err(failure) => {
+                let skip = failure.log_lines.len().saturating_sub(LOG_TAIL_LINES);
+                rpc::BuildOutcome {
+                    kind: rpc::BuildOutcomeKind::failure,
+                    gc_root: None,
+                    log_tail: Some(
+                        failure.log_lines[skip..]
+                            .iter()
+                            .map(|line| line.to_string_lossy().into_owned())
+                            .collect(),
+                    ),
+                }
+            }
+        }
+    }
 }
 
 impl std::convert::TryFrom<&NixFile> for rpc::ShellNix {
@@ -80,12 +740,30 @@ impl std::convert::TryFrom<&NixFile> for rpc::ShellNix {
 
     fn try_from(nix_file: &NixFile) -> Result<Self, Self::Error> {
         match nix_file {
-            NixFile::Shell(shell) => match shell.as_os_str().to_str() {
+            NixFile::Shell { file, args } => match file.as_os_str().to_str() {
                 Some(s) => Ok(rpc::ShellNix {
                     path: s.to_string(),
+                    flake_shell: None,
+                    attr: args.attr.clone(),
+                    args: string_map(&args.args),
+                    argstrs: string_map(&args.argstrs),
+                    env: None,
+                    cwd: None,
                 }),
                 None => Err("nix file path is not UTF-8 clean"),
             },
+            NixFile::Flake { dir, shell } => match dir.as_os_str().to_str() {
+                Some(s) => Ok(rpc::ShellNix {
+                    path: s.to_string(),
+                    flake_shell: Some(shell.clone()),
+                    attr: None,
+                    args: None,
+                    argstrs: None,
+                    env: None,
+                    cwd: None,
+                }),
+                None => Err("flake path is not UTF-8 clean"),
+            },
             NixFile::Services(_) => Err("need a shell nix file, not a services nix file"),
         }
     }
@@ -95,11 +773,311 @@ impl std::convert::TryFrom<rpc::ShellNix> for NixFile {
     type Error = String;
 
     fn try_from(shell_nix: rpc::ShellNix) -> Result<Self, Self::Error> {
+        let args = shell_args(&shell_nix);
         let path = PathBuf::from(shell_nix.path);
+        match shell_nix.flake_shell {
+            Some(shell) if path.is_absolute() && path.join("flake.nix").is_file() => {
+                Ok(NixFile::Flake { dir: path, shell })
+            }
+            Some(_) => Err(format!("flake {} does not exist", path.display())),
+            None if path.as_path().is_file() => Ok(NixFile::shell(path, args)),
+            None => Err(format!("nix file {} does not exist", path.display())),
+        }
+    }
+}
+
+/// How to get the shell environment out of the nix file of `shell_nix`.
+fn shell_args(shell_nix: &rpc::ShellNix) -> ShellArgs {
+    let map = |strings: &Option<varlink::StringHashMap<String>>| {
+        strings
+            .iter()
+            .flatten()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    };
+    ShellArgs {
+        attr: shell_nix.attr.clone(),
+        args: map(&shell_nix.args),
+        argstrs: map(&shell_nix.argstrs),
+    }
+}
+
+/// `strings` as a varlink map, or nothing if it is empty.
+fn string_map(
+    strings: &std::collections::BTreeMap<String, String>,
+) -> Option<varlink::StringHashMap<String>> {
+    if strings.is_empty() {
+        None
+    } else {
+        Some(strings.clone().into_iter().collect())
+    }
+}
+
+/// The environment to evaluate `shell_nix` in, if the client sent one.
+fn eval_env(shell_nix: &rpc::ShellNix) -> Result<Option<EvalEnv>, String> {
+    let vars = match &shell_nix.env {
+        Some(vars) => vars,
+        None => return Ok(None),
+    };
+    let cwd = match &shell_nix.cwd {
+        Some(cwd) if !std::path::Path::new(cwd).is_absolute() => {
+            return Err(String::from("cwd"));
+        }
+        cwd => cwd.as_ref().map(PathBuf::from),
+    };
+    Ok(Some(EvalEnv {
+        vars: vars
+            .iter()
+            .filter(|(name, _)| crate::nix::ENV_VARS.contains(&name.as_str()))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect(),
+        cwd,
+    }))
+}
+
+impl std::convert::TryFrom<&NixFile> for rpc::ServicesNix {
+    type Error = &'static str;
+
+    fn try_from(nix_file: &NixFile) -> Result<Self, Self::Error> {
+        match nix_file {
+            NixFile::Services(services) => match services.as_os_str().to_str() {
+                Some(s) => Ok(rpc::ServicesNix {
+                    path: s.to_string(),
+                }),
+                None => Err("nix file path is not UTF-8 clean"),
+            },
+            NixFile::Shell { .. } | NixFile::Flake { .. } => {
+                Err("need a services nix file, not a shell nix file")
+            }
+        }
+    }
+}
+
+impl std::convert::TryFrom<rpc::ServicesNix> for NixFile {
+    type Error = String;
+
+    fn try_from(services_nix: rpc::ServicesNix) -> Result<Self, Self::Error> {
+        let path = PathBuf::from(services_nix.path);
         if path.as_path().is_file() {
-            Ok(NixFile::Shell(path))
+            Ok(NixFile::Services(path))
         } else {
             Err(format!("nix file {} does not exist", path.display()))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_loop::BuildExitFailure;
+
+    /// A socket bound by someone else (like systemd with socket activation)
+    /// is served just like one the server binds itself.
+    #[test]
+    fn serve_passed_listener() -> std::io::Result<()> {
+        use crate::rpc::VarlinkClientInterface;
+
+        let tempdir = tempfile::tempdir()?;
+        let shell_nix = tempdir.path().join("shell.nix");
+        std::fs::File::create(&shell_nix)?;
+        let socket_path = SocketPath::from(&tempdir.path().join("socket"));
+
+        let lock = socket_path.lock().expect("failed to lock the socket");
+        let listener = UnixListener::bind(socket_path.path())?;
+        let (request_tx, request_rx) = chan::unbounded();
+        let server = Server {
+            request_tx,
+            listener,
+            bound_socket: false,
+            allowed_uids: vec![nix::unistd::geteuid().as_raw()],
+            _lock: lock,
+        };
+        thread::spawn(move || server.serve(chan::never()));
+
+        let connection = varlink::Connection::with_address(&socket_path.address())
+            .expect("failed to connect to the server");
+        rpc::VarlinkClient::new(connection)
+            .watch_shell(rpc::ShellNix {
+                path: path_to_string(&shell_nix),
+                flake_shell: None,
+                attr: None,
+                args: None,
+                argstrs: None,
+                env: None,
+                cwd: None,
+            })
+            .call()
+            .expect("failed to call WatchShell");
+
+        match request_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(Request::IndicateActivity(IndicateActivity { nix_file, env })) => {
+                assert_eq!(nix_file, NixFile::shell(shell_nix, ShellArgs::default()));
+                assert_eq!(env, None);
+            }
+            _ => panic!("expected the server to indicate activity"),
+        }
+        Ok(())
+    }
+
+    /// Clients of users which are not allowed get an error, then the
+    /// connection is closed.
+    #[test]
+    fn reject_other_users() -> std::io::Result<()> {
+        use crate::rpc::VarlinkClientInterface;
+
+        let tempdir = tempfile::tempdir()?;
+        let socket_path = SocketPath::from(&tempdir.path().join("socket"));
+        let lock = socket_path.lock().expect("failed to lock the socket");
+        let listener = UnixListener::bind(socket_path.path())?;
+        let (request_tx, request_rx) = chan::unbounded();
+        let server = Server {
+            request_tx,
+            listener,
+            bound_socket: false,
+            // not even our own user
+            allowed_uids: vec![],
+            _lock: lock,
+        };
+        thread::spawn(move || server.serve(chan::never()));
+
+        let connection = varlink::Connection::with_address(&socket_path.address())
+            .expect("failed to connect to the server");
+        let mut client = rpc::VarlinkClient::new(connection);
+        match client.list_projects().call() {
+            Err(e) => match e.kind() {
+                rpc::ErrorKind::PermissionDenied(Some(args)) => {
+                    assert_eq!(args.uid, i64::from(nix::unistd::geteuid().as_raw()))
+                }
+                other => panic!("expected PermissionDenied, got {}", other),
+            },
+            Ok(reply) => panic!("expected an error, got {:?}", reply),
+        }
+        assert!(
+            client.list_projects().call().is_err(),
+            "the connection was not closed"
+        );
+        assert!(request_rx.try_recv().is_err());
+        Ok(())
+    }
+
+    /// Streaming clients which hung up free their connection slot,
+    /// even if there is nothing to send to them.
+    #[test]
+    fn hung_up_streams_free_their_slot() -> std::io::Result<()> {
+        use crate::rpc::VarlinkClientInterface;
+
+        let tempdir = tempfile::tempdir()?;
+        let socket_path = SocketPath::from(&tempdir.path().join("socket"));
+        let lock = socket_path.lock().expect("failed to lock the socket");
+        let listener = UnixListener::bind(socket_path.path())?;
+        let (request_tx, request_rx) = chan::unbounded();
+        let server = Server {
+            request_tx,
+            listener,
+            bound_socket: false,
+            allowed_uids: vec![nix::unistd::geteuid().as_raw()],
+            _lock: lock,
+        };
+        thread::spawn(move || server.serve(chan::never()));
+        // a quiet daemon: it never sends a build event
+        thread::spawn(move || {
+            let mut event_txs = Vec::new();
+            for request in request_rx {
+                match request {
+                    Request::StreamEvents(StreamEvents { tx }) => event_txs.push(tx),
+                    Request::ListProjects(ListProjects { tx }) => {
+                        let _ = tx.send(vec![]);
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        let connect = || {
+            rpc::VarlinkClient::new(
+                varlink::Connection::with_address(&socket_path.address())
+                    .expect("failed to connect to the server"),
+            )
+        };
+        for _ in 0..MAX_CONNECTIONS {
+            let mut client = connect();
+            client
+                .stream_events()
+                .more()
+                .expect("failed to call StreamEvents");
+            // hangs up
+        }
+
+        let (done_tx, done_rx) = chan::bounded(1);
+        let mut client = connect();
+        thread::spawn(move || done_tx.send(client.list_projects().call().is_ok()));
+        assert_eq!(
+            done_rx.recv_timeout(HANGUP_POLL_INTERVAL * 10),
+            Ok(true),
+            "the client was not served after the streaming clients hung up"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn eval_env_keeps_nix_variables() {
+        let mut vars = varlink::StringHashMap::new();
+        vars.insert(String::from("NIX_PATH"), String::from("nixpkgs=./nixpkgs"));
+        vars.insert(String::from("LD_PRELOAD"), String::from("/evil.so"));
+        let mut shell_nix = rpc::ShellNix {
+            path: String::from("/project/shell.nix"),
+            flake_shell: None,
+            attr: None,
+            args: None,
+            argstrs: None,
+            env: Some(vars),
+            cwd: Some(String::from("/project")),
+        };
+        let env = eval_env(&shell_nix).unwrap().unwrap();
+        assert_eq!(
+            env.vars.into_iter().collect::<Vec<_>>(),
+            vec![(String::from("NIX_PATH"), String::from("nixpkgs=./nixpkgs"))]
+        );
+        assert_eq!(env.cwd, Some(PathBuf::from("/project")));
+
+        shell_nix.cwd = Some(String::from("project"));
+        assert!(eval_env(&shell_nix).is_err());
+        shell_nix.env = None;
+        assert_eq!(eval_env(&shell_nix), Ok(None));
+    }
+
+    #[test]
+    fn files_changed_event_to_rpc() {
+        let event = Event::Started {
+            nix_file: NixFile::shell(PathBuf::from("/project/shell.nix"), ShellArgs::default()),
+            reason: Reason::FilesChanged(vec![PathBuf::from("/project/default.nix")]),
+        };
+        assert_eq!(
+            serde_json::to_value(rpc::Event::from(&event)).unwrap(),
+            serde_json::json!({
+                "kind": "started",
+                "nix_file": "/project/shell.nix",
+                "reason": {
+                    "kind": "files_changed",
+                    "files": ["/project/default.nix"]
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn failure_outcome_contains_log_tail() {
+        let log_lines = (0..LOG_TAIL_LINES + 2)
+            .map(|i| format!("line {}", i).into())
+            .collect();
+        let outcome = rpc::BuildOutcome::from(&Err(BuildExitFailure { log_lines }));
+        assert_eq!(outcome.kind, rpc::BuildOutcomeKind::failure);
+        let tail = outcome.log_tail.expect("failure without log tail");
+        assert_eq!(tail.len(), LOG_TAIL_LINES);
+        assert_eq!(tail[0], "line 2");
+        assert_eq!(
+            tail.last().map(String::as_str),
+            Some(format!("line {}", LOG_TAIL_LINES + 1).as_str())
+        );
+    }
+}
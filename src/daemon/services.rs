@@ -0,0 +1,555 @@
+//! Run the services described by a services nix file.
+//!
+//! `logged-evaluation.nix` writes the evaluated services to a
+//! `services.json` file in the build output. The daemon reads that
+//! file after every successful build, sends the services to all
+//! subscribed clients and, as long as there are any, runs each service
+//! as a child process in the project environment.
+
+use crate::rpc;
+use crate::NixFile;
+use crossbeam_channel as chan;
+use slog_scope::{error, info, warn};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait before restarting a service that exited.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// How often to check whether a running service is still alive.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A service, as written to `services.json` by `logged-evaluation.nix`.
+#[derive(Deserialize, Debug)]
+struct ServiceDefinition {
+    name: String,
+    program: String,
+    args: Vec<String>,
+}
+
+impl From<ServiceDefinition> for rpc::Service {
+    fn from(def: ServiceDefinition) -> rpc::Service {
+        rpc::Service {
+            name: def.name,
+            command: rpc::Command {
+                program: def.program,
+                args: def.args,
+            },
+        }
+    }
+}
+
+/// Read the services from the `services.json` file in the build
+/// output `gc_root`.
+pub fn read(gc_root: &Path) -> std::io::Result<Vec<rpc::Service>> {
+    let file = std::fs::File::open(gc_root.join("services.json"))?;
+    let definitions: Vec<ServiceDefinition> = serde_json::from_reader(file)?;
+    Ok(definitions.into_iter().map(rpc::Service::from).collect())
+}
+
+/// The services of a single services nix file, together with all
+/// clients which want to know about them.
+///
+/// The services only run while at least one client is subscribed.
+pub struct Services {
+    supervisor: Supervisor,
+    /// The result of the latest evaluation and the build output it
+    /// was read from, if there was one yet.
+    current: Option<(Vec<rpc::Service>, PathBuf)>,
+    subscribers: Vec<Subscriber>,
+}
+
+/// A client which wants to know about the services.
+struct Subscriber {
+    tx: chan::Sender<Vec<rpc::Service>>,
+    /// Disconnected once the client hung up.
+    hangup: chan::Receiver<()>,
+}
+
+impl Subscriber {
+    fn hung_up(&self) -> bool {
+        match self.hangup.try_recv() {
+            Err(chan::TryRecvError::Disconnected) => true,
+            Ok(()) | Err(chan::TryRecvError::Empty) => false,
+        }
+    }
+}
+
+impl Services {
+    /// Services are run in the directory containing their nix file.
+    pub fn new(nix_file: &NixFile) -> Services {
+        let nix_file = PathBuf::from(nix_file);
+        let cwd = nix_file
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| PathBuf::from("/"));
+        Services {
+            supervisor: Supervisor::new(cwd),
+            current: None,
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// Send the services to `tx` whenever they are evaluated, until
+    /// the sender of `hangup` is dropped. If they have been evaluated
+    /// before, `tx` receives the latest result immediately.
+    pub fn subscribe(&mut self, tx: chan::Sender<Vec<rpc::Service>>, hangup: chan::Receiver<()>) {
+        if let Some((current, _)) = &self.current {
+            if tx.send(current.clone()).is_err() {
+                return;
+            }
+        }
+        self.subscribers.push(Subscriber { tx, hangup });
+        self.supervise();
+    }
+
+    /// Forget the clients which hung up. Once the last one is gone,
+    /// the services are stopped.
+    pub fn remove_hung_up_subscribers(&mut self) {
+        self.subscribers.retain(|subscriber| !subscriber.hung_up());
+        self.supervise();
+    }
+
+    /// Whether any client is (or, until the next update, might be)
+    /// interested in these services.
+    pub fn has_subscribers(&self) -> bool {
+        !self.subscribers.is_empty()
+    }
+
+    /// Restart the services whose definition changed in a new
+    /// evaluation result and notify the subscribers. `gc_root` is the
+    /// build output, which contains the project environment.
+    pub fn update(&mut self, services: Vec<rpc::Service>, gc_root: PathBuf) {
+        // subscribers which hung up are dropped
+        self.subscribers
+            .retain(|subscriber| subscriber.tx.send(services.clone()).is_ok());
+        self.current = Some((services, gc_root));
+        self.supervise();
+    }
+
+    /// Run the current services if anyone is subscribed, stop them otherwise.
+    fn supervise(&mut self) {
+        match &self.current {
+            Some((services, gc_root)) if self.has_subscribers() => {
+                self.supervisor.update(services, Some(gc_root))
+            }
+            _ => self.supervisor.stop(),
+        }
+    }
+}
+
+struct Running {
+    /// The definition the service was started with.
+    service: rpc::Service,
+    stop_tx: chan::Sender<()>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl Running {
+    fn stop(self) {
+        let Running {
+            service,
+            stop_tx,
+            handle,
+        } = self;
+        // the thread might already be gone, in which case there is nothing to stop
+        let _ = stop_tx.send(());
+        if handle.join().is_err() {
+            error!("service supervisor thread panicked"; "service" => &service.name);
+        }
+    }
+}
+
+/// Runs a set of services as child processes and restarts
+/// them when they exit.
+pub struct Supervisor {
+    /// Working directory of the services.
+    cwd: PathBuf,
+    /// The running services, by name.
+    running: HashMap<String, Running>,
+}
+
+impl Supervisor {
+    /// Create a supervisor which runs its services in `cwd`.
+    pub fn new(cwd: PathBuf) -> Supervisor {
+        Supervisor {
+            cwd,
+            running: HashMap::new(),
+        }
+    }
+
+    /// Run `services`: services which are not part of them anymore or
+    /// whose definition changed are stopped, new ones are started and
+    /// the others keep running.
+    ///
+    /// If `env_root` is given, the services run in the project
+    /// environment exported to `env_root/bash-export` by
+    /// `logged-evaluation.nix`, which is set up like `lorri direnv`
+    /// does it. Services which keep running get the new environment
+    /// the next time they are restarted.
+    pub fn update(&mut self, services: &[rpc::Service], env_root: Option<&Path>) {
+        let outdated: Vec<String> = self
+            .running
+            .iter()
+            .filter(|(_, running)| !services.contains(&running.service))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in outdated {
+            if let Some(running) = self.running.remove(&name) {
+                running.stop();
+            }
+        }
+
+        for service in services {
+            if let Some(running) = self.running.get(&service.name) {
+                if running.service != *service {
+                    warn!("ignoring a service with a duplicate name"; "service" => &service.name);
+                }
+                continue;
+            }
+            let (stop_tx, stop_rx) = chan::bounded(1);
+            let command = command(service, &self.cwd, env_root);
+            match thread::Builder::new()
+                .name(format!("service-{}", service.name))
+                .spawn({
+                    let name = service.name.clone();
+                    move || supervise(name, command, stop_rx)
+                }) {
+                Ok(handle) => {
+                    self.running.insert(
+                        service.name.clone(),
+                        Running {
+                            service: service.clone(),
+                            stop_tx,
+                            handle,
+                        },
+                    );
+                }
+                Err(e) => error!("failed to spawn service supervisor thread"; "error" => %e),
+            }
+        }
+    }
+
+    /// Stop all running services.
+    pub fn stop(&mut self) {
+        for (_, running) in self.running.drain() {
+            running.stop();
+        }
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.stop()
+    }
+}
+
+/// The command which runs `service` in `cwd`. If `env_root` is given,
+/// bash first sources the project environment in it (see `lorri direnv`),
+/// then replaces itself with the service.
+fn command(service: &rpc::Service, cwd: &Path, env_root: Option<&Path>) -> Command {
+    let mut cmd = match env_root {
+        None => Command::new(&service.command.program),
+        Some(env_root) => {
+            let mut cmd = Command::new("bash");
+            cmd.args(&[
+                "-c",
+                concat!(include_str!("../ops/direnv/envrc.bash"), "\nexec \"$@\"\n"),
+                "lorri-service",
+                &service.command.program,
+            ])
+            .env("EVALUATION_ROOT", env_root);
+            cmd
+        }
+    };
+    cmd.args(&service.command.args)
+        .current_dir(cwd)
+        .stdin(Stdio::null());
+    // so we can kill the service together with everything it started
+    crate::nix::own_process_group(&mut cmd);
+    cmd
+}
+
+/// Kill the process group of `child` (see `nix::own_process_group`).
+fn kill_process_group(child: &Child) {
+    let pgid = nix::unistd::Pid::from_raw(child.id() as i32);
+    // the whole group might have exited in the meantime, which is fine
+    let _ = nix::sys::signal::killpg(pgid, nix::sys::signal::Signal::SIGKILL);
+}
+
+/// Run the service `name` with `command` until a message arrives on
+/// `stop_rx` (or its sender is dropped), restarting it whenever it exits.
+fn supervise(name: String, mut command: Command, stop_rx: chan::Receiver<()>) {
+    // Waits for `duration`; returns true if the service should be stopped.
+    let stop_requested = |duration| match stop_rx.recv_timeout(duration) {
+        Err(chan::RecvTimeoutError::Timeout) => false,
+        Ok(()) | Err(chan::RecvTimeoutError::Disconnected) => true,
+    };
+
+    loop {
+        match command.spawn() {
+            Err(e) => error!("failed to start service"; "service" => &name, "error" => %e),
+            Ok(mut child) => {
+                info!("service started"; "service" => &name, "pid" => child.id());
+                loop {
+                    if stop_requested(POLL_INTERVAL) {
+                        kill_process_group(&child);
+                        let _ = child.wait();
+                        info!("service stopped"; "service" => &name);
+                        return;
+                    }
+                    match child.try_wait() {
+                        Ok(None) => {}
+                        Ok(Some(status)) => {
+                            warn!("service exited"; "service" => &name, "status" => %status);
+                            // don’t leave the processes it started running next to the restarted service
+                            kill_process_group(&child);
+                            break;
+                        }
+                        Err(e) => {
+                            error!("failed to check service status"; "service" => &name, "error" => %e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        if stop_requested(RESTART_DELAY) {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn read_services_json() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        std::fs::write(
+            tempdir.path().join("services.json"),
+            r#"[{"args":["--fast"],"name":"service1","program":"/bin/program1"}]"#,
+        )?;
+
+        assert_eq!(
+            read(tempdir.path())?,
+            vec![rpc::Service {
+                name: String::from("service1"),
+                command: rpc::Command {
+                    program: String::from("/bin/program1"),
+                    args: vec![String::from("--fast")],
+                },
+            }]
+        );
+        Ok(())
+    }
+
+    /// Services are restarted after they exit, until the supervisor is dropped.
+    #[test]
+    fn supervisor_restarts_services() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let mut supervisor = Supervisor::new(tempdir.path().to_path_buf());
+        supervisor.update(
+            &[rpc::Service {
+                name: String::from("counter"),
+                command: rpc::Command {
+                    program: String::from("sh"),
+                    args: vec![String::from("-c"), String::from("echo run >> runs")],
+                },
+            }],
+            None,
+        );
+
+        let runs = tempdir.path().join("runs");
+        let start = Instant::now();
+        loop {
+            let count = std::fs::read_to_string(&runs)
+                .map(|s| s.lines().count())
+                .unwrap_or(0);
+            if count >= 2 {
+                break;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "service was not restarted"
+            );
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        drop(supervisor);
+        Ok(())
+    }
+
+    fn sh(name: &str, script: &str) -> rpc::Service {
+        rpc::Service {
+            name: String::from(name),
+            command: rpc::Command {
+                program: String::from("sh"),
+                args: vec![String::from("-c"), String::from(script)],
+            },
+        }
+    }
+
+    /// Wait until `file` has `count` lines.
+    fn wait_for_lines(file: &Path, count: usize) -> String {
+        let start = Instant::now();
+        loop {
+            let content = std::fs::read_to_string(file).unwrap_or_default();
+            if content.lines().count() >= count {
+                return content;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "{} did not get {} lines",
+                file.display(),
+                count
+            );
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Whether the process `pid` is still running (and not just a zombie).
+    fn running(pid: &str) -> bool {
+        match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            // the state follows the parenthesized command name
+            Ok(stat) => stat
+                .rsplit(") ")
+                .next()
+                .map_or(false, |s| !s.starts_with('Z')),
+            Err(_) => false,
+        }
+    }
+
+    /// Stopping a service also stops the processes it started.
+    #[test]
+    fn supervisor_stops_forked_processes() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let mut supervisor = Supervisor::new(tempdir.path().to_path_buf());
+        supervisor.update(&[sh("forks", "sleep 600 & echo $! >> pids; wait")], None);
+        let pids = wait_for_lines(&tempdir.path().join("pids"), 1);
+        let pid = pids.trim();
+        assert!(running(pid), "the forked process did not start");
+
+        drop(supervisor);
+        let start = Instant::now();
+        while running(pid) {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "the forked process was not stopped"
+            );
+            thread::sleep(POLL_INTERVAL);
+        }
+        Ok(())
+    }
+
+    /// Only services whose definition changed are restarted.
+    #[test]
+    fn supervisor_restarts_changed_services() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let mut supervisor = Supervisor::new(tempdir.path().to_path_buf());
+        let unchanged = sh("unchanged", "echo run >> unchanged; exec sleep 600");
+        supervisor.update(
+            &[
+                unchanged.clone(),
+                sh("changed", "echo 1 >> changed; exec sleep 600"),
+            ],
+            None,
+        );
+        wait_for_lines(&tempdir.path().join("unchanged"), 1);
+        wait_for_lines(&tempdir.path().join("changed"), 1);
+
+        supervisor.update(
+            &[
+                unchanged,
+                sh("changed", "echo 2 >> changed; exec sleep 600"),
+            ],
+            None,
+        );
+        assert_eq!(wait_for_lines(&tempdir.path().join("changed"), 2), "1\n2\n");
+        assert_eq!(
+            std::fs::read_to_string(tempdir.path().join("unchanged"))?,
+            "run\n"
+        );
+
+        drop(supervisor);
+        Ok(())
+    }
+
+    /// Services see the environment exported by the project build.
+    #[test]
+    fn services_run_in_project_environment() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let env_root = tempdir.path().join("gc_root");
+        std::fs::create_dir(&env_root)?;
+        std::fs::write(
+            env_root.join("bash-export"),
+            "declare -x PROJECT_VAR=\"from project\"\n",
+        )?;
+        let mut supervisor = Supervisor::new(tempdir.path().to_path_buf());
+        supervisor.update(
+            &[sh("env", "echo \"$PROJECT_VAR\" >> env; exec sleep 600")],
+            Some(&env_root),
+        );
+
+        assert_eq!(
+            wait_for_lines(&tempdir.path().join("env"), 1),
+            "from project\n"
+        );
+        drop(supervisor);
+        Ok(())
+    }
+
+    /// The services stop when the last subscriber hangs up.
+    #[test]
+    fn services_stop_without_subscribers() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let mut services = Services::new(&NixFile::Services(tempdir.path().join("services.nix")));
+        let (tx, rx) = chan::unbounded();
+        let (hangup_tx, hangup_rx) = chan::bounded(0);
+        services.subscribe(tx, hangup_rx);
+        services.update(
+            vec![sh("counter", "echo run >> runs")],
+            tempdir.path().to_path_buf(),
+        );
+        assert_eq!(rx.recv().map(|s| s.len()), Ok(1));
+        wait_for_lines(&tempdir.path().join("runs"), 1);
+
+        drop(hangup_tx);
+        services.remove_hung_up_subscribers();
+        assert!(!services.has_subscribers());
+        let runs = std::fs::read_to_string(tempdir.path().join("runs"))?;
+        thread::sleep(RESTART_DELAY * 2);
+        assert_eq!(
+            std::fs::read_to_string(tempdir.path().join("runs"))?,
+            runs,
+            "the service was restarted after the last subscriber hung up"
+        );
+        Ok(())
+    }
+
+    /// Services are only started once a client subscribes.
+    #[test]
+    fn services_wait_for_a_subscriber() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let mut services = Services::new(&NixFile::Services(tempdir.path().join("services.nix")));
+        services.update(
+            vec![sh("counter", "echo run >> runs; exec sleep 600")],
+            tempdir.path().to_path_buf(),
+        );
+        thread::sleep(RESTART_DELAY);
+        assert!(!tempdir.path().join("runs").exists());
+
+        let (tx, rx) = chan::unbounded();
+        let (_hangup_tx, hangup_rx) = chan::bounded(0);
+        services.subscribe(tx, hangup_rx);
+        assert_eq!(rx.recv().map(|s| s.len()), Ok(1));
+        wait_for_lines(&tempdir.path().join("runs"), 1);
+        Ok(())
+    }
+}
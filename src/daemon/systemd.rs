@@ -0,0 +1,160 @@
+//! The parts of the systemd service protocol the daemon speaks:
+//! socket activation (`sd_listen_fds(3)`) and readiness, status and
+//! watchdog notifications (`sd_notify(3)`).
+//!
+//! Outside of systemd none of the environment variables are set
+//! and all functions in here are no-ops.
+
+use nix::sys::socket::{self, AddressFamily, MsgFlags, SockAddr, SockFlag, SockType, UnixAddr};
+use slog_scope::{debug, warn};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::time::Duration;
+
+/// The first file descriptor passed by systemd, see `sd_listen_fds(3)`.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Take the listening socket passed by systemd, if any.
+///
+/// Only the first passed socket is used. The `LISTEN_*` variables
+/// are removed from the environment, so that child processes
+/// (builds, services) don’t mistake the socket for theirs.
+pub fn take_listener() -> Option<UnixListener> {
+    let fds = passed_fds(
+        std::env::var("LISTEN_PID")
+            .ok()
+            .as_ref()
+            .map(String::as_str),
+        std::env::var("LISTEN_FDS")
+            .ok()
+            .as_ref()
+            .map(String::as_str),
+        std::process::id(),
+    );
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if fds == 0 {
+        return None;
+    }
+    if fds > 1 {
+        warn!("systemd passed more than one socket, only using the first"; "sockets" => fds);
+    }
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + fds as RawFd {
+        use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+        if let Err(e) = fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+            warn!("could not set close-on-exec on a passed socket"; "fd" => fd, "error" => %e);
+        }
+    }
+    // Safe, because systemd handed the file descriptor over to us
+    // and nothing else in this process uses it.
+    Some(unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) })
+}
+
+/// The number of file descriptors systemd passed to the process `pid`,
+/// given the values of `LISTEN_PID` and `LISTEN_FDS`.
+fn passed_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> usize {
+    // the variables might have been inherited from a parent process
+    match listen_pid.and_then(|p| p.parse::<u32>().ok()) {
+        Some(listen_pid) if listen_pid == pid => {}
+        _ => return 0,
+    }
+    listen_fds.and_then(|n| n.parse().ok()).unwrap_or(0)
+}
+
+/// The interval in which systemd expects `WATCHDOG=1` notifications,
+/// if the watchdog is enabled for this process.
+///
+/// Returns half the configured timeout, as recommended by `sd_watchdog_enabled(3)`.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|&usec| usec > 0)
+        .map(|usec| Duration::from_micros(usec / 2))
+}
+
+/// Send a state change notification to systemd, e.g. `READY=1`
+/// or `STATUS=...`. Does nothing if `NOTIFY_SOCKET` is not set.
+///
+/// Failing to notify is logged, but otherwise ignored.
+pub fn notify(state: &str) {
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    debug!("notifying systemd"; "state" => state);
+    if let Err(e) = send_notification(&path, state) {
+        warn!("could not notify systemd"; "state" => state, "error" => %e);
+    }
+}
+
+fn send_notification(path: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let bytes = path.as_bytes();
+    if bytes.first() == Some(&b'@') {
+        // an address in the abstract socket namespace, which std does not support
+        send_abstract(&bytes[1..], state).map_err(|e| match e {
+            nix::Error::Sys(errno) => std::io::Error::from_raw_os_error(errno as i32),
+            other => std::io::Error::new(std::io::ErrorKind::Other, other.to_string()),
+        })
+    } else {
+        UnixDatagram::unbound()?.send_to(state.as_bytes(), path)?;
+        Ok(())
+    }
+}
+
+fn send_abstract(name: &[u8], state: &str) -> nix::Result<()> {
+    let addr = UnixAddr::new_abstract(name)?;
+    let fd = socket::socket(
+        AddressFamily::Unix,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    let sent = socket::sendto(
+        fd,
+        state.as_bytes(),
+        &SockAddr::Unix(addr),
+        MsgFlags::empty(),
+    );
+    nix::unistd::close(fd)?;
+    sent.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passed_fds_only_for_our_pid() {
+        assert_eq!(passed_fds(Some("42"), Some("1"), 42), 1);
+        assert_eq!(passed_fds(Some("42"), Some("2"), 42), 2);
+        // inherited from our parent
+        assert_eq!(passed_fds(Some("41"), Some("1"), 42), 0);
+        assert_eq!(passed_fds(None, Some("1"), 42), 0);
+        assert_eq!(passed_fds(Some("42"), None, 42), 0);
+        assert_eq!(passed_fds(Some("42"), Some("x"), 42), 0);
+    }
+
+    /// A stand-in for systemd’s notification socket receives the messages.
+    #[test]
+    fn notification_is_sent() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let path = tempdir.path().join("notify");
+        let receiver = std::os::unix::net::UnixDatagram::bind(&path)?;
+
+        send_notification(path.as_os_str(), "READY=1").expect("failed to notify");
+
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf)?;
+        assert_eq!(&buf[..len], b"READY=1");
+        Ok(())
+    }
+}
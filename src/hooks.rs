@@ -0,0 +1,187 @@
+//! Run user-supplied commands on build events, see `cli::HookOptions`.
+//!
+//! Every hook is run with `sh -c` in the directory of the project's
+//! nix file. It gets the event as JSON on stdin (in the format of
+//! `lorri stream-events`) and in these environment variables:
+//!
+//! - `LORRI_EVENT`: `started`, `completed`, `failure` or `error`
+//! - `LORRI_NIX_FILE`: the absolute path of the project's nix file
+//! - `LORRI_GC_ROOT`: the GC root of the build result (`completed` only)
+//! - `LORRI_CHANGED_FILES`: the files which caused the build, one per line
+//!   (`started` only, if files changed)
+
+use crate::build_loop::Event;
+use crate::rpc;
+use crossbeam_channel as chan;
+use slog_scope::{debug, warn};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often to check whether a hook exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Runs the hooks in a thread of their own, so slow hooks
+/// never hold up the builds.
+pub struct Hooks {
+    tx: chan::Sender<rpc::Event>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl Hooks {
+    /// Start the thread which runs `commands` for every event passed
+    /// to `Hooks::run`, one after the other. Hooks which run for longer
+    /// than `timeout` are killed, together with their children.
+    pub fn start(commands: Vec<String>, timeout: Duration) -> Hooks {
+        let (tx, rx) = chan::unbounded::<rpc::Event>();
+        let handle = thread::spawn(move || {
+            for event in rx {
+                for command in &commands {
+                    run_hook(command, &event, timeout);
+                }
+            }
+        });
+        Hooks { tx, handle }
+    }
+
+    /// Queue the hooks for `event`. Returns right away.
+    /// `Event::Progress` is too frequent to run hooks for, it is ignored.
+    pub fn run(&self, event: &Event) {
+        if let Event::Progress { .. } = event {
+            return;
+        }
+        self.tx
+            .send(rpc::Event::from(event))
+            .expect("the hook thread died");
+    }
+
+    /// Wait until the hooks of all queued events have run.
+    pub fn finish(self) {
+        drop(self.tx);
+        if self.handle.join().is_err() {
+            warn!("hook thread panicked");
+        }
+    }
+}
+
+fn run_hook(command: &str, event: &rpc::Event, timeout: Duration) {
+    match run_with_timeout(command, event, timeout) {
+        Ok(Some(status)) if status.success() => {
+            debug!("hook succeeded"; "hook" => command, "event" => ?event.kind)
+        }
+        Ok(Some(status)) => {
+            warn!("hook failed"; "hook" => command, "event" => ?event.kind, "status" => %status)
+        }
+        Ok(None) => {
+            warn!("hook timed out and was killed"; "hook" => command, "event" => ?event.kind, "timeout" => ?timeout)
+        }
+        Err(e) => warn!("failed to run hook"; "hook" => command, "error" => %e),
+    }
+}
+
+/// Run `command` for `event`. Returns `None` if it did not exit within `timeout`.
+fn run_with_timeout(
+    command: &str,
+    event: &rpc::Event,
+    timeout: Duration,
+) -> std::io::Result<Option<ExitStatus>> {
+    let nix_file = Path::new(&event.nix_file);
+    let mut cmd = Command::new("sh");
+    cmd.args(&["-c", command])
+        .current_dir(nix_file.parent().unwrap_or_else(|| Path::new("/")))
+        .env("LORRI_EVENT", event_kind(event))
+        .env("LORRI_NIX_FILE", nix_file)
+        .stdin(Stdio::piped());
+    if let Some(gc_root) = &event.gc_root {
+        cmd.env("LORRI_GC_ROOT", gc_root);
+    }
+    if let Some(files) = event.reason.as_ref().and_then(|r| r.files.as_ref()) {
+        cmd.env("LORRI_CHANGED_FILES", files.join("\n"));
+    }
+    // so we can kill the hook together with everything it started
+    crate::nix::own_process_group(&mut cmd);
+
+    let json = serde_json::to_vec(event)?;
+    let mut child = cmd.spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    // The hook might not read its stdin, so writing could block
+    // once the pipe is full; it fails once the hook exited.
+    thread::spawn(move || {
+        let _ = stdin.write_all(&json);
+    });
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            let pgid = nix::unistd::Pid::from_raw(child.id() as i32);
+            // the hook might have exited in the meantime
+            let _ = nix::sys::signal::killpg(pgid, nix::sys::signal::Signal::SIGKILL);
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn event_kind(event: &rpc::Event) -> &'static str {
+    match event.kind {
+        rpc::EventKind::started => "started",
+        rpc::EventKind::progress => "progress",
+        rpc::EventKind::cancelled => "cancelled",
+        rpc::EventKind::completed => "completed",
+        rpc::EventKind::failure => "failure",
+        rpc::EventKind::error => "error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watch::Reason;
+    use crate::{NixFile, ShellArgs};
+
+    #[test]
+    fn hook_gets_event() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let nix_file = tempdir.path().join("shell.nix");
+        let changed = tempdir.path().join("default.nix");
+        let hooks = Hooks::start(
+            vec![String::from(
+                r#"echo "$LORRI_EVENT $LORRI_NIX_FILE $LORRI_CHANGED_FILES" > env; cat > stdin"#,
+            )],
+            Duration::from_secs(10),
+        );
+        hooks.run(&Event::Started {
+            nix_file: NixFile::shell(nix_file.clone(), ShellArgs::default()),
+            reason: Reason::FilesChanged(vec![changed.clone()]),
+        });
+        hooks.finish();
+
+        assert_eq!(
+            std::fs::read_to_string(tempdir.path().join("env"))?,
+            format!("started {} {}\n", nix_file.display(), changed.display())
+        );
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(tempdir.path().join("stdin"))?)?;
+        assert_eq!(json["kind"], "started");
+        assert_eq!(json["reason"]["files"][0], changed.to_str().unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn hook_is_killed_after_timeout() {
+        let hooks = Hooks::start(vec![String::from("sleep 60")], Duration::from_millis(100));
+        let started = Instant::now();
+        hooks.run(&Event::Started {
+            nix_file: NixFile::shell("/shell.nix".into(), ShellArgs::default()),
+            reason: Reason::PingReceived,
+        });
+        hooks.finish();
+        assert!(started.elapsed() < Duration::from_secs(30));
+    }
+}
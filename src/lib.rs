@@ -30,6 +30,7 @@ pub mod changelog;
 pub mod cli;
 pub mod constants;
 pub mod daemon;
+pub mod hooks;
 pub mod locate_file;
 pub mod logging;
 pub mod nix;
@@ -37,6 +38,7 @@ pub mod ops;
 pub mod osstrlines;
 pub mod pathreduction;
 pub mod project;
+pub mod scheduler;
 pub mod socket;
 pub mod thread;
 pub mod watch;
@@ -52,6 +54,7 @@ pub mod rpc {
     pub use super::com_target_lorri::*;
 }
 
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 
 // OUT_DIR and build_rev.rs are generated by cargo, see ../build.rs
@@ -59,22 +62,102 @@ include!(concat!(env!("OUT_DIR"), "/build_rev.rs"));
 
 /// A .nix file.
 #[derive(Hash, PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "SerializedNixFile", into = "SerializedNixFile")]
 pub enum NixFile {
     /// A .nix file which describes a shell environment
-    Shell(PathBuf),
+    Shell {
+        /// The .nix file
+        file: PathBuf,
+        /// How to get the shell environment out of it, if the file
+        /// describes more than one or has to be called with arguments
+        args: ShellArgs,
+    },
     /// A .nix file which describes a list of services
     Services(PathBuf),
+    /// A flake which describes a shell environment as
+    /// `devShells.<system>.<shell>`
+    Flake {
+        /// The directory containing `flake.nix`
+        dir: PathBuf,
+        /// The name of the development shell
+        shell: String,
+    },
+}
+
+/// How a `NixFile` is serialized, e.g. in the daemon's registry.
+/// Shells without arguments are written like before `ShellArgs` existed,
+/// so registries stay readable by both older and newer versions.
+#[derive(Serialize, Deserialize)]
+enum SerializedNixFile {
+    Shell(PathBuf),
+    Services(PathBuf),
+    ShellWithArgs { file: PathBuf, args: ShellArgs },
+    Flake { dir: PathBuf, shell: String },
+}
+
+impl From<SerializedNixFile> for NixFile {
+    fn from(nix_file: SerializedNixFile) -> NixFile {
+        match nix_file {
+            SerializedNixFile::Shell(file) => NixFile::shell(file, ShellArgs::default()),
+            SerializedNixFile::Services(file) => NixFile::Services(file),
+            SerializedNixFile::ShellWithArgs { file, args } => NixFile::Shell { file, args },
+            SerializedNixFile::Flake { dir, shell } => NixFile::Flake { dir, shell },
+        }
+    }
+}
+
+impl From<NixFile> for SerializedNixFile {
+    fn from(nix_file: NixFile) -> SerializedNixFile {
+        match nix_file {
+            NixFile::Shell { file, args } => {
+                if args.is_empty() {
+                    SerializedNixFile::Shell(file)
+                } else {
+                    SerializedNixFile::ShellWithArgs { file, args }
+                }
+            }
+            NixFile::Services(file) => SerializedNixFile::Services(file),
+            NixFile::Flake { dir, shell } => SerializedNixFile::Flake { dir, shell },
+        }
+    }
 }
 
 impl From<&NixFile> for PathBuf {
     fn from(p: &NixFile) -> PathBuf {
         match p {
-            NixFile::Shell(p) => p.to_path_buf(),
+            NixFile::Shell { file, .. } => file.to_path_buf(),
             NixFile::Services(p) => p.to_path_buf(),
+            NixFile::Flake { dir, .. } => dir.join("flake.nix"),
         }
     }
 }
 
+impl NixFile {
+    /// The shell environment described by `file` with `args`.
+    pub fn shell(file: PathBuf, args: ShellArgs) -> NixFile {
+        NixFile::Shell { file, args }
+    }
+}
+
+/// How to get a shell environment out of a .nix file, like the
+/// options of the same names of `nix-shell`.
+#[derive(Hash, PartialEq, Eq, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ShellArgs {
+    /// Select this attribute path (like `shells.docs`) of the file (`--attr`)
+    pub attr: Option<String>,
+    /// Call the file with these Nix expressions as arguments (`--arg`)
+    pub args: BTreeMap<String, String>,
+    /// Call the file with these strings as arguments (`--argstr`)
+    pub argstrs: BTreeMap<String, String>,
+}
+
+impl ShellArgs {
+    /// Whether the file is used as it is.
+    pub fn is_empty(&self) -> bool {
+        self.attr.is_none() && self.args.is_empty() && self.argstrs.is_empty()
+    }
+}
+
 impl slog::Value for NixFile {
     fn serialize(
         &self,
@@ -82,7 +165,12 @@ impl slog::Value for NixFile {
         key: slog::Key,
         serializer: &mut dyn slog::Serializer,
     ) -> slog::Result {
-        serializer.emit_arguments(key, &format_args!("{}", PathBuf::from(self).display()))
+        logging::emit_structured(
+            serializer,
+            key,
+            self,
+            &format_args!("{}", PathBuf::from(self).display()),
+        )
     }
 }
 
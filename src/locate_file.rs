@@ -2,7 +2,12 @@
 
 use std::env;
 use std::io;
-use std::path::PathBuf;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Files and directories which mark the root of a version-controlled
+/// repository; `upwards` does not look further up.
+const VCS_MARKERS: &[&str] = &[".git", ".hg", ".svn"];
 
 /// Error conditions encountered when hunting for a file on disk
 #[derive(Debug)]
@@ -32,12 +37,72 @@ pub fn in_cwd(name: &PathBuf) -> Result<PathBuf, FileLocationError> {
     }
 }
 
+/// Hunt for the first of `names` in `start`, then in its parents.
+/// The directories are searched in order, and in each directory the
+/// names are tried in order.
+///
+/// The search stops after the root of a repository (see `VCS_MARKERS`)
+/// and does not cross into another filesystem.
+pub fn upwards(start: &Path, names: &[PathBuf]) -> Result<PathBuf, FileLocationError> {
+    let mut dir = start.to_path_buf();
+    let device = dir.metadata()?.dev();
+    loop {
+        if let Some(path) = names
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|p| p.is_file())
+        {
+            return Ok(path);
+        }
+        if VCS_MARKERS.iter().any(|marker| dir.join(marker).exists()) {
+            return Err(FileLocationError::NotFound);
+        }
+        match dir.parent() {
+            Some(parent) if parent.metadata()?.dev() == device => dir = parent.to_path_buf(),
+            _ => return Err(FileLocationError::NotFound),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{in_cwd, FileLocationError};
+    use super::{in_cwd, upwards, FileLocationError};
     use std::path::Path;
     use std::path::PathBuf;
 
+    #[test]
+    fn upwards_finds_closest_candidate() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let repo = tmp.path().join("repo");
+        let sub = repo.join("a").join("b");
+        std::fs::create_dir_all(&sub)?;
+        std::fs::create_dir(repo.join(".git"))?;
+        // outside of the repository
+        std::fs::write(tmp.path().join("shell.nix"), "")?;
+        let names = [PathBuf::from("shell.nix"), PathBuf::from("default.nix")];
+
+        match upwards(&sub, &names) {
+            Err(FileLocationError::NotFound) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        std::fs::write(repo.join("shell.nix"), "")?;
+        assert_eq!(upwards(&sub, &names).unwrap(), repo.join("shell.nix"));
+
+        // a closer directory wins, even with a later candidate
+        std::fs::write(repo.join("a").join("default.nix"), "")?;
+        assert_eq!(
+            upwards(&sub, &names).unwrap(),
+            repo.join("a").join("default.nix")
+        );
+        std::fs::write(repo.join("a").join("shell.nix"), "")?;
+        assert_eq!(
+            upwards(&sub, &names).unwrap(),
+            repo.join("a").join("shell.nix")
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_locate_config_file() {
         let mut path = PathBuf::from("shell.nix");
@@ -1,18 +1,24 @@
 # The purpose of this function is as follows:
 # 1. It protects the output paths of its dependencies from being garbage collected.
-# 2. Given 'shellSrc', it generates a shell environment by capturing environment variables in $out/bash-export.
-# 3. Given 'servicesSrc', it generates a services.json file.
+# 2. Given 'shellSrc' or 'flakeSrc', it generates a shell environment by capturing environment variables in $out/bash-export.
+# 3. Given 'servicesSrc', it generates a services.json file. Together with
+#    'shellSrc', the services get the exported shell environment.
 { shellSrc ? null # Nix file describing a shell environment
+, shellAttr ? null # Attribute path of the shell environment in 'shellSrc', like "shells.docs"
+, shellArgs ? {} # Arguments 'shellSrc' is called with
+, shellArgStrs ? "{}" # More (string) arguments 'shellSrc' is called with, as a JSON object
 , servicesSrc ? null # Nix file containing a list of services
+, flakeSrc ? null # Directory of a flake describing a shell environment
+, flakeShell ? "default" # Name of the shell in the flake's `devShells.<system>`
 , runtimeClosure
 }:
-assert shellSrc != null || servicesSrc != null;
+assert shellSrc != null || servicesSrc != null || flakeSrc != null;
 let
   runtimeCfg = import runtimeClosure;
 
   # using scopedImport, replace readDir and readFile with
   # implementations which will log files and paths they see.
-  logged = src:
+  logged = src: args:
     let
       overrides = {
         import = scopedImport overrides;
@@ -24,10 +30,19 @@ let
       };
       raw = overrides.scopedImport overrides src;
     in
+      # like nix-shell, only pass the arguments the function takes
       if (builtins.isFunction raw)
-      then raw {}
+      then raw (builtins.intersectAttrs (builtins.functionArgs raw) args)
       else raw;
 
+  # like `nix-build --attr`
+  selectAttr = attrPath: value:
+    if attrPath == null then value
+    else builtins.foldl'
+      (set: name: set.${name})
+      value
+      (builtins.filter builtins.isString (builtins.split "\\." attrPath));
+
   # If you add a .drv to a gc-root, the `.drv` itself is protected
   # from GC, and the parent `drv`s up the tree are also protected.
   # However, the output paths referenced in any of the drvs are NOT
@@ -155,7 +170,14 @@ let
     }
   );
 
-  shell = if shellSrc == null then {} else logged shellSrc;
-  services = if servicesSrc == null then [] else logged servicesSrc;
+  # nix reads the files of a flake itself, so they are not logged
+  flakeShellOf = src:
+    (builtins.getFlake (toString src)).devShells.${builtins.currentSystem}.${flakeShell};
+
+  shell =
+    if flakeSrc != null then flakeShellOf flakeSrc
+    else if shellSrc == null then {}
+    else selectAttr shellAttr (logged shellSrc (shellArgs // builtins.fromJSON shellArgStrs));
+  services = if servicesSrc == null then [] else logged servicesSrc {};
 in
 wrapped-project shell services
@@ -2,25 +2,260 @@
 
 use crate::cli::Command;
 use slog::Drain;
+use std::cell::RefCell;
+use std::io::Write;
+use std::sync::Mutex;
+
+/// How log records are printed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable text
+    Human,
+    /// One JSON object per record, with all key-value pairs as fields
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(LogFormat::Human),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!(
+                "unknown log format `{}`, expected `human` or `json`",
+                other
+            )),
+        }
+    }
+}
 
 /// Instantiate a root logger appropriate for the subcommand
-pub fn root(verbosity: u8, command: &Command) -> slog::Logger {
+pub fn root(verbosity: u8, format: LogFormat, command: &Command) -> slog::Logger {
     let level = match verbosity {
         0 => slog::Level::Info,
         _ => slog::Level::Debug,
     };
-    let decorator = match command {
+    let to_stderr = match command {
         // direnv swallows stdout, so we must log to stderr
-        Command::Direnv(_) => slog_term::TermDecorator::new().stderr().build(),
-        _ => slog_term::TermDecorator::new().stdout().build(),
+        Command::Direnv(_) => true,
+        // stdout is reserved for the event stream
+        Command::StreamEvents => true,
+        _ => false,
     };
-    let drain = slog_term::FullFormat::new(decorator)
-        .build()
-        .filter_level(level)
-        .fuse();
-    let drain = slog_async::Async::new(drain)
-        .overflow_strategy(slog_async::OverflowStrategy::Block)
-        .build()
-        .fuse();
-    slog::Logger::root(drain, slog::o!("lorri_version" => crate::VERSION_BUILD_REV))
+    let values = slog::o!("lorri_version" => crate::VERSION_BUILD_REV);
+    match format {
+        LogFormat::Human => {
+            let decorator = if to_stderr {
+                slog_term::TermDecorator::new().stderr().build()
+            } else {
+                slog_term::TermDecorator::new().stdout().build()
+            };
+            let drain = slog_term::FullFormat::new(decorator)
+                .build()
+                .filter_level(level)
+                .fuse();
+            let drain = slog_async::Async::new(drain)
+                .overflow_strategy(slog_async::OverflowStrategy::Block)
+                .build()
+                .fuse();
+            slog::Logger::root(drain, values)
+        }
+        // Not asynchronous: `slog_async` would turn structured values into strings.
+        LogFormat::Json => {
+            let out: Box<dyn Write + Send> = if to_stderr {
+                Box::new(std::io::stderr())
+            } else {
+                Box::new(std::io::stdout())
+            };
+            let drain = JsonDrain {
+                out: Mutex::new(out),
+            }
+            .filter_level(level)
+            .fuse();
+            slog::Logger::root(drain, values)
+        }
+    }
+}
+
+/// Writes each record as a single line of JSON.
+struct JsonDrain<W: Write> {
+    out: Mutex<W>,
+}
+
+impl<W: Write> Drain for JsonDrain<W> {
+    type Ok = ();
+    type Err = std::io::Error;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        let mut object = serde_json::Map::new();
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs() as f64 + f64::from(d.subsec_millis()) / 1000.0)
+            .unwrap_or(0.0);
+        object.insert("ts".into(), timestamp.into());
+        object.insert("level".into(), record.level().as_str().into());
+        object.insert("msg".into(), record.msg().to_string().into());
+        {
+            let mut serializer = JsonSerializer(&mut object);
+            slog::KV::serialize(values, record, &mut serializer)?;
+            slog::KV::serialize(&record.kv(), record, &mut serializer)?;
+        }
+
+        let mut out = self.out.lock().expect("log output mutex poisoned");
+        serde_json::to_writer(&mut *out, &object)?;
+        writeln!(out)?;
+        out.flush()
+    }
+}
+
+thread_local! {
+    /// The structured value `emit_structured` is passing to a serializer.
+    static STRUCTURED: RefCell<Option<serde_json::Value>> = RefCell::new(None);
+}
+
+/// Emit `value` as a JSON value for the JSON log format,
+/// and as `text` for all others.
+///
+/// Use this to implement `slog::Value` for types with a useful
+/// structure; see `Structured` for a ready-made wrapper.
+pub fn emit_structured<T: serde::Serialize>(
+    serializer: &mut dyn slog::Serializer,
+    key: slog::Key,
+    value: &T,
+    text: &std::fmt::Arguments,
+) -> slog::Result {
+    // `slog::Serializer` has no method for nested values, so we hand the value
+    // to `JsonSerializer::emit_arguments` on the side.
+    let value = serde_json::to_value(value).map_err(|_| slog::Error::Other)?;
+    STRUCTURED.with(|structured| *structured.borrow_mut() = Some(value));
+    let result = serializer.emit_arguments(key, text);
+    STRUCTURED.with(|structured| structured.borrow_mut().take());
+    result
+}
+
+/// A log value which the JSON log format keeps structured.
+/// Other formats print it as JSON text.
+pub struct Structured<T>(pub T);
+
+impl<T: serde::Serialize> slog::Value for Structured<T> {
+    fn serialize(
+        &self,
+        _record: &slog::Record,
+        key: slog::Key,
+        serializer: &mut dyn slog::Serializer,
+    ) -> slog::Result {
+        let text = serde_json::to_string(&self.0).map_err(|_| slog::Error::Other)?;
+        emit_structured(serializer, key, &self.0, &format_args!("{}", text))
+    }
+}
+
+/// Collects the key-value pairs of a record into a JSON object.
+struct JsonSerializer<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'a> JsonSerializer<'a> {
+    fn insert<V: Into<serde_json::Value>>(&mut self, key: slog::Key, value: V) -> slog::Result {
+        self.0.insert(key.to_string(), value.into());
+        Ok(())
+    }
+}
+
+impl<'a> slog::Serializer for JsonSerializer<'a> {
+    fn emit_arguments(&mut self, key: slog::Key, val: &std::fmt::Arguments) -> slog::Result {
+        let value = STRUCTURED
+            .with(|structured| structured.borrow_mut().take())
+            .unwrap_or_else(|| val.to_string().into());
+        self.insert(key, value)
+    }
+
+    fn emit_str(&mut self, key: slog::Key, val: &str) -> slog::Result {
+        self.insert(key, val)
+    }
+
+    fn emit_bool(&mut self, key: slog::Key, val: bool) -> slog::Result {
+        self.insert(key, val)
+    }
+
+    fn emit_unit(&mut self, key: slog::Key) -> slog::Result {
+        self.insert(key, serde_json::Value::Null)
+    }
+
+    fn emit_none(&mut self, key: slog::Key) -> slog::Result {
+        self.insert(key, serde_json::Value::Null)
+    }
+
+    fn emit_u64(&mut self, key: slog::Key, val: u64) -> slog::Result {
+        self.insert(key, val)
+    }
+
+    fn emit_i64(&mut self, key: slog::Key, val: i64) -> slog::Result {
+        self.insert(key, val)
+    }
+
+    fn emit_u32(&mut self, key: slog::Key, val: u32) -> slog::Result {
+        self.insert(key, val)
+    }
+
+    fn emit_i32(&mut self, key: slog::Key, val: i32) -> slog::Result {
+        self.insert(key, val)
+    }
+
+    fn emit_usize(&mut self, key: slog::Key, val: usize) -> slog::Result {
+        self.insert(key, val as u64)
+    }
+
+    fn emit_isize(&mut self, key: slog::Key, val: isize) -> slog::Result {
+        self.insert(key, val as i64)
+    }
+
+    fn emit_f64(&mut self, key: slog::Key, val: f64) -> slog::Result {
+        self.insert(key, val)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::o;
+
+    /// A `Write` which can be inspected after the logger took ownership.
+    #[derive(Clone)]
+    struct Buffer(std::sync::Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_record_keeps_structure() {
+        let buffer = Buffer(Default::default());
+        let drain = JsonDrain {
+            out: Mutex::new(buffer.clone()),
+        };
+        let log = slog::Logger::root(drain.fuse(), o!("version" => 3));
+        let nix_file =
+            crate::NixFile::shell("/project/shell.nix".into(), crate::ShellArgs::default());
+        slog::info!(log, "hello"; "nix_file" => &nix_file, "list" => Structured(vec![1, 2]), "text" => "yes");
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let record: serde_json::Value = serde_json::from_str(output.trim()).unwrap();
+        assert_eq!(record["msg"], "hello");
+        assert_eq!(record["level"], "INFO");
+        assert_eq!(record["version"], 3);
+        assert_eq!(record["text"], "yes");
+        assert_eq!(record["list"], serde_json::json!([1, 2]));
+        assert_eq!(
+            record["nix_file"],
+            serde_json::json!({ "Shell": "/project/shell.nix" })
+        );
+    }
 }
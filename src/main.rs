@@ -3,12 +3,15 @@ extern crate structopt;
 #[macro_use]
 extern crate human_panic;
 
-use lorri::cli::{Arguments, Command};
+use lorri::cli::{Arguments, Command, ForgetOptions, ShellFileOptions};
 use lorri::constants;
-use lorri::locate_file;
+use lorri::locate_file::{self, FileLocationError};
 use lorri::logging;
 use lorri::ops::error::{ExitError, OpResult};
-use lorri::ops::{daemon, direnv, info, init, ping, upgrade, watch};
+use lorri::ops::{
+    daemon, direnv, forget, info, init, ping, rebuild, services, status, stream_events, upgrade,
+    wait, watch,
+};
 use lorri::project::Project;
 use lorri::NixFile;
 use slog::{debug, error, o};
@@ -29,7 +32,7 @@ fn main() {
         // This logger is asynchronous. It is guaranteed to be flushed upon destruction. By tying
         // its lifetime to this smaller scope, we ensure that it is destroyed before
         // 'std::process::exit' gets called.
-        let log = logging::root(opts.verbosity, &opts.command);
+        let log = logging::root(opts.verbosity, opts.log_format, &opts.command);
         debug!(log, "input options"; "options" => ?opts);
 
         match run_command(log.clone(), opts) {
@@ -48,20 +51,122 @@ fn main() {
     std::process::exit(exit_code);
 }
 
-/// Try to read `shell.nix` from the current working dir.
-fn get_shell_nix(shellfile: &PathBuf) -> Result<NixFile, ExitError> {
-    // use shell.nix from cwd
-    Ok(NixFile::Shell(locate_file::in_cwd(&shellfile).map_err(
-        |_| {
-            ExitError::user_error(format!(
-                "`{}` does not exist\n\
-                 You can use the following minimal `shell.nix` to get started:\n\n\
-                 {}",
-                shellfile.display(),
-                TRIVIAL_SHELL_SRC
+/// Look for the nix file of the project in the current working dir and its parents.
+fn find_shell_nix(opts: &ShellFileOptions) -> Result<NixFile, ExitError> {
+    locate_shell_nix(opts)?.ok_or_else(|| {
+        ExitError::user_error(format!(
+            "none of {} exists in this directory or its parents (up to the repository root)\n\
+             You can use the following minimal `shell.nix` to get started:\n\n\
+             {}",
+            opts.candidates()
+                .iter()
+                .map(|c| format!("`{}`", c.display()))
+                .collect::<Vec<_>>()
+                .join(", "),
+            TRIVIAL_SHELL_SRC
+        ))
+    })
+}
+
+/// The nix file `shellfile`, relative to the current working dir.
+fn get_shell_nix(shellfile: &PathBuf, opts: &ShellFileOptions) -> Result<NixFile, ExitError> {
+    match locate_file::in_cwd(shellfile) {
+        Ok(shell_nix) => Ok(NixFile::shell(shell_nix, opts.shell_args())),
+        Err(FileLocationError::NotFound) => Err(ExitError::user_error(format!(
+            "`{}` does not exist\n\
+             You can use the following minimal `shell.nix` to get started:\n\n\
+             {}",
+            shellfile.display(),
+            TRIVIAL_SHELL_SRC
+        ))),
+        Err(FileLocationError::Io(e)) => Err(ExitError::temporary(format!(
+            "cannot read the current directory: {}",
+            e
+        ))),
+    }
+}
+
+/// Like `find_shell_nix`, but `None` if no candidate exists.
+fn locate_shell_nix(opts: &ShellFileOptions) -> Result<Option<NixFile>, ExitError> {
+    let cwd = current_dir()?;
+    match locate_file::upwards(&cwd, &opts.candidates()) {
+        Ok(shell_nix) => Ok(Some(NixFile::shell(shell_nix, opts.shell_args()))),
+        Err(FileLocationError::NotFound) => Ok(None),
+        Err(FileLocationError::Io(e)) => Err(ExitError::temporary(format!(
+            "cannot look for the nix file in {} and its parents: {}",
+            cwd.display(),
+            e
+        ))),
+    }
+}
+
+/// The project `lorri forget` should forget. Its nix file might have been
+/// deleted, so unlike other commands this does not require it to exist.
+fn forgotten_project(opts: &ForgetOptions) -> Result<forget::Forget, ExitError> {
+    if let Some(hash) = &opts.hash {
+        return Ok(forget::Forget::Hash(hash.clone()));
+    }
+    let nix_file = match &opts.flake_shell {
+        Some(_) if !opts.shell_file.shell_args().is_empty() => {
+            return Err(ExitError::user_error(
+                "--attr, --arg and --argstr can’t be used with --flake",
             ))
+        }
+        Some(shell) => NixFile::Flake {
+            dir: current_dir()?,
+            shell: shell.clone(),
+        },
+        None => match locate_shell_nix(&opts.shell_file)? {
+            Some(nix_file) => nix_file,
+            None => NixFile::shell(
+                current_dir()?.join(&opts.shell_file.candidates()[0]),
+                opts.shell_file.shell_args(),
+            ),
         },
-    )?))
+    };
+    Ok(forget::Forget::NixFile(nix_file))
+}
+
+/// The current working dir.
+fn current_dir() -> Result<PathBuf, ExitError> {
+    std::env::current_dir()
+        .map_err(|e| ExitError::temporary(format!("cannot read the current directory: {}", e)))
+}
+
+/// Split a flake reference like `.#name` into the flake's directory
+/// and the name of the shell, which defaults to `default`.
+fn parse_flake_ref(flake: &str) -> (&str, &str) {
+    let (dir, shell) = match flake.rfind('#') {
+        Some(i) => (&flake[..i], &flake[i + 1..]),
+        None => (flake, ""),
+    };
+    (
+        if dir.is_empty() { "." } else { dir },
+        if shell.is_empty() { "default" } else { shell },
+    )
+}
+
+/// Try to find the flake `flake` (see `parse_flake_ref`), relative to the current working dir.
+fn get_flake(flake: &str) -> Result<NixFile, ExitError> {
+    let (dir, shell) = parse_flake_ref(flake);
+    let dir = std::env::current_dir()
+        .and_then(|cwd| cwd.join(dir).canonicalize())
+        .ok()
+        .filter(|dir| dir.join("flake.nix").is_file())
+        .ok_or_else(|| ExitError::user_error(format!("`{}/flake.nix` does not exist", dir)))?;
+    Ok(NixFile::Flake {
+        dir,
+        shell: shell.to_string(),
+    })
+}
+
+/// Try to read a services nix file from the current working dir.
+fn get_services_nix(servicesfile: &PathBuf) -> Result<NixFile, ExitError> {
+    Ok(NixFile::Services(
+        locate_file::in_cwd(&servicesfile).map_err(|_| {
+            ExitError::user_error(format!("`{}` does not exist", servicesfile.display()))
+        })?,
+    ))
 }
 
 fn create_project(paths: &constants::Paths, shell_nix: NixFile) -> Result<Project, ExitError> {
@@ -81,27 +186,61 @@ fn run_command(log: slog::Logger, opts: Arguments) -> OpResult {
     // one of them so the logger gets set up correctly.
     let without_project = || slog_scope::set_global_logger(log.clone());
     let with_project = |nix_file| -> std::result::Result<(Project, GlobalLoggerGuard), ExitError> {
-        let project = create_project(&lorri::ops::get_paths()?, get_shell_nix(nix_file)?)?;
+        let project = create_project(&lorri::ops::get_paths()?, nix_file)?;
         let guard = slog_scope::set_global_logger(log.new(o!("root" => project.nix_file.clone())));
         Ok((project, guard))
     };
 
     match opts.command {
         Command::Info(opts) => {
-            let (_project, _guard) = with_project(&opts.nix_file)?;
-            info::main(opts.nix_file)
+            let (project, _guard) = with_project(find_shell_nix(&opts.shell_file)?)?;
+            info::main(&project, &opts.shell_file.candidates())
         }
         Command::Direnv(opts) => {
-            let (project, _guard) = with_project(&opts.nix_file)?;
+            let nix_file = match &opts.flake {
+                Some(_) if !opts.shell_file.shell_args().is_empty() => {
+                    return Err(ExitError::user_error(
+                        "--attr, --arg and --argstr can’t be used with --flake",
+                    ))
+                }
+                Some(flake) => get_flake(flake)?,
+                None => find_shell_nix(&opts.shell_file)?,
+            };
+            let (project, _guard) = with_project(nix_file)?;
             direnv::main(project, /* shell_output */ std::io::stdout())
         }
         Command::Watch(opts) => {
-            let (project, _guard) = with_project(&opts.nix_file)?;
+            let (project, _guard) = with_project(find_shell_nix(&opts.shell_file)?)?;
             watch::main(project, opts)
         }
-        Command::Daemon => {
+        Command::Daemon(opts) => {
+            let _guard = without_project();
+            daemon::main(opts)
+        }
+        Command::Services(opts) => {
+            let _guard = without_project();
+            get_services_nix(&opts.nix_file).and_then(services::main)
+        }
+        Command::Status(opts) => {
+            let _guard = without_project();
+            status::main(opts)
+        }
+        Command::Forget(opts) => {
             let _guard = without_project();
-            daemon::main()
+            forgotten_project(&opts).and_then(|project| forget::main(project, opts.delete_gc_roots))
+        }
+        Command::Rebuild(opts) => {
+            let _guard = without_project();
+            find_shell_nix(&opts.shell_file)
+                .and_then(|nix_file| rebuild::main(nix_file, opts.refresh))
+        }
+        Command::Wait(opts) => {
+            let _guard = without_project();
+            find_shell_nix(&opts.shell_file).and_then(|nix_file| wait::main(nix_file, opts.timeout))
+        }
+        Command::StreamEvents => {
+            let _guard = without_project();
+            stream_events::main()
         }
         Command::Upgrade(opts) => {
             let _guard = without_project();
@@ -110,7 +249,11 @@ fn run_command(log: slog::Logger, opts: Arguments) -> OpResult {
         // TODO: remove
         Command::Ping_(opts) => {
             let _guard = without_project();
-            get_shell_nix(&opts.nix_file).and_then(ping::main)
+            match &opts.nix_file {
+                Some(nix_file) => get_shell_nix(nix_file, &opts.shell_file),
+                None => find_shell_nix(&opts.shell_file),
+            }
+            .and_then(ping::main)
         }
         Command::Init => {
             let _guard = without_project();
@@ -165,4 +308,11 @@ mod tests {
         //     Err(nix::InstantiateError::Io(io)) => Err(io)
         // }
     }
+
+    #[test]
+    fn flake_ref_defaults() {
+        assert_eq!(parse_flake_ref(".#dev"), (".", "dev"));
+        assert_eq!(parse_flake_ref("../project"), ("../project", "default"));
+        assert_eq!(parse_flake_ref("#"), (".", "default"));
+    }
 }
@@ -32,16 +32,19 @@
 //! }
 //! ```
 
+pub mod log;
+
 use crate::osstrlines;
 use crossbeam_channel as chan;
 use serde_json;
 use slog_scope::debug;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::{OsStr, OsString};
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
-use std::process::{ChildStderr, ChildStdout, Command, ExitStatus, Stdio};
+use std::process::{Child, ChildStderr, ChildStdout, Command, ExitStatus, Stdio};
 use std::thread;
+use std::time::Duration;
 use vec1::Vec1;
 
 /// Execute Nix commands using a builder-pattern abstraction.
@@ -51,6 +54,61 @@ pub struct CallOpts<'a> {
     attribute: Option<String>,
     argstrs: HashMap<String, String>,
     stderr_line_tx: Option<chan::Sender<OsString>>,
+    log_event_tx: Option<chan::Sender<log::LogEvent>>,
+    cancel_rx: Option<chan::Receiver<()>>,
+    env: Option<EvalEnv>,
+}
+
+/// Environment variables which influence how nix evaluates an expression.
+pub const ENV_VARS: &[&str] = &[
+    "NIX_PATH",
+    "NIX_CONFIG",
+    "NIXPKGS_CONFIG",
+    "NIXPKGS_ALLOW_UNFREE",
+    "NIXPKGS_ALLOW_INSECURE",
+    "NIXPKGS_ALLOW_BROKEN",
+    "NIXPKGS_ALLOW_UNSUPPORTED_SYSTEM",
+];
+
+/// The environment to run nix in, like that of the user who asked
+/// for a build. Without one, nix runs in lorri’s own environment.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EvalEnv {
+    /// The values of the `ENV_VARS`; those which are missing are unset.
+    /// Other variables are ignored.
+    pub vars: BTreeMap<String, String>,
+    /// The working directory, which relative paths in `NIX_PATH` refer to.
+    pub cwd: Option<PathBuf>,
+}
+
+impl EvalEnv {
+    /// The `ENV_VARS` and working directory of this process.
+    pub fn current() -> EvalEnv {
+        EvalEnv {
+            vars: ENV_VARS
+                .iter()
+                .filter_map(|name| {
+                    std::env::var(name)
+                        .ok()
+                        .map(|value| (name.to_string(), value))
+                })
+                .collect(),
+            cwd: std::env::current_dir().ok(),
+        }
+    }
+
+    /// Make `cmd` run in this environment.
+    pub fn apply(&self, cmd: &mut Command) {
+        for name in ENV_VARS {
+            match self.vars.get(*name) {
+                Some(value) => cmd.env(name, value),
+                None => cmd.env_remove(name),
+            };
+        }
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(cwd);
+        }
+    }
 }
 
 /// Which input to give nix.
@@ -108,6 +166,9 @@ impl<'a> CallOpts<'a> {
             attribute: None,
             argstrs: HashMap::new(),
             stderr_line_tx: None,
+            log_event_tx: None,
+            cancel_rx: None,
+            env: None,
         }
     }
 
@@ -118,6 +179,9 @@ impl<'a> CallOpts<'a> {
             attribute: None,
             argstrs: HashMap::new(),
             stderr_line_tx: None,
+            log_event_tx: None,
+            cancel_rx: None,
+            env: None,
         }
     }
 
@@ -145,6 +209,44 @@ impl<'a> CallOpts<'a> {
         self
     }
 
+    /// Provide a Sender half of a channel, where Nix will send the events
+    /// it logs in the internal-json format (see `log::LogEvent`), like the
+    /// start of a build. Nothing is sent if nix does not support the format.
+    pub fn set_log_event_sender(&mut self, sender: chan::Sender<log::LogEvent>) -> &mut Self {
+        self.log_event_tx = Some(sender);
+        self
+    }
+
+    /// Provide a Receiver half of a channel, which cancels the Nix
+    /// call as soon as it receives a message. The Nix process and all
+    /// its children are killed, and the call returns a `Cancelled` error.
+    ///
+    /// ```rust
+    /// extern crate lorri;
+    /// use crossbeam_channel as chan;
+    /// use lorri::nix;
+    ///
+    /// let (cancel_tx, cancel_rx) = chan::bounded(1);
+    /// cancel_tx.send(()).unwrap();
+    /// let output: Result<u8, _> = nix::CallOpts::expression("5")
+    ///     .set_cancel_receiver(cancel_rx)
+    ///     .value();
+    /// match output {
+    ///     Err(nix::EvaluationError::Cancelled) => {}
+    ///     otherwise => panic!(otherwise),
+    /// }
+    /// ```
+    pub fn set_cancel_receiver(&mut self, cancel_rx: chan::Receiver<()>) -> &mut Self {
+        self.cancel_rx = Some(cancel_rx);
+        self
+    }
+
+    /// Run nix in `env` instead of lorri’s own environment.
+    pub fn set_env(&mut self, env: EvalEnv) -> &mut Self {
+        self.env = Some(env);
+        self
+    }
+
     /// Evaluate a sub attribute of the expression. Only supports one:
     /// calling attribute() multiple times is supported, but overwrites
     /// the previous attribute.
@@ -230,6 +332,7 @@ impl<'a> CallOpts<'a> {
     {
         let mut cmd = Command::new("nix-instantiate");
         cmd.args(&["--eval", "--json", "--strict"]);
+        cmd.args(log::log_format_args());
 
         cmd.args(self.command_arguments());
 
@@ -345,6 +448,7 @@ impl<'a> CallOpts<'a> {
             OsStr::new("--out-link"),
             gc_root_dir.path().join(Path::new("result")).as_os_str(),
         ]);
+        cmd.args(log::log_format_args());
 
         cmd.args(self.command_arguments());
 
@@ -369,7 +473,8 @@ impl<'a> CallOpts<'a> {
     }
 
     /// Execute a command (presumably a Nix command :)). stderr output
-    /// is passed line-based to the CallOpts' stderr_line_tx receiver.
+    /// is passed line-based to the CallOpts' stderr_line_tx receiver,
+    /// in nix's human-readable format (see `log::text_lines`).
     /// Stdout is passed as a BufReader to `stdout_fn`.
     fn execute<T: 'static, S: 'static>(
         &self,
@@ -382,6 +487,12 @@ impl<'a> CallOpts<'a> {
     {
         cmd.stderr(Stdio::piped());
         cmd.stdout(Stdio::piped());
+        if let Some(env) = &self.env {
+            env.apply(&mut cmd);
+        }
+        if self.cancel_rx.is_some() {
+            own_process_group(&mut cmd);
+        }
 
         // 0. spawn the process
         let mut nix_proc = cmd.spawn().map_err(|e| match e.kind() {
@@ -392,14 +503,24 @@ impl<'a> CallOpts<'a> {
         // 1. spawn a stderr handling thread
         let stderr_handle: ChildStderr = nix_proc.stderr.take().expect("failed to take stderr");
         let stderr_tx = self.stderr_line_tx.clone();
+        let log_event_tx = self.log_event_tx.clone();
         let stderr_thread = thread::spawn(move || {
             let reader = osstrlines::Lines::from(BufReader::new(stderr_handle));
-            if let Some(tx) = stderr_tx {
-                for line in reader {
-                    tx.send(line.unwrap()).expect("Receiver for nix.rs hung up");
+            for line in reader {
+                let line = line.unwrap();
+                let event = log::LogEvent::parse(&line);
+                if let Some(tx) = &stderr_tx {
+                    let text = match &event {
+                        Some(event) => log::event_text_lines(event),
+                        None => vec![line],
+                    };
+                    for text in text {
+                        tx.send(text).expect("Receiver for nix.rs hung up");
+                    }
+                }
+                if let (Some(tx), Some(event)) = (&log_event_tx, event) {
+                    tx.send(event).expect("Receiver for nix.rs hung up");
                 }
-            } else {
-                for _line in reader {}
             }
         });
 
@@ -408,7 +529,8 @@ impl<'a> CallOpts<'a> {
         let stdout_thread = thread::spawn(move || stdout_fn(BufReader::new(stdout_handle)));
 
         // 3. wait on the process
-        let nix_proc_result = nix_proc.wait().expect("nix wasn't running");
+        let nix_proc_result =
+            wait_or_cancel(&mut nix_proc, self.cancel_rx.as_ref()).expect("nix wasn't running");
 
         // 4. join the stderr handler
         stderr_thread
@@ -420,7 +542,10 @@ impl<'a> CallOpts<'a> {
             .join()
             .expect("stderr handling thread panicked");
 
-        Ok((data_result, nix_proc_result))
+        match nix_proc_result {
+            Some(status) => Ok((data_result, status)),
+            None => Err(ExecuteError::Cancelled),
+        }
     }
 
     /// Fetch common arguments passed to Nix's CLI, specifically
@@ -454,11 +579,66 @@ impl<'a> CallOpts<'a> {
     }
 }
 
+/// Run `cmd` in a new process group, so that it can be killed
+/// together with all of its children, see `wait_or_cancel`.
+pub fn own_process_group(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    // Safe, because `setpgid` is async-signal-safe.
+    unsafe {
+        cmd.pre_exec(|| {
+            nix::unistd::setpgid(nix::unistd::Pid::from_raw(0), nix::unistd::Pid::from_raw(0))
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+        });
+    }
+}
+
+/// How often `wait_or_cancel` checks whether the process exited.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Wait for `child` to exit. If a message arrives on `cancel` first,
+/// terminate the process group of `child` (see `own_process_group`),
+/// wait for it to exit and return `None`.
+#[allow(clippy::drop_copy, clippy::zero_ptr)] // triggered by `select!`
+pub fn wait_or_cancel(
+    child: &mut Child,
+    cancel: Option<&chan::Receiver<()>>,
+) -> std::io::Result<Option<ExitStatus>> {
+    let cancel = match cancel {
+        Some(cancel) => cancel,
+        None => return child.wait().map(Some),
+    };
+    // check for a pending cancellation right away
+    let mut timeout = Duration::from_secs(0);
+    loop {
+        chan::select! {
+            recv(cancel) -> msg => return match msg {
+                Ok(()) => {
+                    debug!("cancelling nix process"; "pid" => child.id());
+                    let pgid = nix::unistd::Pid::from_raw(child.id() as i32);
+                    // the process might have exited in the meantime
+                    let _ = nix::sys::signal::killpg(pgid, nix::sys::signal::Signal::SIGTERM);
+                    child.wait()?;
+                    Ok(None)
+                }
+                // nobody can cancel anymore
+                Err(chan::RecvError) => child.wait().map(Some),
+            },
+            default(timeout) => {}
+        }
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        timeout = CANCEL_POLL_INTERVAL;
+    }
+}
+
 /// Errors returned by the `execute()` helper
 enum ExecuteError {
     /// one of the nix executables not found
     NixNotFound,
     Io(std::io::Error),
+    /// the call was cancelled, see `CallOpts::set_cancel_receiver`
+    Cancelled,
 }
 
 /// Possible error conditions encountered when executing Nix evaluation commands.
@@ -476,6 +656,9 @@ pub enum EvaluationError {
     /// The data returned from nix-instantiate did not match the
     /// data time you expect.
     Decoding(serde_json::Error),
+
+    /// The evaluation was cancelled, see `CallOpts::set_cancel_receiver`.
+    Cancelled,
 }
 
 impl From<std::io::Error> for EvaluationError {
@@ -489,6 +672,7 @@ impl From<ExecuteError> for EvaluationError {
         match e {
             ExecuteError::NixNotFound => EvaluationError::NixNotFound,
             ExecuteError::Io(e) => EvaluationError::from(e),
+            ExecuteError::Cancelled => EvaluationError::Cancelled,
         }
     }
 }
@@ -526,6 +710,9 @@ pub enum BuildError {
 
     /// Build produced no paths
     NoResult,
+
+    /// The build was cancelled, see `CallOpts::set_cancel_receiver`.
+    Cancelled,
 }
 
 impl From<std::io::Error> for BuildError {
@@ -539,6 +726,7 @@ impl From<ExecuteError> for BuildError {
         match e {
             ExecuteError::NixNotFound => BuildError::NixNotFound,
             ExecuteError::Io(e) => BuildError::from(e),
+            ExecuteError::Cancelled => BuildError::Cancelled,
         }
     }
 }
@@ -575,11 +763,29 @@ impl From<BuildError> for OnePathError {
 
 #[cfg(test)]
 mod tests {
-    use super::CallOpts;
+    use super::{CallOpts, EvalEnv};
     use crossbeam_channel as chan;
     use std::env;
     use std::ffi::OsStr;
     use std::path::Path;
+    use std::process::Command;
+    use std::time::{Duration, Instant};
+
+    /// A cancelled process (here a shell waiting for its child) is terminated right away.
+    #[test]
+    fn cancelled_process_is_terminated() -> std::io::Result<()> {
+        let mut cmd = Command::new("sh");
+        cmd.args(&["-c", "sleep 60 & wait"]);
+        super::own_process_group(&mut cmd);
+        let mut child = cmd.spawn()?;
+
+        let (cancel_tx, cancel_rx) = chan::bounded(1);
+        cancel_tx.send(()).unwrap();
+        let start = Instant::now();
+        assert!(super::wait_or_cancel(&mut child, Some(&cancel_rx))?.is_none());
+        assert!(start.elapsed() < Duration::from_secs(10));
+        Ok(())
+    }
 
     #[test]
     fn cmd_arguments_expression() {
@@ -658,4 +864,30 @@ mod tests {
             "looking for a line like 'building \'/nix/store...'"
         );
     }
+
+    #[test]
+    fn eval_env_sets_and_unsets_variables() -> std::io::Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let mut env = EvalEnv::default();
+        env.vars
+            .insert("NIX_PATH".into(), "nixpkgs=./nixpkgs".into());
+        env.cwd = Some(tempdir.path().to_path_buf());
+
+        let mut cmd = Command::new("sh");
+        cmd.args(&[
+            "-c",
+            r#"echo "$NIX_PATH|${NIXPKGS_ALLOW_UNFREE-unset}|$(pwd -P)""#,
+        ])
+        .env("NIXPKGS_ALLOW_UNFREE", "1");
+        env.apply(&mut cmd);
+        let output = cmd.output()?;
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout).trim_end(),
+            format!(
+                "nixpkgs=./nixpkgs|unset|{}",
+                tempdir.path().canonicalize()?.display()
+            )
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,312 @@
+//! Parse the logs nix prints on stderr with `--log-format internal-json`.
+//!
+//! In this format, nix prints every log message, every activity (like a
+//! build or a download) and every result of an activity (like a line of
+//! build output) as a JSON object on a line of its own, prefixed by `@nix `.
+//! Older versions of nix don’t know the option, see `internal_json_supported`;
+//! their human-readable output is passed on unchanged.
+
+use regex::Regex;
+use slog_scope::debug;
+use std::ffi::{OsStr, OsString};
+use std::process::{Command, Stdio};
+
+/// The arguments which make nix log in the internal-json format.
+pub const LOG_FORMAT_ARGS: &[&str] = &["--log-format", "internal-json"];
+
+/// Prefix of the lines nix prints in the internal-json format.
+const PREFIX: &str = "@nix ";
+
+lazy_static! {
+    static ref SUPPORTED: bool = probe();
+}
+
+/// Whether the installed nix supports `--log-format internal-json`.
+/// Only checked once, by running `nix-instantiate` with it.
+pub fn internal_json_supported() -> bool {
+    *SUPPORTED
+}
+
+/// `LOG_FORMAT_ARGS` if the installed nix supports them, nothing otherwise.
+pub fn log_format_args() -> &'static [&'static str] {
+    if internal_json_supported() {
+        LOG_FORMAT_ARGS
+    } else {
+        &[]
+    }
+}
+
+fn probe() -> bool {
+    let supported = Command::new("nix-instantiate")
+        .args(LOG_FORMAT_ARGS)
+        .args(&["--eval", "--expr", "null"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false);
+    debug!("checked for nix’s internal-json log format"; "supported" => supported);
+    supported
+}
+
+/// A line nix printed in the internal-json format.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum LogEvent {
+    /// A log message, like a warning, an error or a `builtins.trace`
+    Msg {
+        /// The verbosity level of the message, 0 (error) to 7 (vomit)
+        #[serde(default)]
+        level: u64,
+        /// The message, which might span multiple lines
+        msg: String,
+    },
+    /// An activity started
+    Start {
+        /// Identifies the activity in later events
+        id: u64,
+        /// What kind of activity this is
+        #[serde(rename = "type")]
+        activity: ActivityType,
+        /// A description of the activity, might be empty
+        #[serde(default)]
+        text: String,
+        /// Details, depending on the `activity`
+        #[serde(default)]
+        fields: Vec<Field>,
+        /// The activity this one is part of, or 0
+        #[serde(default)]
+        parent: u64,
+    },
+    /// An activity finished
+    Stop {
+        /// See `LogEvent::Start`
+        id: u64,
+    },
+    /// An activity reported a result
+    Result {
+        /// See `LogEvent::Start`
+        id: u64,
+        /// What kind of result this is
+        #[serde(rename = "type")]
+        result: ResultType,
+        /// Details, depending on the `result`
+        #[serde(default)]
+        fields: Vec<Field>,
+    },
+}
+
+/// The kinds of activities nix reports, see `LogEvent::Start`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(from = "u64")]
+pub enum ActivityType {
+    /// Copying a single path, e.g. from a binary cache
+    CopyPath,
+    /// Downloading a file
+    FileTransfer,
+    /// Building and substituting a set of paths
+    Realise,
+    /// Copying a set of paths
+    CopyPaths,
+    /// Building a set of derivations
+    Builds,
+    /// Building a single derivation
+    Build,
+    /// Substituting a single path
+    Substitute,
+    /// Any other activity
+    Other(u64),
+}
+
+impl From<u64> for ActivityType {
+    fn from(number: u64) -> Self {
+        match number {
+            100 => ActivityType::CopyPath,
+            101 => ActivityType::FileTransfer,
+            102 => ActivityType::Realise,
+            103 => ActivityType::CopyPaths,
+            104 => ActivityType::Builds,
+            105 => ActivityType::Build,
+            108 => ActivityType::Substitute,
+            other => ActivityType::Other(other),
+        }
+    }
+}
+
+/// The kinds of results activities report, see `LogEvent::Result`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(from = "u64")]
+pub enum ResultType {
+    /// A line of output of a builder; the first field
+    BuildLogLine,
+    /// The build entered another phase, like `buildPhase`; the first field
+    SetPhase,
+    /// How far the activity got: done, expected, running and failed units
+    Progress,
+    /// How many units of a kind of activity are expected: the activity type and the count
+    SetExpected,
+    /// A line of output of the post-build hook; the first field
+    PostBuildLogLine,
+    /// Any other result
+    Other(u64),
+}
+
+impl From<u64> for ResultType {
+    fn from(number: u64) -> Self {
+        match number {
+            101 => ResultType::BuildLogLine,
+            104 => ResultType::SetPhase,
+            105 => ResultType::Progress,
+            106 => ResultType::SetExpected,
+            107 => ResultType::PostBuildLogLine,
+            other => ResultType::Other(other),
+        }
+    }
+}
+
+/// A detail of an activity or result.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Field {
+    /// A number
+    Int(u64),
+    /// A string
+    String(String),
+}
+
+impl LogEvent {
+    /// Parse a line nix printed on stderr. Returns `None` if it is not in
+    /// the internal-json format.
+    pub fn parse(line: &OsStr) -> Option<LogEvent> {
+        // builders can print anything, which nix passes on unchanged
+        let line = line.to_string_lossy();
+        if !line.starts_with(PREFIX) {
+            return None;
+        }
+        serde_json::from_str(&line[PREFIX.len()..]).ok()
+    }
+
+    /// What nix would have printed for this event in its human-readable format.
+    pub fn text(&self) -> Option<String> {
+        lazy_static! {
+            // newer nix versions color their messages
+            static ref ANSI_ESCAPE: Regex =
+                Regex::new("\x1b\\[[0-9;]*m").expect("invalid regex!");
+        }
+        match self {
+            LogEvent::Msg { msg, .. } => Some(ANSI_ESCAPE.replace_all(msg, "").into_owned()),
+            LogEvent::Start { text, .. } if !text.is_empty() => Some(text.clone()),
+            LogEvent::Result {
+                result: ResultType::BuildLogLine,
+                fields,
+                ..
+            }
+            | LogEvent::Result {
+                result: ResultType::PostBuildLogLine,
+                fields,
+                ..
+            } => match fields.first() {
+                Some(Field::String(line)) => Some(line.clone()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Turn a line nix printed on stderr into the lines it would have
+/// printed in its human-readable format. Lines which are not in the
+/// internal-json format are returned unchanged.
+pub fn text_lines(line: OsString) -> Vec<OsString> {
+    match LogEvent::parse(&line) {
+        Some(event) => event_text_lines(&event),
+        None => vec![line],
+    }
+}
+
+/// The lines nix would have printed for `event` in its human-readable format.
+pub fn event_text_lines(event: &LogEvent) -> Vec<OsString> {
+    event
+        .text()
+        .map(|text| text.lines().map(OsString::from).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Option<LogEvent> {
+        LogEvent::parse(OsStr::new(line))
+    }
+
+    #[test]
+    fn parse_events() {
+        assert_eq!(
+            parse(
+                r#"@nix {"action":"msg","level":5,"msg":"evaluating file '/project/shell.nix'"}"#
+            ),
+            Some(LogEvent::Msg {
+                level: 5,
+                msg: String::from("evaluating file '/project/shell.nix'")
+            })
+        );
+        assert_eq!(
+            parse(
+                r#"@nix {"action":"start","id":42,"level":3,"type":105,"text":"building '/nix/store/abc-hello.drv'","fields":["/nix/store/abc-hello.drv","",1,1],"parent":7}"#
+            ),
+            Some(LogEvent::Start {
+                id: 42,
+                activity: ActivityType::Build,
+                text: String::from("building '/nix/store/abc-hello.drv'"),
+                fields: vec![
+                    Field::String(String::from("/nix/store/abc-hello.drv")),
+                    Field::String(String::new()),
+                    Field::Int(1),
+                    Field::Int(1)
+                ],
+                parent: 7,
+            })
+        );
+        assert_eq!(
+            parse(r#"@nix {"action":"result","id":42,"type":105,"fields":[1,4,1,0]}"#),
+            Some(LogEvent::Result {
+                id: 42,
+                result: ResultType::Progress,
+                fields: vec![Field::Int(1), Field::Int(4), Field::Int(1), Field::Int(0)],
+            })
+        );
+        assert_eq!(
+            parse(r#"@nix {"action":"stop","id":42}"#),
+            Some(LogEvent::Stop { id: 42 })
+        );
+        assert_eq!(parse("building '/nix/store/abc-hello.drv'..."), None);
+        assert_eq!(parse(r#"@nix {"action":"unheard-of"}"#), None);
+    }
+
+    #[test]
+    fn text_lines_like_human_readable_format() {
+        let lines = |line: &str| text_lines(OsString::from(line));
+        assert_eq!(
+            lines(
+                r#"@nix {"action":"msg","level":0,"msg":"\u001b[31;1merror:\u001b[0m oops\n  at here"}"#
+            ),
+            vec![OsString::from("error: oops"), OsString::from("  at here")]
+        );
+        assert_eq!(
+            lines(r#"@nix {"action":"result","id":1,"type":101,"fields":["compiling hello.c"]}"#),
+            vec![OsString::from("compiling hello.c")]
+        );
+        assert_eq!(
+            lines(
+                r#"@nix {"action":"start","id":1,"level":4,"type":0,"text":"","fields":[],"parent":0}"#
+            ),
+            Vec::<OsString>::new()
+        );
+        assert_eq!(
+            lines("trace: lorri read: '/project/file'"),
+            vec![OsString::from("trace: lorri read: '/project/file'")]
+        );
+    }
+}
@@ -1,26 +1,59 @@
 //! Run a BuildLoop for `shell.nix`, watching for input file changes.
 //! Can be used together with `direnv`.
 
-use crate::daemon::Daemon;
-use crate::ops::error::{ok, OpResult};
+use crate::build_loop::Event;
+use crate::cli::DaemonOptions;
+use crate::daemon::{Config, Daemon};
+use crate::hooks::Hooks;
+use crate::logging::Structured;
+use crate::ops::error::{ok, ExitError, OpResult};
 use crate::socket::SocketPath;
-use slog_scope::info;
+use crossbeam_channel as chan;
+use slog_scope::{debug, info};
+use std::time::Duration;
 
 /// See the documentation for lorri::cli::Command::Daemon for details.
-pub fn main() -> OpResult {
-    let (daemon, build_rx) = Daemon::new();
-    let build_handle = std::thread::spawn(|| {
+pub fn main(opts: DaemonOptions) -> OpResult {
+    let paths = crate::ops::get_paths()?;
+    let config = Config {
+        max_builds: opts.max_builds,
+        stale_after: opts.stale_after.map(Duration::from_secs),
+        evict_after: opts.evict_after.map(Duration::from_secs),
+        registry_file: Some(paths.daemon_registry_file().to_path_buf()),
+        restore_within: Duration::from_secs(opts.restore_within),
+        debounce: Duration::from_millis(opts.debounce),
+        metrics_file: opts.metrics_file,
+        allowed_uids: opts.allow_uids,
+    };
+    let hooks = Hooks::start(
+        opts.hooks.hooks,
+        Duration::from_secs(opts.hooks.hook_timeout),
+    );
+    let (daemon, build_rx) = Daemon::new(config);
+    let build_handle = std::thread::spawn(move || {
         for msg in build_rx {
-            info!("build status"; "message" => ?msg);
+            let event = Structured(crate::rpc::Event::from(&msg));
+            match msg {
+                Event::Progress { .. } => debug!("build progress"; "event" => event),
+                _ => info!("build status"; "event" => event),
+            }
+            hooks.run(&msg);
         }
+        hooks.finish();
     });
+    let (shutdown_tx, shutdown_rx) = chan::bounded(1);
+    crate::ops::signal::on_termination(move || {
+        // the daemon might be shutting down already
+        let _ = shutdown_tx.send(());
+    })
+    .map_err(|e| ExitError::temporary(format!("failed to install the signal handlers: {}", e)))?;
     info!("ready");
 
-    let paths = crate::ops::get_paths()?;
     daemon.serve(
         SocketPath::from(paths.daemon_socket_file()),
         paths.gc_root_dir().to_path_buf(),
         paths.cas_store().clone(),
+        shutdown_rx,
     )?;
     build_handle
         .join()
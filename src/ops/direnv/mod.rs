@@ -3,6 +3,7 @@
 mod version;
 
 use self::version::{DirenvVersion, MIN_DIRENV_VERSION};
+use crate::nix::EvalEnv;
 use crate::ops::error::{ok, ExitError, OpResult};
 use crate::project::roots::Roots;
 use crate::project::Project;
@@ -19,14 +20,17 @@ pub fn main<W: std::io::Write>(project: Project, mut shell_output: W) -> OpResul
     let root_paths = Roots::from_project(&project).paths();
     let paths_are_cached: bool = root_paths.all_exist();
     let address = crate::ops::get_paths()?.daemon_socket_address();
-    let shell_nix = rpc::ShellNix::try_from(&project.nix_file).map_err(ExitError::temporary)?;
+    let mut shell_nix = rpc::ShellNix::try_from(&project.nix_file).map_err(ExitError::temporary)?;
+    // the daemon should evaluate the project like `nix-shell` would in this shell
+    let env = EvalEnv::current();
+    shell_nix.env = Some(env.vars.into_iter().collect());
+    shell_nix.cwd = env.cwd.map(|cwd| cwd.to_string_lossy().into_owned());
 
     let ping_sent = if let Ok(connection) = varlink::Connection::with_address(&address) {
         use rpc::VarlinkClientInterface;
-        rpc::VarlinkClient::new(connection)
-            .watch_shell(shell_nix)
-            .call()
-            .is_ok()
+        let mut client = rpc::VarlinkClient::new(connection);
+        crate::ops::check_daemon_version(&mut client);
+        client.watch_shell(shell_nix).call().is_ok()
     } else {
         false
     };
@@ -36,10 +40,13 @@ pub fn main<W: std::io::Write>(project: Project, mut shell_output: W) -> OpResul
 
         // Ping sent & paths aren't cached: once the environment is created
         // the direnv environment will be updated automatically.
-        (true, false) =>
+        (true, false) => {
+            // The build was most likely just started by our ping, so it would not
+            // have any progress to show yet, and we must not keep direnv waiting.
             info!(
-                "lorri has not completed an evaluation for this project yet"
-            ),
+                "lorri has not completed an evaluation for this project yet, see `lorri status` for its progress"
+            );
+        }
 
         // Ping not sent and paths are cached: we can load a stale environment
         // When the daemon is started, we'll send a fresh ping.
@@ -0,0 +1,55 @@
+//! Make the daemon stop watching a project.
+
+use crate::ops::error::{ok, ExitError, OpResult};
+use crate::rpc;
+use crate::NixFile;
+use std::convert::TryFrom;
+
+/// How the project to forget is identified.
+pub enum Forget {
+    /// By its nix file, which does not need to exist anymore
+    NixFile(NixFile),
+    /// By the hash `lorri status` shows for it
+    Hash(String),
+}
+
+/// See the documentation for lorri::cli::Command::Forget for details.
+pub fn main(forget: Forget, delete_gc_roots: bool) -> OpResult {
+    use rpc::VarlinkClientInterface;
+    let mut client = crate::ops::connect_to_daemon()?;
+
+    let (project, reply) = match forget {
+        Forget::Hash(hash) => {
+            let reply = client.unwatch_project(hash.clone(), delete_gc_roots).call();
+            (format!("the project with hash {}", hash), reply)
+        }
+        Forget::NixFile(nix_file) => {
+            let shell_nix = rpc::ShellNix::try_from(&nix_file).map_err(ExitError::user_error)?;
+            let project = shell_nix.path.clone();
+            let reply = client
+                .unwatch_shell(shell_nix, delete_gc_roots)
+                .call()
+                .map(|reply| rpc::UnwatchProject_Reply {
+                    was_watched: reply.was_watched,
+                });
+            (project, reply)
+        }
+    };
+
+    let was_watched = reply
+        .map_err(|e| match e.kind() {
+            rpc::ErrorKind::GcRootsNotDeleted(Some(args)) => ExitError::temporary(format!(
+                "stopped watching {}, but failed to delete its GC roots: {}",
+                project, args.message
+            )),
+            _ => ExitError::temporary(format!("call to daemon server failed: {}", e)),
+        })?
+        .was_watched;
+
+    if was_watched {
+        println!("The lorri daemon no longer watches {}.", project);
+    } else {
+        println!("The lorri daemon was not watching {}.", project);
+    }
+    ok()
+}
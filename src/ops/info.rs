@@ -1,17 +1,39 @@
 //! The info callable is for printing
 
 use crate::ops::error::{ok, OpResult};
+use crate::project::Project;
+use crate::NixFile;
 use crate::VERSION_BUILD_REV;
 use std::path::PathBuf;
 
 /// See the documentation for lorri::cli::Command::Info for more
 /// details.
-pub fn main(shell_nix: PathBuf) -> OpResult {
+pub fn main(project: &Project, candidates: &[PathBuf]) -> OpResult {
     println!("lorri version: {}", VERSION_BUILD_REV);
     println!("Lorri Project Configuration");
     println!();
 
-    println!("expression: {}", shell_nix.display());
+    println!("expression: {}", PathBuf::from(&project.nix_file).display());
+    println!(
+        "found by looking for: {}",
+        candidates
+            .iter()
+            .map(|c| c.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    if let NixFile::Shell { args, .. } = &project.nix_file {
+        if let Some(attr) = &args.attr {
+            println!("attribute: {}", attr);
+        }
+        for (name, expr) in &args.args {
+            println!("argument: {} = {}", name, expr);
+        }
+        for (name, value) in &args.argstrs {
+            println!("argument: {} = {:?}", name, value);
+        }
+    }
+    println!("project hash: {}", project.hash());
 
     ok()
 }
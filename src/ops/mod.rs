@@ -2,12 +2,20 @@
 
 pub mod daemon;
 pub mod direnv;
+pub mod forget;
 pub mod info;
 pub mod init;
 pub mod ping;
+pub mod rebuild;
+pub mod services;
+pub mod status;
+pub mod stream_events;
 pub mod upgrade;
+pub mod wait;
 pub mod watch;
 
+mod signal;
+
 /// Set up necessary directories or fail.
 pub fn get_paths() -> Result<crate::constants::Paths, error::ExitError> {
     crate::constants::Paths::initialize().map_err(|e| {
@@ -15,6 +23,45 @@ pub fn get_paths() -> Result<crate::constants::Paths, error::ExitError> {
     })
 }
 
+/// Warn if the running daemon is of another version than this lorri,
+/// which happens if lorri was upgraded but the daemon was not restarted.
+pub fn check_daemon_version(client: &mut crate::rpc::VarlinkClient) {
+    use crate::rpc::VarlinkClientInterface;
+    use slog_scope::warn;
+    const RESTART: &str = "please restart it, e.g. with `systemctl --user restart lorri`";
+    let build_rev = crate::VERSION_BUILD_REV as i64;
+    match client.get_version().call() {
+        Ok(version) if version.protocol != crate::daemon::PROTOCOL_VERSION => warn!(
+            "the running lorri daemon speaks another protocol than this lorri, {}", RESTART;
+            "daemon_protocol" => version.protocol,
+            "protocol" => crate::daemon::PROTOCOL_VERSION
+        ),
+        Ok(version) if version.build_rev != build_rev => warn!(
+            "the running lorri daemon is of another version than this lorri, {}", RESTART;
+            "daemon_build_rev" => version.build_rev,
+            "build_rev" => build_rev
+        ),
+        Ok(_) => {}
+        // daemons from before `GetVersion` was added don’t know the call
+        Err(e) => warn!(
+            "failed to ask the lorri daemon for its version, it is probably older than this lorri, {}", RESTART;
+            "error" => %e
+        ),
+    }
+}
+
+/// Connect to the RPC endpoint of a running `lorri daemon`.
+pub fn connect_to_daemon() -> Result<crate::rpc::VarlinkClient, error::ExitError> {
+    let address = get_paths()?.daemon_socket_address();
+    let connection = varlink::Connection::with_address(&address).map_err(|e| {
+        error::ExitError::temporary(format!(
+            "could not connect to the lorri daemon, is `lorri daemon` running? ({})",
+            e
+        ))
+    })?;
+    Ok(crate::rpc::VarlinkClient::new(connection))
+}
+
 /// Error handling in ops.
 pub mod error {
 
@@ -12,11 +12,13 @@ pub fn main(nix_file: NixFile) -> OpResult {
     let shell_nix = rpc::ShellNix::try_from(&nix_file).unwrap();
 
     use rpc::VarlinkClientInterface;
-    rpc::VarlinkClient::new(
+    let mut client = rpc::VarlinkClient::new(
         varlink::Connection::with_address(&address).expect("failed to connect to daemon server"),
-    )
-    .watch_shell(shell_nix)
-    .call()
-    .expect("call to daemon server failed");
+    );
+    crate::ops::check_daemon_version(&mut client);
+    client
+        .watch_shell(shell_nix)
+        .call()
+        .expect("call to daemon server failed");
     ok()
 }
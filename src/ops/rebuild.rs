@@ -0,0 +1,18 @@
+//! Make the daemon build a project again.
+
+use crate::ops::error::{ok, ExitError, OpResult};
+use crate::rpc;
+use crate::NixFile;
+use std::convert::TryFrom;
+
+/// See the documentation for lorri::cli::Command::Rebuild for details.
+pub fn main(nix_file: NixFile, refresh: bool) -> OpResult {
+    let shell_nix = rpc::ShellNix::try_from(&nix_file).map_err(ExitError::temporary)?;
+
+    use rpc::VarlinkClientInterface;
+    crate::ops::connect_to_daemon()?
+        .rebuild(shell_nix, refresh)
+        .call()
+        .map_err(|e| ExitError::temporary(format!("call to daemon server failed: {}", e)))?;
+    ok()
+}
@@ -0,0 +1,32 @@
+//! Run the services defined in a services nix file through the daemon.
+
+use crate::logging::Structured;
+use crate::ops::error::{ok, ExitError, OpResult};
+use crate::rpc;
+use crate::NixFile;
+use slog_scope::info;
+use std::convert::TryFrom;
+
+/// See the documentation for lorri::cli::Command::Services for details.
+pub fn main(nix_file: NixFile) -> OpResult {
+    let services_nix = rpc::ServicesNix::try_from(&nix_file).map_err(ExitError::temporary)?;
+
+    use rpc::VarlinkClientInterface;
+    let mut client = crate::ops::connect_to_daemon()?;
+    let mut call = client.watch_services(services_nix);
+    let replies = call
+        .more()
+        .map_err(|e| ExitError::temporary(format!("call to daemon server failed: {}", e)))?;
+    for reply in replies {
+        let service = reply
+            .map_err(|e| ExitError::temporary(format!("daemon server stream failed: {}", e)))?
+            .service;
+        info!(
+            "service";
+            "name" => service.name,
+            "program" => service.command.program,
+            "args" => Structured(&service.command.args)
+        );
+    }
+    ok()
+}
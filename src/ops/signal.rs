@@ -0,0 +1,83 @@
+//! Turn SIGTERM and SIGINT into a graceful shutdown of `lorri daemon`
+//! or `lorri watch`, which cancels the running builds.
+//!
+//! The signal handler only writes to a pipe (the “self-pipe trick”),
+//! a regular thread reads from it and does the actual work.
+
+use nix::libc::c_int;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd;
+use slog_scope::{info, warn};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// The write end of the pipe the signal handler writes to.
+static PIPE_WRITE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn write_to_pipe(signal: c_int) {
+    // only async-signal-safe functions are allowed in here
+    let _ = unistd::write(PIPE_WRITE.load(Ordering::SeqCst), &[signal as u8]);
+}
+
+/// Call `on_first` in a separate thread when the process receives
+/// SIGTERM or SIGINT for the first time. On the second signal,
+/// the process exits immediately.
+pub fn on_termination<F>(on_first: F) -> std::io::Result<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let (read, write) = unistd::pipe().map_err(to_io_error)?;
+    for fd in &[read, write] {
+        use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+        fcntl(*fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(to_io_error)?;
+    }
+    PIPE_WRITE.store(write, Ordering::SeqCst);
+
+    std::thread::Builder::new()
+        .name(String::from("signal-handler"))
+        .spawn(move || {
+            let mut on_first = Some(on_first);
+            while let Some(signal) = read_signal(read) {
+                match on_first.take() {
+                    Some(f) => {
+                        info!("received signal, shutting down"; "signal" => signal);
+                        f()
+                    }
+                    None => {
+                        warn!("received signal again, exiting immediately"; "signal" => signal);
+                        std::process::exit(1)
+                    }
+                }
+            }
+        })?;
+
+    let action = SigAction::new(
+        SigHandler::Handler(write_to_pipe),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for signal in &[Signal::SIGTERM, Signal::SIGINT] {
+        // Safe, because the handler only calls async-signal-safe functions.
+        unsafe { signal::sigaction(*signal, &action) }.map_err(to_io_error)?;
+    }
+    Ok(())
+}
+
+/// Block until the signal handler wrote to the pipe.
+fn read_signal(read: RawFd) -> Option<c_int> {
+    let mut buf = [0];
+    loop {
+        match unistd::read(read, &mut buf) {
+            Ok(1) => return Some(c_int::from(buf[0])),
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            _ => return None,
+        }
+    }
+}
+
+fn to_io_error(e: nix::Error) -> std::io::Error {
+    match e {
+        nix::Error::Sys(errno) => std::io::Error::from_raw_os_error(errno as i32),
+        other => std::io::Error::new(std::io::ErrorKind::Other, other.to_string()),
+    }
+}
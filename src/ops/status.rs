@@ -0,0 +1,85 @@
+//! Show the projects watched by the daemon.
+
+use crate::build_loop::BuildProgress;
+use crate::cli::StatusOptions;
+use crate::ops::error::{ok, ExitError, OpResult};
+use crate::rpc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// See the documentation for lorri::cli::Command::Status for details.
+pub fn main(opts: StatusOptions) -> OpResult {
+    use rpc::VarlinkClientInterface;
+    let mut projects = crate::ops::connect_to_daemon()?
+        .list_projects()
+        .call()
+        .map_err(|e| ExitError::temporary(format!("call to daemon server failed: {}", e)))?
+        .projects;
+    projects.sort_by(|a, b| a.nix_file.cmp(&b.nix_file));
+
+    if opts.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&projects).expect("failed to serialize projects")
+        );
+        return ok();
+    }
+
+    if projects.is_empty() {
+        println!("The lorri daemon is not watching any projects.");
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    for project in projects {
+        println!("{}", project.nix_file);
+        println!("  hash:       {}", project.hash);
+        println!(
+            "  state:      {}{}",
+            state(&project.state),
+            if project.stale { " (stale)" } else { "" }
+        );
+        if let Some(progress) = project.progress {
+            println!("  progress:   {}", BuildProgress::from(&progress));
+        }
+        if let Some(time) = project.last_build_time {
+            println!("  last build: {} ago", since(now - time));
+        }
+        if let Some(gc_root) = project.last_gc_root {
+            println!("  gc root:    {}", gc_root);
+        }
+    }
+    ok()
+}
+
+fn state(state: &rpc::ProjectState) -> &'static str {
+    match state {
+        rpc::ProjectState::idle => "idle",
+        rpc::ProjectState::building => "building",
+        rpc::ProjectState::failed => "failed",
+    }
+}
+
+/// Render a number of seconds in the largest sensible unit.
+fn since(seconds: i64) -> String {
+    match seconds {
+        s if s < 60 => format!("{}s", s.max(0)),
+        s if s < 60 * 60 => format!("{}m", s / 60),
+        s if s < 24 * 60 * 60 => format!("{}h", s / (60 * 60)),
+        s => format!("{}d", s / (24 * 60 * 60)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::since;
+
+    #[test]
+    fn since_picks_largest_unit() {
+        assert_eq!(since(-3), "0s");
+        assert_eq!(since(59), "59s");
+        assert_eq!(since(60), "1m");
+        assert_eq!(since(2 * 60 * 60 + 1), "2h");
+        assert_eq!(since(3 * 24 * 60 * 60), "3d");
+    }
+}
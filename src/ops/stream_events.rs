@@ -0,0 +1,24 @@
+//! Print the daemon's build events to stdout.
+
+use crate::ops::error::{ok, ExitError, OpResult};
+
+/// See the documentation for lorri::cli::Command::StreamEvents for details.
+pub fn main() -> OpResult {
+    use crate::rpc::VarlinkClientInterface;
+    let mut client = crate::ops::connect_to_daemon()?;
+    let mut call = client.stream_events();
+    let replies = call
+        .more()
+        .map_err(|e| ExitError::temporary(format!("call to daemon server failed: {}", e)))?;
+    for reply in replies {
+        let event = reply
+            .map_err(|e| ExitError::temporary(format!("daemon server stream failed: {}", e)))?
+            .event;
+        // one JSON object per line
+        println!(
+            "{}",
+            serde_json::to_string(&event).expect("failed to serialize event")
+        );
+    }
+    ok()
+}
@@ -0,0 +1,40 @@
+//! Wait for the daemon to finish building a project.
+
+use crate::ops::error::{ok, ExitError, OpResult};
+use crate::rpc;
+use crate::NixFile;
+use std::convert::TryFrom;
+
+/// See the documentation for lorri::cli::Command::Wait for details.
+pub fn main(nix_file: NixFile, timeout: Option<u64>) -> OpResult {
+    let shell_nix = rpc::ShellNix::try_from(&nix_file).map_err(ExitError::temporary)?;
+
+    use rpc::VarlinkClientInterface;
+    let outcome = crate::ops::connect_to_daemon()?
+        .wait_for_build(shell_nix, timeout.map(|t| t as i64))
+        .call()
+        .map_err(|e| match e.kind() {
+            rpc::ErrorKind::Timeout(_) => ExitError::temporary(format!(
+                "the build did not finish within {} seconds",
+                timeout.unwrap_or(0)
+            )),
+            rpc::ErrorKind::NotWatched(_) => ExitError::temporary(
+                "the daemon stopped watching the project before the build finished",
+            ),
+            _ => ExitError::temporary(format!("call to daemon server failed: {}", e)),
+        })?
+        .outcome;
+
+    match outcome.kind {
+        rpc::BuildOutcomeKind::success => {
+            if let Some(gc_root) = outcome.gc_root {
+                println!("{}", gc_root);
+            }
+            ok()
+        }
+        rpc::BuildOutcomeKind::failure => Err(ExitError::expected_error(format!(
+            "the build failed:\n{}",
+            outcome.log_tail.unwrap_or_default().join("\n")
+        ))),
+    }
+}
@@ -1,14 +1,16 @@
 //! Run a BuildLoop for `shell.nix`, watching for input file changes.
 //! Can be used together with `direnv`.
 
-use crate::build_loop::{BuildError, BuildLoop};
+use crate::build_loop::{BuildError, BuildLoop, Event, Ping};
 use crate::cli::WatchOptions;
+use crate::hooks::Hooks;
 use crate::ops::error::{ok, ExitError, OpResult};
 use crate::project::Project;
 use crossbeam_channel as chan;
 use slog_scope::info;
 use std::fmt::Debug;
 use std::thread;
+use std::time::Duration;
 
 /// See the documentation for lorri::cli::Command::Shell for more
 /// details.
@@ -16,7 +18,11 @@ pub fn main(project: Project, opts: WatchOptions) -> OpResult {
     if opts.once {
         main_run_once(project)
     } else {
-        main_run_forever(project)
+        let hooks = Hooks::start(
+            opts.hooks.hooks,
+            Duration::from_secs(opts.hooks.hook_timeout),
+        );
+        main_run_forever(project, Duration::from_millis(opts.debounce), hooks)
     }
 }
 
@@ -34,22 +40,33 @@ fn main_run_once(project: Project) -> OpResult {
     }
 }
 
-fn main_run_forever(project: Project) -> OpResult {
+fn main_run_forever(project: Project, debounce: Duration, hooks: Hooks) -> OpResult {
     let (tx, rx) = chan::unbounded();
+    // The `watch` command does not currently react to pings. Hanging up
+    // stops the build loop, which cancels the running build: nix runs in
+    // its own process group, so it doesn’t get the signal from the terminal.
+    let (ping_tx, ping_rx) = chan::unbounded::<Ping>();
+    crate::ops::signal::on_termination(move || drop(ping_tx)).map_err(|e| {
+        ExitError::temporary(format!("failed to install the signal handlers: {}", e))
+    })?;
     let build_thread = {
         thread::spawn(move || {
             let mut build_loop = BuildLoop::new(&project);
-
-            // The `watch` command does not currently react to pings, hence the `chan::never()`
-            build_loop.forever(tx, chan::never());
+            build_loop.debounce(debounce);
+            build_loop.forever(tx, ping_rx);
         })
     };
 
     for msg in rx {
-        print_build_message(msg);
+        hooks.run(&msg);
+        match &msg {
+            Event::Progress { progress, .. } => info!("build progress: {}", progress),
+            _ => print_build_message(msg),
+        }
     }
 
     build_thread.join().unwrap();
+    hooks.finish();
 
     ok()
 }
@@ -4,7 +4,7 @@ pub mod roots;
 
 use crate::cas::ContentAddressable;
 use crate::NixFile;
-use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::OsStringExt;
 use std::path::{Path, PathBuf};
 
 /// A “project” knows how to handle the lorri state
@@ -18,7 +18,8 @@ pub struct Project {
     /// garbage collection roots are stored.
     gc_root_path: PathBuf,
 
-    /// Hash of the nix file’s absolute path.
+    /// Hash of the nix file’s absolute path
+    /// (and the shell's name or arguments, if any).
     hash: String,
 
     /// Content-addressable store to save static files in
@@ -34,10 +35,20 @@ impl Project {
         gc_root_dir: &Path,
         cas: ContentAddressable,
     ) -> std::io::Result<Project> {
-        let hash = format!(
-            "{:x}",
-            md5::compute(PathBuf::from(&nix_file).as_os_str().as_bytes())
-        );
+        let mut id = PathBuf::from(&nix_file).into_os_string().into_vec();
+        // a file can describe more than one shell
+        match &nix_file {
+            NixFile::Flake { shell, .. } => {
+                id.push(b'#');
+                id.extend(shell.as_bytes());
+            }
+            NixFile::Shell { args, .. } if !args.is_empty() => {
+                id.push(b'?');
+                id.extend(serde_json::to_vec(args)?);
+            }
+            NixFile::Shell { .. } | NixFile::Services(_) => {}
+        }
+        let hash = format!("{:x}", md5::compute(id));
         let project_gc_root = gc_root_dir.join(&hash).join("gc_root").to_path_buf();
 
         std::fs::create_dir_all(&project_gc_root)?;
@@ -50,8 +61,27 @@ impl Project {
         })
     }
 
+    /// Delete the GC roots of this project, so nix can collect its
+    /// build results.
+    pub fn remove_gc_roots(&self) -> std::io::Result<()> {
+        // the directory named after the hash also contains `gc_root_path`
+        match self.gc_root_path.parent() {
+            Some(dir) => std::fs::remove_dir_all(dir),
+            None => std::fs::remove_dir_all(&self.gc_root_path),
+        }
+    }
+
     /// Generate a "unique" ID for this project based on its absolute path.
     pub fn hash(&self) -> &str {
         &self.hash
     }
 }
+
+/// Delete the GC roots of the project with the given `hash` (see
+/// `Project::hash`), which don’t need to exist.
+pub fn remove_gc_roots(gc_root_dir: &Path, hash: &str) -> std::io::Result<()> {
+    match std::fs::remove_dir_all(gc_root_dir.join(hash)) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
@@ -25,6 +25,11 @@ impl RootPath {
     pub fn as_os_str(&self) -> &std::ffi::OsStr {
         self.0.as_os_str()
     }
+
+    /// Underlying `Path`.
+    pub fn as_path(&self) -> &Path {
+        self.0.as_ref()
+    }
 }
 
 impl OutputPaths<RootPath> {
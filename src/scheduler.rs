@@ -0,0 +1,238 @@
+//! Run the builds of all projects on a fixed number of worker threads.
+//!
+//! The daemon’s `BuildLoops` submit a job to the shared `Scheduler` for
+//! every build. If all workers are busy, the waiting projects get the
+//! next free worker in order of their latest ping, so the project the
+//! user is working on right now is built first.
+
+use crate::NixFile;
+use slog_scope::error;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::SystemTime;
+
+/// Work for a worker thread, usually a build.
+pub type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs jobs on a limited number of worker threads, see the module documentation.
+pub struct Scheduler {
+    shared: Arc<Shared>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Notified whenever a job is queued or the scheduler is closed.
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<State> {
+        self.state
+            .lock()
+            .expect("scheduler mutex poisoned, a worker panicked")
+    }
+}
+
+struct State {
+    /// The latest ping of every registered project.
+    pings: HashMap<NixFile, SystemTime>,
+    /// The jobs waiting for a worker (at most one per project),
+    /// with the order in which they were submitted.
+    queue: HashMap<NixFile, (u64, Job)>,
+    next_ticket: u64,
+    closed: bool,
+}
+
+impl State {
+    /// Take the job which gets the next free worker.
+    fn next(&mut self) -> Option<Job> {
+        let pings = &self.pings;
+        let next = self
+            .queue
+            .iter()
+            // most recently pinged first, then first come first served
+            .max_by_key(|(nix_file, (ticket, _))| {
+                (pings.get(*nix_file), std::cmp::Reverse(*ticket))
+            })
+            .map(|(nix_file, _)| nix_file.clone())?;
+        self.queue.remove(&next).map(|(_, job)| job)
+    }
+}
+
+impl Scheduler {
+    /// A scheduler which runs up to `max_builds` jobs
+    /// at the same time (at least one).
+    pub fn new(max_builds: usize) -> Scheduler {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                pings: HashMap::new(),
+                queue: HashMap::new(),
+                next_ticket: 0,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let workers = (0..max_builds.max(1))
+            .map(|_| {
+                let shared = shared.clone();
+                thread::Builder::new()
+                    .name(String::from("build-worker"))
+                    .spawn(move || work(&shared))
+                    .expect("failed to spawn a build worker thread")
+            })
+            .collect();
+        Scheduler { shared, workers }
+    }
+
+    /// Register `nix_file`, or update its priority: it was pinged at `time`.
+    pub fn ping(&self, nix_file: &NixFile, time: SystemTime) {
+        self.shared.lock().pings.insert(nix_file.clone(), time);
+    }
+
+    /// Unregister `nix_file`. Its queued job (if any) does not run.
+    pub fn forget(&self, nix_file: &NixFile) {
+        let mut state = self.shared.lock();
+        state.pings.remove(nix_file);
+        state.queue.remove(nix_file);
+    }
+
+    /// Run `job` for `nix_file` once a worker is free.
+    /// A job of `nix_file` which is still waiting is replaced.
+    pub fn submit(&self, nix_file: &NixFile, job: Job) {
+        let mut state = self.shared.lock();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.queue.insert(nix_file.clone(), (ticket, job));
+        self.shared.changed.notify_one();
+    }
+
+    /// Take back the job of `nix_file`, if it is still waiting for a worker.
+    /// Returns `false` if there is no such job, e.g. because it already runs.
+    pub fn withdraw(&self, nix_file: &NixFile) -> bool {
+        self.shared.lock().queue.remove(nix_file).is_some()
+    }
+
+    /// Drop the waiting jobs and wait for the running ones to finish.
+    /// Jobs submitted afterwards never run.
+    pub fn close(&mut self) {
+        {
+            let mut state = self.shared.lock();
+            state.closed = true;
+            state.queue.clear();
+        }
+        self.shared.changed.notify_all();
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                error!("build worker thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.close()
+    }
+}
+
+/// Run queued jobs until the scheduler is closed.
+fn work(shared: &Shared) {
+    loop {
+        let job = {
+            let mut state = shared.lock();
+            loop {
+                if state.closed {
+                    return;
+                }
+                if let Some(job) = state.next() {
+                    break job;
+                }
+                state = shared
+                    .changed
+                    .wait(state)
+                    .expect("scheduler mutex poisoned, a worker panicked");
+            }
+        };
+        // a panicking job must not take the worker down with it
+        if std::panic::catch_unwind(std::panic::AssertUnwindSafe(job)).is_err() {
+            error!("a job panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ShellArgs;
+    use crossbeam_channel as chan;
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn nix_file(name: &str) -> NixFile {
+        NixFile::shell(PathBuf::from(name), ShellArgs::default())
+    }
+
+    /// A job which reports `name` on `tx` when it runs.
+    fn report(name: &'static str, tx: &chan::Sender<&'static str>) -> Job {
+        let tx = tx.clone();
+        Box::new(move || tx.send(name).unwrap())
+    }
+
+    /// Keep the only worker of `scheduler` busy until the returned sender is dropped.
+    fn occupy_worker(scheduler: &Scheduler) -> chan::Sender<()> {
+        let (started_tx, started_rx) = chan::bounded(1);
+        let (release_tx, release_rx) = chan::bounded::<()>(0);
+        scheduler.ping(&nix_file("running"), UNIX_EPOCH);
+        scheduler.submit(
+            &nix_file("running"),
+            Box::new(move || {
+                started_tx.send(()).unwrap();
+                let _ = release_rx.recv();
+            }),
+        );
+        started_rx
+            .recv_timeout(Duration::from_secs(1))
+            .expect("the job did not start");
+        release_tx
+    }
+
+    #[test]
+    fn most_recently_pinged_project_goes_first() {
+        let scheduler = Scheduler::new(1);
+        let release = occupy_worker(&scheduler);
+        let (tx, rx) = chan::unbounded();
+        for (name, secs) in &[("old", 1), ("new", 2)] {
+            scheduler.ping(&nix_file(name), UNIX_EPOCH + Duration::from_secs(*secs));
+            scheduler.submit(&nix_file(name), report(name, &tx));
+        }
+        drop(release);
+
+        let order: Vec<_> = (0..2)
+            .map(|_| rx.recv_timeout(Duration::from_secs(1)).unwrap())
+            .collect();
+        assert_eq!(order, vec!["new", "old"]);
+    }
+
+    #[test]
+    fn forgotten_or_withdrawn_jobs_do_not_run() {
+        let scheduler = Scheduler::new(1);
+        let release = occupy_worker(&scheduler);
+        let (tx, rx) = chan::unbounded();
+        for name in &["forgotten", "withdrawn", "replaced"] {
+            scheduler.ping(&nix_file(name), UNIX_EPOCH);
+            scheduler.submit(&nix_file(name), report(name, &tx));
+        }
+        scheduler.forget(&nix_file("forgotten"));
+        assert!(scheduler.withdraw(&nix_file("withdrawn")));
+        assert!(!scheduler.withdraw(&nix_file("withdrawn")));
+        scheduler.submit(&nix_file("replaced"), report("replacement", &tx));
+        drop(release);
+
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok("replacement"));
+        // dropping the scheduler waits for the workers
+        drop(scheduler);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), Vec::<&str>::new());
+    }
+}
@@ -1,6 +1,8 @@
 //! `bind()`ing & `connect()`ing to sockets.
 
+use slog_scope::warn;
 use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
 use std::path::{Path, PathBuf};
 
 /// Small wrapper that makes sure lorri sockets are handled correctly.
@@ -57,6 +59,28 @@ impl SocketPath {
         Ok(BindLock(h))
     }
 
+    /// Bind a listening socket to the path, replacing a stale socket
+    /// file left behind by a previous process.
+    ///
+    /// The `BindLock` of this path proves no other process is listening.
+    pub fn bind(&self, _lock: &BindLock) -> Result<UnixListener, BindError> {
+        match std::fs::remove_file(self.path()) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            other => other,
+        }?;
+        Ok(UnixListener::bind(self.path())?)
+    }
+
+    /// Remove the socket file, once the server stopped listening.
+    pub fn remove_socket(&self) {
+        remove_file_if_exists(self.path())
+    }
+
+    /// Remove the lock file, once the server stopped listening.
+    pub fn remove_lockfile(&self) {
+        remove_file_if_exists(&self.lockfile())
+    }
+
     /// The absolute path of the socket.
     pub fn path(&self) -> &Path {
         self.0.as_ref()
@@ -80,6 +104,14 @@ impl SocketPath {
     }
 }
 
+fn remove_file_if_exists(path: &Path) {
+    match std::fs::remove_file(path) {
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!("failed to remove file"; "file" => path.display(), "error" => %e),
+        Ok(()) => {}
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
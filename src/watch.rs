@@ -5,16 +5,31 @@ use crate::NixFile;
 use crossbeam_channel as chan;
 use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use slog_scope::{debug, info};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 /// A dynamic list of paths to watch for changes, and
 /// react to changes when they occur.
+///
+/// One `Watch` serves many projects: it remembers which paths each
+/// project needs and routes changes to the projects they concern.
+/// A path is watched as long as any project needs it.
 pub struct Watch {
     /// Event receiver. Process using `Watch::process`.
     pub rx: chan::Receiver<notify::Result<notify::Event>>,
     notify: RecommendedWatcher,
+    projects: HashMap<NixFile, Watched>,
+    /// How many projects need each path which is watched by `notify`.
+    watch_counts: HashMap<PathBuf, usize>,
+}
+
+/// The paths of a single project in a `Watch`.
+#[derive(Default)]
+struct Watched {
+    /// Changes to or directly below these paths concern the project.
+    paths: HashSet<PathBuf>,
+    /// All paths watched for the project, including the parents of `paths`.
     watches: HashSet<PathBuf>,
 }
 
@@ -35,20 +50,50 @@ pub enum Reason {
     ProjectAdded(NixFile),
     /// When a ping is received.
     PingReceived,
+    /// When somebody asked for a rebuild, see `build_loop::Ping::Rebuild`.
+    RebuildRequested {
+        /// Whether unpinned inputs are fetched again.
+        refresh: bool,
+        /// Files which changed as well, see `Reason::files`.
+        files: Vec<PathBuf>,
+    },
+    /// When the environment to evaluate the project in changed,
+    /// see `build_loop::Ping::Activity`.
+    EnvironmentChanged,
     /// When there is a filesystem change, the first changed file is recorded,
     /// along with a count of other filesystem events.
     FilesChanged(Vec<PathBuf>),
     /// When the underlying notifier reports something strange.
-    UnknownEvent(DebugMessage),
+    UnknownEvent {
+        /// What the notifier reported.
+        message: DebugMessage,
+        /// Files which changed as well, see `Reason::files`.
+        files: Vec<PathBuf>,
+    },
+}
+
+impl Reason {
+    /// The files which changed. A single build may replace the builds
+    /// for several reasons, so not only `FilesChanged` has files.
+    pub fn files(&self) -> &[PathBuf] {
+        match self {
+            Reason::FilesChanged(files)
+            | Reason::RebuildRequested { files, .. }
+            | Reason::UnknownEvent { files, .. } => files,
+            Reason::ProjectAdded(_) | Reason::PingReceived | Reason::EnvironmentChanged => &[],
+        }
+    }
 }
 
 /// We weren’t able to understand a `notify::Event`.
 #[derive(Clone, Debug)]
 pub enum EventError {
-    /// No message was received from the raw event channel
+    /// The raw event channel hung up, so the watcher died
     RxNoEventReceived,
     /// The changed file event had no file path
     EventHasNoFilePath(notify::Event),
+    /// The watcher reported an error, so changes might have been missed
+    Notify(DebugMessage),
 }
 
 impl Watch {
@@ -58,52 +103,105 @@ impl Watch {
 
         Ok(Watch {
             notify: Watcher::new(tx, Duration::from_millis(100))?,
-            watches: HashSet::new(),
+            projects: HashMap::new(),
+            watch_counts: HashMap::new(),
             rx,
         })
     }
 
+    /// A new Watch which watches the same paths for the same projects,
+    /// to replace this one after it failed.
+    pub fn renew(&self) -> Result<Watch, notify::Error> {
+        let mut watch = Watch::try_new()?;
+        for (nix_file, watched) in &self.projects {
+            watch.extend(nix_file, &watched.paths.iter().cloned().collect::<Vec<_>>())?;
+        }
+        Ok(watch)
+    }
+
     /// Process `notify::Event`s coming in via `Watch::rx`.
+    /// Returns the projects which are concerned by the event,
+    /// each with the changed paths it watches.
     pub fn process(
         &self,
         event: notify::Result<notify::Event>,
-    ) -> Option<Result<Reason, EventError>> {
+    ) -> Result<Vec<(NixFile, Reason)>, EventError> {
         match event {
-            Err(err) => panic!("notify error: {}", err),
+            Err(err) => Err(EventError::Notify(DebugMessage::from(format!("{:?}", err)))),
             Ok(event) => {
                 self.log_event(&event);
                 if event.paths.is_empty() {
-                    Some(Err(EventError::EventHasNoFilePath(event)))
+                    Err(EventError::EventHasNoFilePath(event))
                 } else {
-                    let interesting_paths: Vec<PathBuf> = event
-                        .paths
-                        .into_iter()
-                        .filter(|p| self.path_is_interesting(p))
-                        .collect();
-                    if !interesting_paths.is_empty() {
-                        Some(Ok(Reason::FilesChanged(interesting_paths)))
-                    } else {
-                        None
-                    }
+                    Ok(self.route(&event.paths))
                 }
             }
         }
     }
 
-    /// Extend the watch list with an additional list of paths.
+    /// All paths which are currently watched for `nix_file`.
+    pub fn paths(&self, nix_file: &NixFile) -> Vec<PathBuf> {
+        self.projects
+            .get(nix_file)
+            .map_or_else(Vec::new, |watched| watched.paths.iter().cloned().collect())
+    }
+
+    /// Extend the watch list of `nix_file` with an additional list of paths.
     /// Note: Watch maintains a list of already watched paths, and
     /// will not add duplicates.
-    pub fn extend(&mut self, paths: &[PathBuf]) -> Result<(), notify::Error> {
+    pub fn extend(&mut self, nix_file: &NixFile, paths: &[PathBuf]) -> Result<(), notify::Error> {
         for path in paths {
-            self.add_path(&path)?;
+            self.add_path(nix_file, &path)?;
             if path.is_dir() {
-                self.add_path_recursively(&path)?;
+                self.add_path_recursively(nix_file, &path)?;
             }
         }
 
         Ok(())
     }
 
+    /// Stop watching the paths of `nix_file`,
+    /// unless other projects still need them.
+    pub fn remove(&mut self, nix_file: &NixFile) {
+        let watched = match self.projects.remove(nix_file) {
+            Some(watched) => watched,
+            None => return,
+        };
+        for path in watched.watches {
+            let count = self
+                .watch_counts
+                .get_mut(&path)
+                .expect("watched path is not counted");
+            *count -= 1;
+            if *count == 0 {
+                self.watch_counts.remove(&path);
+                debug!("no longer watching path"; "path" => path.to_str());
+                // the path might be gone already
+                let _ = self.notify.unwatch(&path);
+            }
+        }
+    }
+
+    /// The projects which watch any of the changed `paths`,
+    /// each with the changed paths it watches.
+    fn route(&self, paths: &[PathBuf]) -> Vec<(NixFile, Reason)> {
+        self.projects
+            .iter()
+            .filter_map(|(nix_file, watched)| {
+                let interesting_paths: Vec<PathBuf> = paths
+                    .iter()
+                    .filter(|p| path_match(&watched.paths, p))
+                    .cloned()
+                    .collect();
+                if interesting_paths.is_empty() {
+                    None
+                } else {
+                    Some((nix_file.clone(), Reason::FilesChanged(interesting_paths)))
+                }
+            })
+            .collect()
+    }
+
     fn log_event(&self, event: &notify::Event) {
         debug!("Watch Event: {:#?}", event);
         match &event.kind {
@@ -116,7 +214,11 @@ impl Watch {
         }
     }
 
-    fn add_path_recursively(&mut self, path: &PathBuf) -> Result<(), notify::Error> {
+    fn add_path_recursively(
+        &mut self,
+        nix_file: &NixFile,
+        path: &PathBuf,
+    ) -> Result<(), notify::Error> {
         if path.canonicalize()?.starts_with(Path::new("/nix/store")) {
             return Ok(());
         }
@@ -125,8 +227,8 @@ impl Watch {
             let subpath = entry?.path();
 
             if subpath.is_dir() {
-                self.add_path(&subpath)?;
-                self.add_path_recursively(&subpath)?;
+                self.add_path(nix_file, &subpath)?;
+                self.add_path_recursively(nix_file, &subpath)?;
             }
 
             // Skip adding files, watching in the dir will handle it.
@@ -134,27 +236,40 @@ impl Watch {
         Ok(())
     }
 
-    fn add_path(&mut self, path: &PathBuf) -> Result<(), notify::Error> {
-        if !self.watches.contains(path) {
-            debug!("watching path"; "path" => path.to_str());
-
-            self.notify.watch(path, RecursiveMode::NonRecursive)?;
-            self.watches.insert(path.clone());
-        }
+    fn add_path(&mut self, nix_file: &NixFile, path: &PathBuf) -> Result<(), notify::Error> {
+        self.watch_path(nix_file, path)?;
+        self.projects
+            .entry(nix_file.clone())
+            .or_default()
+            .paths
+            .insert(path.clone());
 
         if let Some(parent) = path.parent() {
-            if !self.watches.contains(parent) {
-                debug!("watching parent path"; "parent_path" => parent.to_str());
-
-                self.notify.watch(&parent, RecursiveMode::NonRecursive)?;
-            }
+            self.watch_path(nix_file, parent)?;
         }
 
         Ok(())
     }
 
-    fn path_is_interesting(&self, path: &PathBuf) -> bool {
-        path_match(&self.watches, path)
+    /// Make sure `path` is watched for `nix_file`. Only the first
+    /// project which needs a path makes `notify` watch it.
+    fn watch_path(&mut self, nix_file: &NixFile, path: &Path) -> Result<(), notify::Error> {
+        let watched = self.projects.entry(nix_file.clone()).or_default();
+        if watched.watches.contains(path) {
+            return Ok(());
+        }
+        let count = self.watch_counts.entry(path.to_path_buf()).or_insert(0);
+        if *count == 0 {
+            debug!("watching path"; "path" => path.to_str());
+
+            if let Err(e) = self.notify.watch(path, RecursiveMode::NonRecursive) {
+                self.watch_counts.remove(path);
+                return Err(e);
+            }
+        }
+        *count += 1;
+        watched.watches.insert(path.to_path_buf());
+        Ok(())
     }
 }
 
@@ -212,6 +327,8 @@ fn path_match(watched_paths: &HashSet<PathBuf>, event_path: &Path) -> bool {
 mod tests {
     use super::{EventError, Reason, Watch};
     use crate::bash::expect_bash;
+    use crate::{NixFile, ShellArgs};
+    use std::path::PathBuf;
     use std::thread::sleep;
     use std::time::Duration;
     use tempfile::tempdir;
@@ -221,9 +338,28 @@ mod tests {
         Duration::from_millis(500)
     }
 
+    /// The project all paths are watched for
+    fn project() -> NixFile {
+        NixFile::shell(PathBuf::from("/project/shell.nix"), ShellArgs::default())
+    }
+
     /// Collect all notifications
     fn process_all(watch: &Watch) -> Vec<Option<Result<Reason, EventError>>> {
-        watch.rx.try_iter().map(|e| watch.process(e)).collect()
+        watch
+            .rx
+            .try_iter()
+            .map(|e| {
+                watch
+                    .process(e)
+                    .map(|routes| {
+                        routes
+                            .into_iter()
+                            .find(|(nix_file, _)| nix_file == &project())
+                            .map(|(_, reason)| reason)
+                    })
+                    .transpose()
+            })
+            .collect()
     }
 
     /// Returns true iff the given file has changed
@@ -294,7 +430,9 @@ mod tests {
         let temp = tempdir().unwrap();
 
         expect_bash(r#"mkdir -p "$1""#, &[temp.path().as_os_str()]);
-        watcher.extend(&[temp.path().to_path_buf()]).unwrap();
+        watcher
+            .extend(&project(), &[temp.path().to_path_buf()])
+            .unwrap();
 
         expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
         sleep(upper_watcher_timeout());
@@ -312,7 +450,9 @@ mod tests {
 
         expect_bash(r#"mkdir -p "$1""#, &[temp.path().as_os_str()]);
         expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
-        watcher.extend(&[temp.path().join("foo")]).unwrap();
+        watcher
+            .extend(&project(), &[temp.path().join("foo")])
+            .unwrap();
         macos_eat_late_notifications(&mut watcher);
 
         expect_bash(r#"echo 1 > "$1/foo""#, &[temp.path().as_os_str()]);
@@ -328,7 +468,9 @@ mod tests {
 
         expect_bash(r#"mkdir -p "$1""#, &[temp.path().as_os_str()]);
         expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
-        watcher.extend(&[temp.path().join("foo")]).unwrap();
+        watcher
+            .extend(&project(), &[temp.path().join("foo")])
+            .unwrap();
         macos_eat_late_notifications(&mut watcher);
 
         // bar is not watched, expect error
@@ -351,4 +493,56 @@ mod tests {
         sleep(upper_watcher_timeout());
         assert_file_changed(&watcher, "foo");
     }
+
+    #[test]
+    fn changes_are_routed_to_the_projects_which_watch_them() {
+        let mut watcher = Watch::try_new().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+        let one = NixFile::shell(temp.path().join("one.nix"), ShellArgs::default());
+        let two = NixFile::shell(temp.path().join("two.nix"), ShellArgs::default());
+        let shared = temp.path().join("shared");
+        let only_two = temp.path().join("only-two");
+        std::fs::create_dir(&shared).unwrap();
+        std::fs::create_dir(&only_two).unwrap();
+        watcher.extend(&one, &[shared.clone()]).unwrap();
+        watcher
+            .extend(&two, &[shared.clone(), only_two.clone()])
+            .unwrap();
+
+        let routed = |path: PathBuf| {
+            let mut projects: Vec<NixFile> = watcher
+                .route(&[path])
+                .into_iter()
+                .map(|(nix_file, _)| nix_file)
+                .collect();
+            projects.sort_by_key(|nix_file| PathBuf::from(nix_file));
+            projects
+        };
+        assert_eq!(routed(shared.join("foo")), vec![one.clone(), two.clone()]);
+        assert_eq!(routed(only_two.join("foo")), vec![two.clone()]);
+        assert_eq!(routed(temp.path().join("elsewhere/foo")), vec![]);
+    }
+
+    #[test]
+    fn paths_are_watched_while_any_project_needs_them() {
+        let mut watcher = Watch::try_new().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+        let one = NixFile::shell(temp.path().join("one.nix"), ShellArgs::default());
+        let two = NixFile::shell(temp.path().join("two.nix"), ShellArgs::default());
+        let file = temp.path().join("foo");
+        std::fs::write(&file, "").unwrap();
+        watcher.extend(&one, &[file.clone()]).unwrap();
+        watcher.extend(&two, &[file.clone()]).unwrap();
+        // extending twice does not count twice
+        watcher.extend(&two, &[file.clone()]).unwrap();
+        assert_eq!(watcher.watch_counts.get(&file), Some(&2));
+
+        watcher.remove(&one);
+        assert_eq!(watcher.watch_counts.get(&file), Some(&1));
+        assert_eq!(watcher.paths(&one), Vec::<PathBuf>::new());
+        assert_eq!(watcher.paths(&two), vec![file.clone()]);
+
+        watcher.remove(&two);
+        assert!(watcher.watch_counts.is_empty());
+    }
 }
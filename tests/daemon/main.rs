@@ -1,14 +1,18 @@
 extern crate lorri;
 extern crate tempfile;
 
+use crossbeam_channel as chan;
 use lorri::build_loop;
 use lorri::cas::ContentAddressable;
-use lorri::daemon::Daemon;
+use lorri::daemon::{Config, Daemon};
 use lorri::rpc;
+use lorri::rpc::VarlinkClientInterface;
 use lorri::socket::SocketPath;
 use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
 use std::thread;
 use std::time::{Duration, Instant};
+use tempfile::TempDir;
 
 /// This tests the basic working of the client/daemon setup.
 ///
@@ -18,49 +22,199 @@ use std::time::{Duration, Instant};
 /// that the build is starting up (`Event::Started`).
 #[test]
 pub fn start_job_with_ping() -> std::io::Result<()> {
-    // TODO: this code is a mess because Daeomon is not
-    // nicely abstracted yet.
-
-    let tempdir = tempfile::tempdir()?;
-    let shell_nix = tempdir.as_ref().join("shell.nix");
-    std::fs::File::create(&shell_nix)?;
-
-    let socket_path = SocketPath::from(&tempdir.path().join("socket"));
-    let address = socket_path.address();
-    let cas = ContentAddressable::new(tempdir.path().join("cas")).unwrap();
-    let gc_root_dir = tempdir.path().join("gc_root").to_path_buf();
-
-    // The daemon knows how to build stuff
-    let (daemon, build_rx) = Daemon::new();
-    let accept_handle = thread::spawn(move || {
-        daemon
-            .serve(socket_path, gc_root_dir, cas)
-            .expect("failed to serve daemon endpoint");
-    });
+    let (tempdir, address, build_rx) = start_daemon(Config::default());
+    let shell_nix_path = create_shell_nix(&tempdir)?;
 
     // connect to socket and send a ping message
-    use crate::lorri::rpc::VarlinkClientInterface;
     rpc::VarlinkClient::new(connect(&address, Duration::from_millis(1000)))
-        .watch_shell(rpc::ShellNix {
-            path: shell_nix.to_str().unwrap().to_string(),
-        })
+        .watch_shell(shell_nix(&shell_nix_path))
         .call()
         .unwrap();
 
     // Read the first build event, which should be a `Started` message
     match build_rx.recv_timeout(Duration::from_millis(100)).unwrap() {
-        build_loop::Event::Started(_) => Ok(()),
+        build_loop::Event::Started { .. } => Ok(()),
         ev => Err(Error::new(
             ErrorKind::Other,
             format!("didn’t expect event {:?}", ev),
         )),
-    }?;
+    }
+}
+
+/// A `Rebuild` call starts another build, even though nothing changed.
+#[test]
+pub fn rebuild_starts_build() -> std::io::Result<()> {
+    let (tempdir, address, build_rx) = start_daemon(Config::default());
+    let shell = shell_nix(&create_shell_nix(&tempdir)?);
+
+    let mut client = rpc::VarlinkClient::new(connect(&address, Duration::from_millis(1000)));
+    client.watch_shell(shell.clone()).call().unwrap();
+    // wait for the first build to end, however it ends
+    loop {
+        match build_rx.recv_timeout(Duration::from_secs(60)).unwrap() {
+            build_loop::Event::Started { .. } => continue,
+            _ => break,
+        }
+    }
+
+    client.rebuild(shell, true).call().unwrap();
+    loop {
+        match build_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            build_loop::Event::Started {
+                reason: lorri::watch::Reason::RebuildRequested { refresh: true, .. },
+                ..
+            } => return Ok(()),
+            // the first build might have hit an internal error and be retried
+            _ => continue,
+        }
+    }
+}
+
+/// `UnwatchShell` stops watching a project and can delete its GC roots.
+#[test]
+pub fn unwatch_shell_forgets_project() -> std::io::Result<()> {
+    let (tempdir, address, _build_rx) = start_daemon(Config::default());
+    let shell = shell_nix(&create_shell_nix(&tempdir)?);
+
+    let mut client = rpc::VarlinkClient::new(connect(&address, Duration::from_millis(1000)));
+    client.watch_shell(shell.clone()).call().unwrap();
+    assert_eq!(client.list_projects().call().unwrap().projects.len(), 1);
+
+    assert!(
+        client
+            .unwatch_shell(shell.clone(), true)
+            .call()
+            .unwrap()
+            .was_watched
+    );
+    assert!(client.list_projects().call().unwrap().projects.is_empty());
+    assert_eq!(
+        std::fs::read_dir(tempdir.path().join("gc_root"))?.count(),
+        0,
+        "the GC roots were not deleted"
+    );
+
+    assert!(
+        !client
+            .unwatch_shell(shell, false)
+            .call()
+            .unwrap()
+            .was_watched
+    );
+    Ok(())
+}
+
+/// `UnwatchProject` forgets a project by the hash `ListProjects` reports.
+#[test]
+pub fn unwatch_project_forgets_project_by_hash() -> std::io::Result<()> {
+    let (tempdir, address, _build_rx) = start_daemon(Config::default());
+    let shell = rpc::ShellNix {
+        attr: Some(String::from("shell")),
+        ..shell_nix(&create_shell_nix(&tempdir)?)
+    };
+
+    let mut client = rpc::VarlinkClient::new(connect(&address, Duration::from_millis(1000)));
+    client.watch_shell(shell).call().unwrap();
+    let projects = client.list_projects().call().unwrap().projects;
+    assert_eq!(projects.len(), 1);
+
+    let hash = projects[0].hash.clone();
+    assert!(
+        client
+            .unwatch_project(hash.clone(), true)
+            .call()
+            .unwrap()
+            .was_watched
+    );
+    assert!(client.list_projects().call().unwrap().projects.is_empty());
+    assert!(
+        !tempdir.path().join("gc_root").join(&hash).exists(),
+        "the GC roots were not deleted"
+    );
+
+    assert!(client
+        .unwatch_project(String::from("../.."), true)
+        .call()
+        .is_err());
+    Ok(())
+}
+
+/// The `Shutdown` call stops the daemon, which then cleans up its socket.
+#[test]
+pub fn shutdown_removes_socket() -> std::io::Result<()> {
+    let (tempdir, address, _build_rx) = start_daemon(Config::default());
+    let socket_file = tempdir.path().join("socket");
+
+    rpc::VarlinkClient::new(connect(&address, Duration::from_millis(1000)))
+        .shutdown()
+        .call()
+        .unwrap();
+
+    let start = Instant::now();
+    while socket_file.exists() {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "socket file was not removed"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
+}
+
+/// `GetVersion` reports the version of the lorri the daemon was built from.
+#[test]
+pub fn get_version_matches_client() -> std::io::Result<()> {
+    let (_tempdir, address, _build_rx) = start_daemon(Config::default());
 
-    drop(accept_handle);
-    drop(tempdir);
+    let version = rpc::VarlinkClient::new(connect(&address, Duration::from_millis(1000)))
+        .get_version()
+        .call()
+        .unwrap();
+    assert_eq!(version.protocol, lorri::daemon::PROTOCOL_VERSION);
+    assert_eq!(version.build_rev, lorri::VERSION_BUILD_REV as i64);
     Ok(())
 }
 
+/// Start a daemon with `config` in a new temporary directory, which also
+/// holds its socket (see the returned address), GC roots (`gc_root`)
+/// and content-addressable store (`cas`). The daemon stops once the
+/// test ends.
+fn start_daemon(config: Config) -> (TempDir, String, chan::Receiver<build_loop::Event>) {
+    let tempdir = tempfile::tempdir().expect("failed to create a temporary directory");
+    let socket_path = SocketPath::from(&tempdir.path().join("socket"));
+    let address = socket_path.address();
+    let cas = ContentAddressable::new(tempdir.path().join("cas")).unwrap();
+    let gc_root_dir = tempdir.path().join("gc_root");
+
+    let (daemon, build_rx) = Daemon::new(config);
+    thread::spawn(move || {
+        daemon
+            .serve(socket_path, gc_root_dir, cas, chan::never())
+            .expect("failed to serve daemon endpoint")
+    });
+    (tempdir, address, build_rx)
+}
+
+/// The project described by the nix file at `path`, as it is.
+fn shell_nix(path: &Path) -> rpc::ShellNix {
+    rpc::ShellNix {
+        path: path.to_str().unwrap().to_string(),
+        flake_shell: None,
+        attr: None,
+        args: None,
+        argstrs: None,
+        env: None,
+        cwd: None,
+    }
+}
+
+/// An empty `shell.nix` in `dir`.
+fn create_shell_nix(dir: &TempDir) -> std::io::Result<PathBuf> {
+    let shell_nix = dir.path().join("shell.nix");
+    std::fs::File::create(&shell_nix)?;
+    Ok(shell_nix)
+}
+
 /// The server side of the connection is started in a separate thread. This function waits until
 /// the socket address is available for connection.
 fn connect(
@@ -7,7 +7,7 @@ use lorri::{
     cas::ContentAddressable,
     ops::direnv,
     project::Project,
-    NixFile,
+    NixFile, ShellArgs,
 };
 use std::fs::File;
 use std::iter::FromIterator;
@@ -31,7 +31,7 @@ impl DirenvTestCase {
         let test_root =
             PathBuf::from_iter(&[env!("CARGO_MANIFEST_DIR"), "tests", "integration", name]);
 
-        let shell_file = NixFile::Shell(test_root.join("shell.nix"));
+        let shell_file = NixFile::shell(test_root.join("shell.nix"), ShellArgs::default());
 
         let cas = ContentAddressable::new(cachedir.path().join("cas").to_owned()).unwrap();
         let project = Project::new(